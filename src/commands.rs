@@ -3,6 +3,9 @@ bot が実行するコマンド
 */
 
 use crate::hatagenpei::controller::*;
+use crate::hatagenpei::rating::INIT_RATING;
+use crate::hatagenpei::replay::{seed_commitment, ReplayError};
+use crate::hatagenpei::team::{Contribution, Side};
 use log::{info, warn};
 use slack::RtmClient;
 
@@ -16,21 +19,1106 @@ pub fn on_hatagenpei(
     message_user_name: &String,
     message_user_id: &String,
     chid: &String,
+    board_token: Option<&String>,
+    rule_set_id: Option<&str>,
 ) -> Result<(), failure::Error> {
     info!("called on_hatagenpei, user_id = {}", message_user_id);
     match controller {
         Some(controller) => {
-            let res = controller.step(message_user_name);
+            // 人間同士の対戦中なら、そちらを進める
+            if controller.has_match(message_user_id) {
+                let (s, board) = match controller.match_step(message_user_id, message_user_name) {
+                    Ok(res) => (["```", &res.logs.join("\n"), "```"].concat(), res.board),
+                    Err(err) => (match_error_message(&err), None),
+                };
+                let _ = cli.sender().send_message(chid, &s);
+                upload_board(board_token, chid, board);
+                return Ok(());
+            }
+
+            if let Some(rule_set_id) = rule_set_id {
+                if controller.get_rule_set(rule_set_id).is_none() {
+                    let _ = cli
+                        .sender()
+                        .send_message(chid, &format!("ルール {} は見つからないげん", rule_set_id));
+                    return Ok(());
+                }
+            }
+
+            let res = controller.step(message_user_id, message_user_name, rule_set_id);
+
+            let prefix = if res.is_start {
+                "旗源平を始めるげん!\n\n"
+            } else {
+                ""
+            };
+
+            let joined_logs = [prefix, "```", &res.logs.join("\n"), "```"].concat();
+
+            let _ = cli.sender().send_message(chid, &joined_logs);
+            upload_board(board_token, chid, res.board);
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// bot との旗源平を決着がつくまで一気に進める
+// チャンネルには要約だけを投稿し、全手番のログは thread_ts のスレッドに投稿する
+pub fn on_hatagenpei_auto(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    message_user_name: &String,
+    message_user_id: &String,
+    chid: &String,
+    thread_ts: Option<&String>,
+    rule_set_id: Option<&str>,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_auto");
+    match controller {
+        Some(controller) => {
+            if controller.has_match(message_user_id) {
+                let _ = cli
+                    .sender()
+                    .send_message(chid, "対戦中は一気に進められないげん");
+                return Ok(());
+            }
+
+            if let Some(rule_set_id) = rule_set_id {
+                if controller.get_rule_set(rule_set_id).is_none() {
+                    let _ = cli
+                        .sender()
+                        .send_message(chid, &format!("ルール {} は見つからないげん", rule_set_id));
+                    return Ok(());
+                }
+            }
+
+            let res = controller.auto_play(message_user_id, message_user_name, rule_set_id);
+            let prefix = if res.is_start {
+                "旗源平を一気に進めるげん!\n\n"
+            } else {
+                "続きから一気に進めるげん!\n\n"
+            };
+            let summary = [prefix, "```", &res.summary.join("\n"), "```"].concat();
+            let _ = cli.sender().send_message(chid, &summary);
+
+            // 全手番のログは長くなるので、分割してスレッドに投稿する
+            for chunk in split_lines(&res.logs, MAX_MESSAGE_LENGTH) {
+                let s = ["```", &chunk, "```"].concat();
+                match thread_ts {
+                    Some(thread_ts) => send_thread_message(cli, chid, thread_ts, &s),
+                    None => {
+                        let _ = cli.sender().send_message(chid, &s);
+                    }
+                }
+            }
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// bot とのチンチロリンを 1 勝負進める
+pub fn on_chinchirorin(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    message_user_name: &String,
+    message_user_id: &String,
+    chid: &String,
+    bet: Option<i32>,
+) -> Result<(), failure::Error> {
+    info!("called on_chinchirorin");
+    match controller {
+        Some(controller) => {
+            let s = match controller.chinchiro_step(message_user_id, message_user_name, bet) {
+                Ok(res) => {
+                    let prefix = if res.is_start {
+                        "チンチロリンを始めるげん!\n\n"
+                    } else {
+                        ""
+                    };
+                    [prefix, "```", &res.logs.join("\n"), "```"].concat()
+                }
+                Err(ChinchiroError::InvalidBet(max)) => {
+                    format!("賭け数は 1 から {} までにするげん", max)
+                }
+            };
+            let _ = cli.sender().send_message(chid, &s);
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// 実物のサイコロを振って出た目を入力して、bot との旗源平を進める
+pub fn on_hatagenpei_dice(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    message_user_name: &String,
+    message_user_id: &String,
+    chid: &String,
+    board_token: Option<&String>,
+    dice: Option<(u8, u8)>,
+    rule_set_id: Option<&str>,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_dice");
+    match controller {
+        Some(controller) => {
+            if let Some(rule_set_id) = rule_set_id {
+                if controller.get_rule_set(rule_set_id).is_none() {
+                    let _ = cli
+                        .sender()
+                        .send_message(chid, &format!("ルール {} は見つからないげん", rule_set_id));
+                    return Ok(());
+                }
+            }
+
+            let (s, board) = match dice {
+                None => (
+                    "出た目を `旗源平 出目 3 4` のように 1 から 6 の数字 2 つで入力するげん"
+                        .to_string(),
+                    None,
+                ),
+                Some(_) if controller.has_match(message_user_id) => {
+                    ("対戦中は出目を入力できないげん".to_string(), None)
+                }
+                Some((dice1, dice2)) => {
+                    let res = controller.step_with_dice(
+                        message_user_id,
+                        message_user_name,
+                        dice1,
+                        dice2,
+                        rule_set_id,
+                    );
+                    let prefix = if res.is_start {
+                        "旗源平を始めるげん!\n\n"
+                    } else {
+                        ""
+                    };
+                    (
+                        [prefix, "```", &res.logs.join("\n"), "```"].concat(),
+                        res.board,
+                    )
+                }
+            };
+            let _ = cli.sender().send_message(chid, &s);
+            upload_board(board_token, chid, board);
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// challenged (id, 名前) に旗源平の対戦を挑む
+pub fn on_hatagenpei_challenge(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    message_user_name: &String,
+    message_user_id: &String,
+    chid: &String,
+    challenged: Option<(&String, &String)>,
+    rule_set_id: Option<&str>,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_challenge");
+    match controller {
+        Some(controller) => {
+            let s = match challenged {
+                None => "挑戦する相手を `旗源平 挑戦 @相手` のようにメンションで指定するげん"
+                    .to_string(),
+                Some((challenged_id, challenged_name)) => {
+                    match controller.challenge(
+                        message_user_id,
+                        message_user_name,
+                        challenged_id,
+                        challenged_name,
+                        rule_set_id,
+                    ) {
+                        Ok(()) => format!(
+                            "{} が {} に旗源平を挑んだげん！\n{} は `旗源平 受諾` か `旗源平 辞退` で返事をするげん",
+                            message_user_name, challenged_name, challenged_name
+                        ),
+                        Err(err) => match_error_message(&err),
+                    }
+                }
+            };
+            let _ = cli.sender().send_message(chid, &s);
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// 自分への旗源平の挑戦を受ける
+pub fn on_hatagenpei_accept(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    message_user_name: &String,
+    message_user_id: &String,
+    chid: &String,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_accept");
+    match controller {
+        Some(controller) => {
+            let s = match controller.accept_challenge(message_user_id, message_user_name) {
+                Ok(match_progress) => {
+                    let (player1, player2) = (&match_progress.player1, &match_progress.player2);
+                    let mut s = format!(
+                        "{} と {} の旗源平を始めるげん！\n{} から順番に `旗源平` でサイコロを振るげん",
+                        player1.name, player2.name, player1.name
+                    );
+                    // ハンデがある場合は、開始時の旗を表示する
+                    if player1.my_score != player2.my_score {
+                        for player in [player1, player2].iter() {
+                            s.push_str(&format!(
+                                "\n{} の旗 【{}】",
+                                player.name,
+                                player.my_score.to_string()
+                            ));
+                        }
+                    }
+                    s
+                }
+                Err(err) => match_error_message(&err),
+            };
+            let _ = cli.sender().send_message(chid, &s);
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// 自分への旗源平の挑戦を断る
+pub fn on_hatagenpei_decline(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    message_user_name: &String,
+    message_user_id: &String,
+    chid: &String,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_decline");
+    match controller {
+        Some(controller) => {
+            let s = match controller.decline_challenge(message_user_id, message_user_name) {
+                Ok(challenge) => format!(
+                    "{} は {} からの挑戦を断ったげん",
+                    challenge.challenged, challenge.challenger
+                ),
+                Err(err) => match_error_message(&err),
+            };
+            let _ = cli.sender().send_message(chid, &s);
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// チャンネルの3人以上で遊ぶ卓に参加する。卓がなければ新しく開く
+pub fn on_hatagenpei_table_join(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    message_user_name: &String,
+    message_user_id: &String,
+    chid: &String,
+    rule_set_id: Option<&str>,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_table_join");
+    match controller {
+        Some(controller) => {
+            let s = match controller.join_table(chid, message_user_id, message_user_name, rule_set_id)
+            {
+                Ok(table) => format!(
+                    "{} が卓に参加したげん（{} 人）\n3人以上そろったら `旗源平 卓 開始` で始めるげん",
+                    message_user_name,
+                    table.seats.len()
+                ),
+                Err(err) => table_error_message(&err),
+            };
+            let _ = cli.sender().send_message(chid, &s);
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// チャンネルの卓のゲームを開始する
+pub fn on_hatagenpei_table_start(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    message_user_name: &String,
+    message_user_id: &String,
+    chid: &String,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_table_start");
+    match controller {
+        Some(controller) => {
+            let s = match controller.start_table(chid, message_user_id, message_user_name) {
+                Ok(table) => {
+                    let names = table
+                        .seats
+                        .iter()
+                        .map(|s| s.player.name.clone())
+                        .collect::<Vec<_>>();
+                    format!(
+                        "{} で旗源平を始めるげん！\nこの順番に `旗源平 卓` でサイコロを振るげん。`旗源平 卓 @相手` で旗をやり取りする相手を選べるげん",
+                        names.join(", ")
+                    )
+                }
+                Err(err) => table_error_message(&err),
+            };
+            let _ = cli.sender().send_message(chid, &s);
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// チャンネルの卓で、サイコロを振る
+pub fn on_hatagenpei_table_step(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    message_user_name: &String,
+    message_user_id: &String,
+    chid: &String,
+    target: Option<(&String, &String)>,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_table_step");
+    match controller {
+        Some(controller) => {
+            let s = match controller.table_step(
+                chid,
+                message_user_id,
+                message_user_name,
+                target.map(|(id, name)| (id.as_str(), name.as_str())),
+            ) {
+                Ok(res) => ["```", &res.logs.join("\n"), "```"].concat(),
+                Err(err) => table_error_message(&err),
+            };
+            let _ = cli.sender().send_message(chid, &s);
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// チャンネルのチーム戦の side 側に参加する。チーム戦がなければ新しく開く
+pub fn on_hatagenpei_team_join(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    message_user_name: &String,
+    message_user_id: &String,
+    chid: &String,
+    side: Side,
+    rule_set_id: Option<&str>,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_team_join");
+    match controller {
+        Some(controller) => {
+            let s = match controller.join_team(
+                chid,
+                message_user_id,
+                message_user_name,
+                side,
+                rule_set_id,
+            ) {
+                Ok(team_match) => format!(
+                    "{} が {} に参加したげん（源氏 {} 人、平家 {} 人）\n両方にメンバーがそろったら `旗源平 源平 開始` で始めるげん",
+                    message_user_name,
+                    side.to_string(),
+                    team_match.genji.members.len(),
+                    team_match.heike.members.len()
+                ),
+                Err(err) => team_error_message(&err),
+            };
+            let _ = cli.sender().send_message(chid, &s);
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// チャンネルのチーム戦を開始する
+pub fn on_hatagenpei_team_start(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    message_user_name: &String,
+    message_user_id: &String,
+    chid: &String,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_team_start");
+    match controller {
+        Some(controller) => {
+            let s = match controller.start_team_match(chid, message_user_id, message_user_name) {
+                Ok(team_match) => {
+                    let names = |members: &Vec<Contribution>| {
+                        members
+                            .iter()
+                            .map(|m| m.name.clone())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    };
+                    format!(
+                        "源氏（{}） と 平家（{}） のチーム戦を始めるげん！\n源氏から交互に、チームの中で順番に `旗源平 源平` でサイコロを振るげん",
+                        names(&team_match.genji.members),
+                        names(&team_match.heike.members)
+                    )
+                }
+                Err(err) => team_error_message(&err),
+            };
+            let _ = cli.sender().send_message(chid, &s);
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// チャンネルのチーム戦で、チームの代表としてサイコロを振る
+pub fn on_hatagenpei_team_step(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    message_user_name: &String,
+    message_user_id: &String,
+    chid: &String,
+    board_token: Option<&String>,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_team_step");
+    match controller {
+        Some(controller) => {
+            let (s, board) = match controller.team_step(chid, message_user_id, message_user_name) {
+                Ok(res) => (["```", &res.logs.join("\n"), "```"].concat(), res.board),
+                Err(err) => (team_error_message(&err), None),
+            };
+            let _ = cli.sender().send_message(chid, &s);
+            upload_board(board_token, chid, board);
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// 過去のチーム戦の、陣営の勝敗とメンバーの貢献度を表示する
+pub fn on_hatagenpei_team_stats(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    chid: &String,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_team_stats");
+    match controller {
+        Some(controller) => {
+            let mut s = "```".to_string();
+            s.push_str("# 陣営の勝敗\n");
+            for win_lose in controller.get_team_win_loses() {
+                s.push_str(&format!(
+                    "- {} 【{}勝 {}敗】\n",
+                    win_lose.name, win_lose.win, win_lose.lose
+                ));
+            }
+            s.push_str("# 貢献度\n");
+            for contribution in controller.get_contributions() {
+                s.push_str(&format!(
+                    "- {} 【{}戦 {}勝】 旗 {:+} 本、まとい {} 回（{} 手番）\n",
+                    escape_name(&contribution.name),
+                    contribution.games,
+                    contribution.wins,
+                    contribution.flags,
+                    contribution.matoi_captures,
+                    contribution.rolls
+                ));
+            }
+            s.push_str("```");
+            let _ = cli.sender().send_message(chid, &s);
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// target_id のハンデを表示する。handicap が指定された場合は、管理者だけが設定できる
+// handicap が Some(Ok(None)) の場合は、勝率から自動で決めるように戻す
+// handicap が Some(Err(_)) の場合は、本数として受け付けられない指定だったので、設定せずに範囲を伝える
+pub fn on_hatagenpei_handicap(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    chid: &String,
+    is_admin: bool,
+    target_id: &String,
+    target_name: &String,
+    handicap: Option<Result<Option<i32>, String>>,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_handicap");
+    let out_of_range = |arg: &str| {
+        format!(
+            "{} はハンデに指定できないげん。0 から {} までの中旗の本数か 自動 で指定するげん",
+            arg, HANDICAP_MAX
+        )
+    };
+    match controller {
+        Some(controller) => {
+            let s = match handicap {
+                Some(_) if !is_admin => "ハンデを設定できるのは管理者だけげん".to_string(),
+                Some(Err(arg)) => out_of_range(&arg),
+                Some(Ok(handicap)) => {
+                    match controller.set_handicap(target_id, target_name, handicap) {
+                        Err(_) => out_of_range(&handicap.unwrap_or_default().to_string()),
+                        Ok(()) => match handicap {
+                            Some(handicap) => {
+                                format!("{} のハンデを中旗 {} 本にしたげん", target_name, handicap)
+                            }
+                            None => {
+                                format!("{} のハンデを勝率から決めるようにしたげん", target_name)
+                            }
+                        },
+                    }
+                }
+                None => format!(
+                    "{} のハンデは中旗 {} 本げん",
+                    target_name,
+                    controller.get_handicap(target_id)
+                ),
+            };
+            let _ = cli.sender().send_message(chid, &s);
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// 進行中の旗源平を降参し、負けとして記録する
+pub fn on_hatagenpei_surrender(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    message_user_name: &String,
+    message_user_id: &String,
+    chid: &String,
+) -> Result<(), failure::Error> {
+    info!(
+        "called on_hatagenpei_surrender, user_id = {}",
+        message_user_id
+    );
+    match controller {
+        Some(controller) => {
+            let s = match controller.surrender(message_user_id, message_user_name) {
+                Some(logs) => ["```", &logs.join("\n"), "```"].concat(),
+                None => format!("{} は旗源平をしていないげん", message_user_name),
+            };
+            let _ = cli.sender().send_message(chid, &s);
+        }
+        None => {
+            // do nothing
+        }
+    }
 
-            let prefix = if res.is_start {
-                "旗源平を始めるげん!\n\n"
-            } else {
-                ""
+    return Ok(());
+}
+
+// target の進行中の旗源平を、勝敗を記録せずに破棄する。管理者だけが実行できる
+// メンションからユーザーがわからなかった場合、target は Err(メンション) になる
+pub fn on_hatagenpei_abandon(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    chid: &String,
+    is_admin: bool,
+    target: Result<(&String, &String), &String>,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_abandon, target = {:?}", target);
+    match controller {
+        Some(controller) => {
+            let s = match target {
+                _ if !is_admin => "ゲームを破棄できるのは管理者だけげん".to_string(),
+                Err(mention) => format!("{} が誰か見つからないげん", mention),
+                Ok((target_id, target_name)) => {
+                    if controller.abandon(target_id) {
+                        format!("{} の旗源平を破棄したげん", target_name)
+                    } else {
+                        format!("{} は旗源平をしていないげん", target_name)
+                    }
+                }
             };
+            let _ = cli.sender().send_message(chid, &s);
+        }
+        None => {
+            // do nothing
+        }
+    }
 
-            let joined_logs = [prefix, "```", &res.logs.join("\n"), "```"].concat();
+    return Ok(());
+}
 
-            let _ = cli.sender().send_message(chid, &joined_logs);
+// name1 と name2 の bot 同士の模範試合を行い、1 手番ずつ間を空けて投稿する
+// 名前が指定されない場合は、出せる bot の一覧を表示する
+pub fn on_hatagenpei_exhibition(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    chid: &String,
+    names: Option<(&str, &str)>,
+    rule_set_id: Option<&str>,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_exhibition");
+    match controller {
+        Some(controller) => {
+            let (name1, name2) = match names {
+                Some(names) => names,
+                None => {
+                    let personas = controller.get_personas();
+                    let s = if personas.is_empty() {
+                        "模範試合に出られる bot はいないげん".to_string()
+                    } else {
+                        let mut lines = vec!["模範試合に出られる bot の一覧げん".to_string()];
+                        for persona in personas {
+                            lines.push(format!(
+                                "- {} （ハンデ : 中旗 {} 本、ルール : {}）",
+                                persona.name, persona.handicap, persona.rule_set_id
+                            ));
+                        }
+                        lines.push("`旗源平 模範 <名前> <名前>` で対戦させるげん".to_string());
+                        lines.join("\n")
+                    };
+                    let _ = cli.sender().send_message(chid, &s);
+                    return Ok(());
+                }
+            };
+
+            match controller.exhibition(name1, name2, rule_set_id) {
+                Ok(res) => {
+                    // 試合はもう終わっているので、投稿だけを別スレッドで間を空けて行う
+                    let sender = cli.sender().clone();
+                    let chid = chid.clone();
+                    let header = format!(
+                        "{} と {} の模範試合を始めるげん！\nルール : {}",
+                        name1, name2, res.rule_set_name
+                    );
+                    std::thread::spawn(move || {
+                        let _ = sender.send_message(&chid, &header);
+                        for turn in res.turns {
+                            std::thread::sleep(EXHIBITION_TURN_INTERVAL);
+                            let _ = sender
+                                .send_message(&chid, &["```", &turn.join("\n"), "```"].concat());
+                        }
+                        std::thread::sleep(EXHIBITION_TURN_INTERVAL);
+                        let _ = sender.send_message(&chid, &format!("{} の勝ちげん", res.winner));
+                    });
+                }
+                Err(err) => {
+                    let _ = cli
+                        .sender()
+                        .send_message(chid, &exhibition_error_message(&err));
+                }
+            }
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// 模範試合の bot ごとの勝敗を表示する
+pub fn on_hatagenpei_exhibition_stats(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    chid: &String,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_exhibition_stats");
+    match controller {
+        Some(controller) => {
+            let mut lines = vec!["## 模範試合の勝敗".to_string()];
+            for win_lose in controller.get_exhibition_win_loses() {
+                lines.push(format!(
+                    "- {} : {} 勝 {} 敗",
+                    win_lose.name, win_lose.win, win_lose.lose
+                ));
+            }
+            let _ = cli
+                .sender()
+                .send_message(chid, &["```", &lines.join("\n"), "```"].concat());
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// 遊べるルールの一覧を表示する
+pub fn on_hatagenpei_rule_sets(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    chid: &String,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_rule_sets");
+    match controller {
+        Some(controller) => {
+            let mut s = "```".to_string();
+            s.push_str("# ルール\n");
+            for rule_set in controller.get_rule_sets() {
+                s.push_str(&format!("- {} : {}\n", rule_set.id, rule_set.name));
+            }
+            s.push_str("```");
+            let _ = cli.sender().send_message(chid, &s);
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// 進行中の旗源平のゲームの勝率を表示する
+pub fn on_hatagenpei_win_probability(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    message_user_name: &String,
+    message_user_id: &String,
+    chid: &String,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_win_probability");
+    match controller {
+        Some(controller) => {
+            let s = match controller.get_win_probability(message_user_id) {
+                Some(probability) => format!(
+                    "```{} の勝率 : {:.1} %\nゲームが終わるまでの手番数の期待値 : {:.1}```",
+                    message_user_name,
+                    probability.player1 * 100.0,
+                    probability.expected_turns
+                ),
+                None => format!("{} の進行中のゲームが見つからないげん", message_user_name),
+            };
+            let _ = cli.sender().send_message(chid, &s);
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// target_id のレーティングと偏差、変動率、直近のゲームでの推移を表示する
+pub fn on_hatagenpei_rating(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    chid: &String,
+    target_id: &String,
+    target_name: &String,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_rating, target_id = {}", target_id);
+    match controller {
+        Some(controller) => {
+            // 推移を出すために、表示するゲームの 1 つ前の記録まで取る
+            let ratings = controller.get_ratings(target_id, RATING_TREND_COUNT + 1);
+            let latest = match ratings.first() {
+                Some(latest) => &latest.rating,
+                None => {
+                    let _ = cli
+                        .sender()
+                        .send_message(chid, &format!("{} のレートはまだないげん", target_name));
+                    return Ok(());
+                }
+            };
+
+            // 記録が足りなければ、最初のゲームの前は初期値だったとみなす
+            let mut trend = ratings
+                .iter()
+                .rev()
+                .map(|record| record.rating.rating)
+                .collect::<Vec<_>>();
+            if ratings.len() <= RATING_TREND_COUNT {
+                trend.insert(0, INIT_RATING);
+            }
+            let diffs = trend
+                .windows(2)
+                .map(|w| format!("{:+.0}", w[1] - w[0]))
+                .collect::<Vec<_>>();
+
+            let s = format!(
+                "```{} のレート : {:.0}（RD {:.0}、σ {:.4}）\n直近 {} 戦の推移 : {}（計 {:+.0}）```",
+                target_name,
+                latest.rating,
+                latest.deviation,
+                latest.volatility,
+                diffs.len(),
+                diffs.join(" / "),
+                trend[trend.len() - 1] - trend[0]
+            );
+            let _ = cli.sender().send_message(chid, &s);
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// target_id の終了したゲームの履歴から計算した統計を表示する
+pub fn on_hatagenpei_stats(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    chid: &String,
+    target_id: &String,
+    target_name: &String,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_stats, target_id = {}", target_id);
+    match controller {
+        Some(controller) => {
+            let stats = controller.get_player_stats(target_id);
+            if stats.duel_games == 0 {
+                let _ = cli.sender().send_message(
+                    chid,
+                    &format!("{} の集計できるゲームが見つからないげん", target_name),
+                );
+                return Ok(());
+            }
+
+            let win_rate = |(win, lose): (i32, i32)| {
+                if win + lose == 0 {
+                    return format!("{}勝 {}敗", win, lose);
+                }
+                return format!(
+                    "{}勝 {}敗（勝率 {:.1} %）",
+                    win,
+                    lose,
+                    win as f64 * 100.0 / (win + lose) as f64
+                );
+            };
+            let frequent_dice = stats
+                .frequent_dice
+                .iter()
+                .map(|((dice1, dice2), count)| format!("{}・{}（{} 回）", dice1, dice2, count))
+                .collect::<Vec<_>>();
+
+            let mut s = "```".to_string();
+            s.push_str(&format!("# {} の統計\n", target_name));
+            s.push_str(&format!(
+                "ゲーム数 : {}（1対1 {} 戦、卓とチーム戦 {} 戦）\n",
+                stats.games,
+                stats.duel_games,
+                stats.games - stats.duel_games
+            ));
+            s.push_str(&format!("先手 : {}\n", win_rate(stats.first)));
+            s.push_str(&format!("後手 : {}\n", win_rate(stats.second)));
+            s.push_str(&format!("よく出た目 : {}\n", frequent_dice.join(" / ")));
+            s.push_str(&format!(
+                "最長の振り直し : {} 回連続\n",
+                stats.longest_reroll_chain
+            ));
+            if let Some(turns) = stats.average_turns_to_win {
+                s.push_str(&format!("勝つまでの平均手番数 : {:.1}\n", turns));
+            }
+            if let Some(comeback) = stats.biggest_comeback {
+                s.push_str(&format!(
+                    "最大の逆転 : 旗が小旗 {} 本分まで減ってから勝った\n",
+                    comeback
+                ));
+            }
+            s.push_str(&format!(
+                "最長連勝 : {} / 最長連敗 : {}",
+                stats.longest_win_streak, stats.longest_lose_streak
+            ));
+            s.push_str("```");
+            let _ = cli.sender().send_message(chid, &s);
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// 直近に終了した旗源平のゲームを、リプレイから再実行して検証する
+pub fn on_hatagenpei_verify(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    message_user_name: &String,
+    message_user_id: &String,
+    chid: &String,
+    game_seed: Option<u64>,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_verify");
+    match controller {
+        Some(controller) => {
+            let s = match controller.verify_latest_replay(message_user_id, game_seed) {
+                None => format!("{} の終了したゲームが見つからないげん", message_user_name),
+                Some((replay, Ok(()))) => match game_seed {
+                    Some(game_seed) => format!(
+                        "シード {} から {} の直近のゲーム（{} step）のすべての出目を計算し直したげん。記録と一致したげん\nシードは開始時に公開したコミットメント {} とも一致したげん",
+                        game_seed,
+                        message_user_name,
+                        replay.steps.len(),
+                        seed_commitment(game_seed)
+                    ),
+                    None => format!(
+                        "{} の直近のゲーム（{} step）を再実行したげん。記録と一致したげん",
+                        message_user_name,
+                        replay.steps.len()
+                    ),
+                },
+                Some((_, Err(ReplayError::CommitmentMismatch))) => format!(
+                    "シード {} は、{} の直近のゲームの開始時に公開したコミットメントと一致しないげん",
+                    game_seed.unwrap_or(0),
+                    message_user_name
+                ),
+                Some((_, Err(ReplayError::SeedMismatch(step)))) => format!(
+                    "{} step 目のシードが、シード {} から計算したものと食い違ったげん",
+                    step + 1,
+                    game_seed.unwrap_or(0)
+                ),
+                Some((_, Err(ReplayError::Diverged { step, turn, .. }))) => format!(
+                    "{} の直近のゲームを再実行したら、{} step 目の {} 番目の手番で記録と食い違ったげん",
+                    message_user_name,
+                    step + 1,
+                    turn + 1
+                ),
+                Some((_, Err(err))) => format!("ゲームを検証できなかったげん : {}", err),
+            };
+            let _ = cli.sender().send_message(chid, &s);
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// 終了した旗源平のゲームを、新しい順に手番ごとに表示する
+pub fn on_hatagenpei_histories(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    message_user_name: &String,
+    message_user_id: &String,
+    chid: &String,
+    count: usize,
+) -> Result<(), failure::Error> {
+    info!("called on_hatagenpei_histories");
+    match controller {
+        Some(controller) => {
+            let histories = controller.get_game_histories(message_user_id, count);
+            if histories.is_empty() {
+                let _ = cli.sender().send_message(
+                    chid,
+                    &format!("{} の終了したゲームが見つからないげん", message_user_name),
+                );
+                return Ok(());
+            }
+
+            let mut s = "```".to_string();
+            for history in histories {
+                // ルールのないゲーム（チンチロリン）は、ゲームの種類を表示する
+                let rule = if history.rule_set_id.is_empty() {
+                    history.game_id.clone()
+                } else {
+                    format!("ルール {}", history.rule_set_id)
+                };
+                s.push_str(&format!(
+                    "# {} （{} の勝ち、{}）\n",
+                    history.players.join(" vs "),
+                    history.winner,
+                    rule
+                ));
+                for (i, game_log) in history.logs.iter().enumerate() {
+                    let explains = game_log
+                        .commands
+                        .iter()
+                        .map(|cmd| cmd.explain.clone())
+                        .collect::<Vec<_>>();
+                    let flags = [&game_log.player1, &game_log.player2]
+                        .iter()
+                        .map(|p| {
+                            format!("{} {}本", p.name, p.my_score.value() + p.got_score.value())
+                        })
+                        .collect::<Vec<_>>();
+                    s.push_str(&format!(
+                        "{}. {} : {} 【{}】\n",
+                        i + 1,
+                        game_log.player(&game_log.player_turn).name,
+                        explains.join(" → "),
+                        flags.join(" / ")
+                    ));
+                }
+                // 卓のゲームは、旗をやり取りした相手と、全員の旗の本数を表示する
+                for (i, game_log) in history.table_logs.iter().enumerate() {
+                    let explains = game_log
+                        .rolls
+                        .iter()
+                        .map(|roll| {
+                            format!(
+                                "{} ({})",
+                                roll.command.explain, game_log.seats[roll.target].player.name
+                            )
+                        })
+                        .collect::<Vec<_>>();
+                    let flags = game_log
+                        .seats
+                        .iter()
+                        .map(|seat| {
+                            format!(
+                                "{} {}本",
+                                seat.player.name,
+                                seat.player.my_score.value() + seat.player.got_score.value()
+                            )
+                        })
+                        .collect::<Vec<_>>();
+                    s.push_str(&format!(
+                        "{}. {} : {} 【{}】\n",
+                        i + 1,
+                        game_log.seats[game_log.roller].player.name,
+                        explains.join(" → "),
+                        flags.join(" / ")
+                    ));
+                }
+                s.push_str("\n");
+            }
+            s.push_str("```");
+            let _ = cli.sender().send_message(chid, &s);
         }
         None => {
             // do nothing
@@ -40,26 +1128,100 @@ pub fn on_hatagenpei(
     return Ok(());
 }
 
+// 旗源平の順位表を order の順に並べて、page ページ目を表示する
+// 呼び出したプレイヤーがそのページに載らない場合は、最後に自分の順位を表示する
 pub fn on_hatagenpei_winloses(
     cli: &RtmClient,
     controller: &mut Option<HatagenpeiController>,
-    _message_user_name: &String,
+    message_user_name: &String,
+    message_user_id: &String,
     chid: &String,
+    order: LeaderboardOrder,
+    page: usize,
 ) -> Result<(), failure::Error> {
     info!("called on_hatagenpei_winloses");
     match controller {
         Some(controller) => {
+            let leaderboard = controller.get_leaderboard(&order, message_user_id, page);
+            let title = match order {
+                LeaderboardOrder::WinRate => {
+                    format!("勝率順（{} 戦以上）", LEADERBOARD_MIN_GAMES)
+                }
+                LeaderboardOrder::Wins => "勝数順".to_string(),
+                LeaderboardOrder::Rating => "レート順".to_string(),
+            };
+            let mut s = "```".to_string();
+            s.push_str(&format!(
+                "# 勝敗 {} {}/{} ページ\n",
+                title, leaderboard.page, leaderboard.page_count
+            ));
+            if leaderboard.entries.is_empty() {
+                s.push_str("まだ順位に入っているプレイヤーがいないげん\n");
+            }
+            for entry in &leaderboard.entries {
+                s.push_str(&format_leaderboard_entry(entry));
+            }
+            if let Some(own) = &leaderboard.own {
+                s.push_str("...\n");
+                s.push_str(&format_leaderboard_entry(own));
+            } else if !leaderboard.is_ranked {
+                s.push_str(&format!("（{} は順位に入っていない）\n", message_user_name));
+            }
+            s.push_str("```");
+            let _ = cli.sender().send_message(chid, &s);
+        }
+        None => {
+            // do nothing
+        }
+    }
+
+    return Ok(());
+}
+
+// player の、opponent との勝敗を表示する（player, opponent は (id, 名前) の組）
+// opponent が指定されない場合は、player の相手ごとの勝敗をすべて表示する
+pub fn on_hatagenpei_head_to_head(
+    cli: &RtmClient,
+    controller: &mut Option<HatagenpeiController>,
+    chid: &String,
+    player: (&String, &String),
+    opponent: Option<(&String, &String)>,
+) -> Result<(), failure::Error> {
+    info!(
+        "called on_hatagenpei_head_to_head, player_id = {}",
+        player.0
+    );
+    match controller {
+        Some(controller) => {
+            let head_to_heads = match opponent {
+                Some((opponent_id, _)) => controller
+                    .get_head_to_head(player.0, opponent_id)
+                    .into_iter()
+                    .collect::<Vec<_>>(),
+                None => controller.get_head_to_heads(player.0),
+            };
+            if head_to_heads.is_empty() {
+                let s = match opponent {
+                    Some((_, opponent_name)) => {
+                        format!("{} と {} はまだ対戦していないげん", player.1, opponent_name)
+                    }
+                    None => format!("{} はまだ誰とも対戦していないげん", player.1),
+                };
+                let _ = cli.sender().send_message(chid, &s);
+                return Ok(());
+            }
+
             let mut s = "```".to_string();
-            s.push_str("# 勝敗\n");
-            for win_lose in controller.get_win_loses() {
-                let escaped_name = escape_name(&win_lose.name);
-                s.push_str(
-                    &format!(
-                        "- {} 【{}勝 {}敗】\n",
-                        escaped_name, win_lose.win, win_lose.lose
-                    )
-                    .to_string(),
-                );
+            s.push_str(&format!("# {} の対戦成績\n", escape_name(player.1)));
+            for head_to_head in head_to_heads {
+                let games = head_to_head.win + head_to_head.lose;
+                s.push_str(&format!(
+                    "- vs {} 【{}勝 {}敗 勝率 {:.1} %】\n",
+                    escape_name(&head_to_head.opponent_name),
+                    head_to_head.win,
+                    head_to_head.lose,
+                    head_to_head.win as f64 * 100.0 / games as f64
+                ));
             }
             s.push_str("```");
             let _ = cli.sender().send_message(chid, &s);
@@ -119,6 +1281,162 @@ fn get_nowtime_string() -> String {
     return tokyo.to_string();
 }
 
+// 順位表の 1 行を表示用の文字列にする
+fn format_leaderboard_entry(entry: &LeaderboardEntry) -> String {
+    let games = entry.win + entry.lose;
+    let win_rate = if games > 0 {
+        entry.win as f64 * 100.0 / games as f64
+    } else {
+        0.0
+    };
+    let rating = match entry.rating {
+        Some(rating) => format!(" レート {:.0}", rating),
+        None => "".to_string(),
+    };
+    return format!(
+        "{}. {} 【{}勝 {}敗 勝率 {:.1} %{}】\n",
+        entry.rank,
+        escape_name(&entry.name),
+        entry.win,
+        entry.lose,
+        win_rate,
+        rating
+    );
+}
+
+fn match_error_message(err: &MatchError) -> String {
+    return match err {
+        MatchError::SelfChallenge => "自分には挑戦できないげん".to_string(),
+        MatchError::BotChallenge => "bot とは `旗源平` だけで遊べるげん".to_string(),
+        MatchError::AlreadyInMatch(name) => format!("{} はもう対戦中げん", name),
+        MatchError::AlreadyChallenged(name) => format!("{} はもう別の挑戦を受けているげん", name),
+        MatchError::RuleSetNotFound(rule_set_id) => {
+            format!("ルール {} は見つからないげん", rule_set_id)
+        }
+        MatchError::ChallengeNotFound(name) => format!("{} への挑戦は見つからないげん", name),
+        MatchError::MatchNotFound(name) => format!("{} は対戦中ではないげん", name),
+        MatchError::NotYourTurn(name) => format!("今は {} の番げん", name),
+    };
+}
+
+fn table_error_message(err: &TableError) -> String {
+    return match err {
+        TableError::TableNotFound => {
+            "このチャンネルに卓はないげん。`旗源平 卓 参加` で開くげん".to_string()
+        }
+        TableError::AlreadyStarted => "卓はもう始まっているげん".to_string(),
+        TableError::NotStarted => "卓はまだ始まっていないげん".to_string(),
+        TableError::AlreadyJoined(name) => format!("{} はもう卓に参加しているげん", name),
+        TableError::NotJoined(name) => format!("{} は卓に参加していないげん", name),
+        TableError::NotEnoughPlayers(count) => format!("卓は {} 人からげん", count),
+        TableError::InvalidTarget(name) => format!("{} は相手に選べないげん", name),
+        TableError::RuleSetNotFound(rule_set_id) => {
+            format!("ルール {} は見つからないげん", rule_set_id)
+        }
+        TableError::NotYourTurn(name) => format!("今は {} の番げん", name),
+    };
+}
+
+fn exhibition_error_message(err: &ExhibitionError) -> String {
+    return match err {
+        ExhibitionError::PersonaNotFound(name) => format!("{} という bot はいないげん", name),
+        ExhibitionError::SamePersona(name) => format!("{} 同士では対戦できないげん", name),
+        ExhibitionError::RuleSetNotFound(rule_set_id) => {
+            format!("ルール {} は見つからないげん", rule_set_id)
+        }
+    };
+}
+
+fn team_error_message(err: &TeamError) -> String {
+    return match err {
+        TeamError::TeamMatchNotFound => {
+            "このチャンネルにチーム戦はないげん。`旗源平 源平 源` か `旗源平 源平 平` で開くげん"
+                .to_string()
+        }
+        TeamError::AlreadyStarted => "チーム戦はもう始まっているげん".to_string(),
+        TeamError::NotStarted => "チーム戦はまだ始まっていないげん".to_string(),
+        TeamError::AlreadyJoined(name) => format!("{} はもうチーム戦に参加しているげん", name),
+        TeamError::NotJoined(name) => format!("{} はチーム戦に参加していないげん", name),
+        TeamError::EmptySide(side) => format!("{} にメンバーがいないげん", side),
+        TeamError::RuleSetNotFound(rule_set_id) => {
+            format!("ルール {} は見つからないげん", rule_set_id)
+        }
+        TeamError::NotYourTurn(name) => format!("今は {} の番げん", name),
+    };
+}
+
+// 模範試合で、手番を投稿する間隔。チャンネルで観戦できるように間を空ける
+const EXHIBITION_TURN_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3);
+
+// レートの推移として表示するゲームの数
+const RATING_TREND_COUNT: usize = 5;
+
+// 1 メッセージに載せる文字数の上限。RTM で送れるメッセージの長さには上限がある
+const MAX_MESSAGE_LENGTH: usize = 3000;
+
+// 盤面の SVG を、chid にファイルとしてアップロードする
+// board_token が None（盤面のアップロードが無効）の場合や、盤面がない場合は何もしない
+fn upload_board(board_token: Option<&String>, chid: &str, board: Option<String>) {
+    let (token, board) = match (board_token, board) {
+        (Some(token), Some(board)) => (token, board),
+        _ => return,
+    };
+    let client = match slack::api::requests::default_client() {
+        Ok(client) => client,
+        Err(err) => {
+            warn!("failed to create slack api client: {}", err);
+            return;
+        }
+    };
+    let request = slack::api::files::UploadRequest {
+        content: Some(&board),
+        filename: Some("hatagenpei.svg"),
+        filetype: Some("svg"),
+        title: Some("旗源平の盤面"),
+        channels: Some(chid),
+        ..Default::default()
+    };
+    if let Err(err) = slack::api::files::upload(&client, token, &request) {
+        warn!("failed to upload board: {}", err);
+    }
+}
+
+// chid の thread_ts のスレッドに返信する
+fn send_thread_message(cli: &RtmClient, chid: &str, thread_ts: &str, text: &str) {
+    let sender = cli.sender();
+    let raw = serde_json::json!({
+        "id": sender.get_msg_uid(),
+        "type": "message",
+        "channel": chid,
+        "text": text,
+        "thread_ts": thread_ts,
+    });
+    if let Err(err) = sender.send(&raw.to_string()) {
+        warn!("failed to send thread message: {}", err);
+    }
+}
+
+// lines を改行でつなぎ、1 つあたり max_len 文字以内になるように分割する
+// 1 行で max_len 文字を超える場合は、その行だけで 1 つにする
+fn split_lines(lines: &[String], max_len: usize) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut chunk = String::new();
+    for line in lines {
+        if !chunk.is_empty() && chunk.chars().count() + line.chars().count() + 1 > max_len {
+            chunks.push(chunk);
+            chunk = String::new();
+        }
+        if !chunk.is_empty() {
+            chunk.push('\n');
+        }
+        chunk.push_str(line);
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    return chunks;
+}
+
 fn escape_name(name: &str) -> String {
     let (_, escaped_name) =
         name.chars()
@@ -138,6 +1456,14 @@ fn escape_name(name: &str) -> String {
 /*****************
 tests
 ******************/
+#[test]
+fn split_lines_test() {
+    let lines = vec!["abc".to_string(), "de".to_string(), "fghij".to_string()];
+    assert_eq!(split_lines(&lines, 6), vec!["abc\nde", "fghij"]);
+    assert_eq!(split_lines(&lines, 3), vec!["abc", "de", "fghij"]);
+    assert!(split_lines(&[], 6).is_empty());
+}
+
 #[test]
 fn get_nowtime_string_test() {
     use regex::Regex;
@@ -19,10 +19,14 @@
 
 use crate::hatagenpei::controller::DataStore;
 use crate::hatagenpei::controller::*;
+use crate::hatagenpei::persona::Persona;
+use crate::hatagenpei::rule_set::RuleSet;
+use crate::hatagenpei::team::Side;
 use log::{debug, info, warn};
 use slack::api::rtm::StartResponse;
-use slack::api::{Message, MessageStandard};
+use slack::api::{Message, MessageStandard, User};
 use slack::{Event, RtmClient};
+use std::collections::BTreeMap;
 
 #[derive(Debug, Fail)]
 enum EventHandlerError {
@@ -41,13 +45,43 @@ pub struct MyHandler {
     myuid: String,
     myname: String,
     data_store: DataStore,
+    rule_sets: Vec<RuleSet>,
+    /// 模範試合に出せる bot の一覧
+    personas: Vec<Persona>,
+    /// 管理者の user_id の一覧
+    admins: Vec<String>,
+    /// 盤面の SVG をアップロードするときに使う Slack の token。None なら盤面はアップロードしない
+    board_token: Option<String>,
+    /// 最後にサイコロを振ってからこの日数がたった bot とのゲームは、自動で破棄する。None なら破棄しない
+    expire_days: Option<i64>,
+    /// 次に接続したときに、表示名をキーにして記録していた頃のデータを user_id をキーにして記録し直すかどうか
+    /// 再接続のたびに実行しないように、1 度実行したら false に戻す
+    migrate_player_ids: bool,
+    /// 次に接続したときに、相手ごとの勝敗を記録する前の bot の勝敗を相手ごとの勝敗に書くかどうか
+    /// 書くのは 1 度だけでよいので、再接続のたびに実行しないように、1 度実行したら false に戻す
+    backfill_bot_head_to_heads: bool,
     hatagenpei_controller: Option<HatagenpeiController>,
 }
 
 impl MyHandler {
-    pub fn new(data_store: DataStore) -> MyHandler {
+    pub fn new(
+        data_store: DataStore,
+        rule_sets: Vec<RuleSet>,
+        personas: Vec<Persona>,
+        admins: Vec<String>,
+        board_token: Option<String>,
+        expire_days: Option<i64>,
+        migrate_player_ids: bool,
+    ) -> MyHandler {
         return MyHandler {
             data_store: data_store,
+            rule_sets: rule_sets,
+            personas: personas,
+            admins: admins,
+            board_token: board_token,
+            expire_days: expire_days,
+            migrate_player_ids: migrate_player_ids,
+            backfill_bot_head_to_heads: true,
             start_response: None,
             myuid: "".to_string(),
             myname: "".to_string(),
@@ -62,24 +96,46 @@ impl MyHandler {
         let res = users
             .into_iter()
             .find(|u| return u.id == Some(user_id.to_string()))?;
+        return username(res);
+    }
 
-        let display_name = match &res.profile {
-            Some(profile) => match &profile.display_name {
-                Some(name) => name.clone(),
-                _ => "".to_string(),
-            },
-            _ => "".to_string(),
-        };
-        // もし diaplay_name が "" なら、res.real_name を使う
-        let name = if display_name == "" {
-            res.real_name.clone()
-        } else {
-            Some(display_name)
+    /// "<@U12345>" の形式のメンションから、(user_id, username) を取得する
+    fn retrieve_user_from_mention(&self, mention: &str) -> Option<(String, String)> {
+        let user_id = extract_user_id(mention)?;
+        let name = self.retrieve_username_from_user_id(&user_id)?;
+        return Some((user_id, name));
+    }
+
+    /// 起動時のユーザー一覧から、username から user_id への対応表を作る
+    /// 表示名をキーにして記録していた頃のデータを、user_id をキーにして記録し直すのに使う
+    fn retrieve_player_ids(&self) -> BTreeMap<String, String> {
+        let mut ids = BTreeMap::new();
+        let users = match self.start_response.as_ref().and_then(|r| r.users.as_ref()) {
+            Some(users) => users,
+            None => return ids,
         };
-        return name;
+        for user in users {
+            if let (Some(id), Some(name)) = (&user.id, username(user)) {
+                ids.insert(name, id.clone());
+            }
+        }
+        return ids;
+    }
+
+    /// 期限切れの bot とのゲームを破棄する
+    fn expire_stale_games(&mut self) {
+        if let Some(controller) = self.hatagenpei_controller.as_mut() {
+            for progress in controller.expire_stale_games(chrono::Utc::now().timestamp()) {
+                info!(
+                    "expired a stale game, user_id = {}, name = {}",
+                    progress.user.id, progress.user.name
+                );
+            }
+        }
     }
 
     fn on_message(&mut self, cli: &RtmClient, message: &Message) -> Result<(), failure::Error> {
+        self.expire_stale_games();
         match message {
             Message::Standard(ms) => {
                 let bot_id = &ms.bot_id;
@@ -103,9 +159,12 @@ impl MyHandler {
                             .trim_start()
                             .to_string();
                         // メンションに対する処理
+                        // スレッド内のメンションならそのスレッドに、そうでなければメンションに返信する
+                        let thread_ts = ms.thread_ts.as_ref().or(ms.ts.as_ref());
                         self.on_mention(
                             cli,
                             chid,
+                            thread_ts,
                             &message_user_name,
                             &message_user_id,
                             text_without_mention,
@@ -138,6 +197,7 @@ impl MyHandler {
         &mut self,
         cli: &RtmClient,
         chid: &String,
+        thread_ts: Option<&String>,
         message_user_name: &String,
         message_user_id: &String,
         text_without_mention: &String,
@@ -168,27 +228,395 @@ impl MyHandler {
             ),
             (
                 "旗源平",
-                "旗源平 - 旗源平で遊ぶ",
-                Box::new(move |handler, _| {
-                    on_hatagenpei(
+                "旗源平 - 旗源平で遊ぶ\n旗源平 一気 [<id>] - bot との旗源平を決着まで一気に進める。全手番のログはスレッドに投稿する\n旗源平 出目 <目> <目> [<id>] - 実物のサイコロを振って出た目で、bot との旗源平を進める。<id> を指定すると、新しく始めるゲームをそのルールで遊ぶ\n旗源平 ルール [<id>] - ルールの一覧を表示する。<id> を指定すると、そのルールで遊ぶ\n旗源平 挑戦 @相手 [<id>] - 相手に旗源平の対戦を挑む\n旗源平 受諾 / 旗源平 辞退 - 挑戦を受ける / 断る\n旗源平 卓 参加 [<id>] - チャンネルの卓に参加する（3人以上で遊ぶ）\n旗源平 卓 開始 - 卓のゲームを始める\n旗源平 卓 [@相手] - 卓でサイコロを振る。相手を指定しない場合は順番に回す\n旗源平 源平 源 / 旗源平 源平 平 [<id>] - チャンネルのチーム戦に源氏 / 平家として参加する\n旗源平 源平 開始 - チーム戦を始める\n旗源平 源平 - チーム戦でチームの代表としてサイコロを振る\n旗源平 源平 成績 - チーム戦の陣営の勝敗とメンバーの貢献度を表示する\n旗源平 模範 [<名前> <名前> [<id>]] - bot 同士の模範試合を行う。名前を指定しない場合は、出られる bot の一覧を表示する\n旗源平 模範 成績 - 模範試合の bot ごとの勝敗を表示する\n旗源平 ハンデ [@相手 [<中旗の本数> | 自動]] - ハンデを表示する。本数を指定すると設定する（管理者のみ）\n旗源平 降参 - 進行中のゲームを降参する（負けとして記録される）\n旗源平 破棄 [@相手] - 進行中のゲームを、勝敗を記録せずに破棄する（管理者のみ）\n旗源平 確率 - 進行中のゲーム（bot とのゲームか人間同士の対戦）の勝率を表示する\n旗源平 検証 [<シード>] - 直近に終了したゲームを、リプレイから再実行して検証する。終了時に公開したシードを指定すると、シードからすべての出目を計算し直す\n旗源平 レート [@相手] - Glicko-2 のレート、偏差（RD）、変動率（σ）と、直近 5 戦の推移を表示する\n旗源平 統計 [@相手] - 終了したゲームから、先手・後手の勝率、よく出た目、最長の振り直し、最大の逆転、最長の連勝・連敗などを表示する\n旗源平 履歴 [<件数>] - 終了したゲームを新しい順に、手番ごとに表示する（最大 5 件）",
+                Box::new(move |handler, arg| {
+                    let mut args = arg.split_whitespace();
+                    match args.next() {
+                        Some("ルール") => match args.next() {
+                            Some(rule_set_id) => {
+                                on_hatagenpei(
+                                    cli,
+                                    &mut handler.hatagenpei_controller,
+                                    message_user_name,
+                                    message_user_id,
+                                    chid,
+                                    handler.board_token.as_ref(),
+                                    Some(rule_set_id),
+                                )?;
+                            }
+                            None => {
+                                on_hatagenpei_rule_sets(
+                                    cli,
+                                    &mut handler.hatagenpei_controller,
+                                    chid,
+                                )?;
+                            }
+                        },
+                        Some("出目") => {
+                            let face = |arg: Option<&str>| {
+                                arg.and_then(|s| s.parse::<u8>().ok())
+                                    .filter(|face| 1 <= *face && *face <= 6)
+                            };
+                            let dice = match (face(args.next()), face(args.next())) {
+                                (Some(dice1), Some(dice2)) => Some((dice1, dice2)),
+                                _ => None,
+                            };
+                            on_hatagenpei_dice(
+                                cli,
+                                &mut handler.hatagenpei_controller,
+                                message_user_name,
+                                message_user_id,
+                                chid,
+                                handler.board_token.as_ref(),
+                                dice,
+                                args.next(),
+                            )?;
+                        }
+                        Some("挑戦") => {
+                            let challenged = args
+                                .next()
+                                .and_then(|mention| handler.retrieve_user_from_mention(mention));
+                            on_hatagenpei_challenge(
+                                cli,
+                                &mut handler.hatagenpei_controller,
+                                message_user_name,
+                                message_user_id,
+                                chid,
+                                challenged.as_ref().map(|(id, name)| (id, name)),
+                                args.next(),
+                            )?;
+                        }
+                        Some("受諾") => {
+                            on_hatagenpei_accept(
+                                cli,
+                                &mut handler.hatagenpei_controller,
+                                message_user_name,
+                                message_user_id,
+                                chid,
+                            )?;
+                        }
+                        Some("辞退") => {
+                            on_hatagenpei_decline(
+                                cli,
+                                &mut handler.hatagenpei_controller,
+                                message_user_name,
+                                message_user_id,
+                                chid,
+                            )?;
+                        }
+                        Some("卓") => match args.next() {
+                            Some("参加") => {
+                                on_hatagenpei_table_join(
+                                    cli,
+                                    &mut handler.hatagenpei_controller,
+                                    message_user_name,
+                                    message_user_id,
+                                    chid,
+                                    args.next(),
+                                )?;
+                            }
+                            Some("開始") => {
+                                on_hatagenpei_table_start(
+                                    cli,
+                                    &mut handler.hatagenpei_controller,
+                                    message_user_name,
+                                    message_user_id,
+                                    chid,
+                                )?;
+                            }
+                            target => {
+                                let target = target.and_then(|mention| {
+                                    handler.retrieve_user_from_mention(mention)
+                                });
+                                on_hatagenpei_table_step(
+                                    cli,
+                                    &mut handler.hatagenpei_controller,
+                                    message_user_name,
+                                    message_user_id,
+                                    chid,
+                                    target.as_ref().map(|(id, name)| (id, name)),
+                                )?;
+                            }
+                        },
+                        Some("源平") => match args.next() {
+                            Some(side @ "源") | Some(side @ "平") => {
+                                let side = if side == "源" {
+                                    Side::Genji
+                                } else {
+                                    Side::Heike
+                                };
+                                on_hatagenpei_team_join(
+                                    cli,
+                                    &mut handler.hatagenpei_controller,
+                                    message_user_name,
+                                    message_user_id,
+                                    chid,
+                                    side,
+                                    args.next(),
+                                )?;
+                            }
+                            Some("開始") => {
+                                on_hatagenpei_team_start(
+                                    cli,
+                                    &mut handler.hatagenpei_controller,
+                                    message_user_name,
+                                    message_user_id,
+                                    chid,
+                                )?;
+                            }
+                            Some("成績") => {
+                                on_hatagenpei_team_stats(
+                                    cli,
+                                    &mut handler.hatagenpei_controller,
+                                    chid,
+                                )?;
+                            }
+                            _ => {
+                                on_hatagenpei_team_step(
+                                    cli,
+                                    &mut handler.hatagenpei_controller,
+                                    message_user_name,
+                                    message_user_id,
+                                    chid,
+                                    handler.board_token.as_ref(),
+                                )?;
+                            }
+                        },
+                        Some("模範") => match args.next() {
+                            Some("成績") => {
+                                on_hatagenpei_exhibition_stats(
+                                    cli,
+                                    &mut handler.hatagenpei_controller,
+                                    chid,
+                                )?;
+                            }
+                            name1 => {
+                                let names = match (name1, args.next()) {
+                                    (Some(name1), Some(name2)) => Some((name1, name2)),
+                                    _ => None,
+                                };
+                                on_hatagenpei_exhibition(
+                                    cli,
+                                    &mut handler.hatagenpei_controller,
+                                    chid,
+                                    names,
+                                    args.next(),
+                                )?;
+                            }
+                        },
+                        Some("ハンデ") => {
+                            let (target_id, target_name) = args
+                                .next()
+                                .and_then(|mention| handler.retrieve_user_from_mention(mention))
+                                .unwrap_or((message_user_id.clone(), message_user_name.clone()));
+                            // 本数として読めない値や範囲外の値は、設定せずに Err で伝える
+                            let handicap = match args.next() {
+                                Some("自動") => Some(Ok(None)),
+                                Some(count) => Some(
+                                    count
+                                        .parse::<i32>()
+                                        .ok()
+                                        .filter(|count| 0 <= *count && *count <= HANDICAP_MAX)
+                                        .map(Some)
+                                        .ok_or_else(|| count.to_string()),
+                                ),
+                                None => None,
+                            };
+                            let is_admin = handler.admins.contains(message_user_id);
+                            on_hatagenpei_handicap(
+                                cli,
+                                &mut handler.hatagenpei_controller,
+                                chid,
+                                is_admin,
+                                &target_id,
+                                &target_name,
+                                handicap,
+                            )?;
+                        }
+                        Some("降参") => {
+                            on_hatagenpei_surrender(
+                                cli,
+                                &mut handler.hatagenpei_controller,
+                                message_user_name,
+                                message_user_id,
+                                chid,
+                            )?;
+                        }
+                        Some("破棄") => {
+                            // 別のユーザーのゲームを消さないように、読めないメンションは呼んだ人に置き換えずに Err で伝える
+                            let target = match args.next() {
+                                Some(mention) => handler
+                                    .retrieve_user_from_mention(mention)
+                                    .ok_or_else(|| mention.to_string()),
+                                None => Ok((message_user_id.clone(), message_user_name.clone())),
+                            };
+                            let is_admin = handler.admins.contains(message_user_id);
+                            on_hatagenpei_abandon(
+                                cli,
+                                &mut handler.hatagenpei_controller,
+                                chid,
+                                is_admin,
+                                target.as_ref().map(|(id, name)| (id, name)),
+                            )?;
+                        }
+                        Some("一気") => {
+                            on_hatagenpei_auto(
+                                cli,
+                                &mut handler.hatagenpei_controller,
+                                message_user_name,
+                                message_user_id,
+                                chid,
+                                thread_ts,
+                                args.next(),
+                            )?;
+                        }
+                        Some("確率") => {
+                            on_hatagenpei_win_probability(
+                                cli,
+                                &mut handler.hatagenpei_controller,
+                                message_user_name,
+                                message_user_id,
+                                chid,
+                            )?;
+                        }
+                        Some("レート") => {
+                            let (target_id, target_name) = args
+                                .next()
+                                .and_then(|mention| handler.retrieve_user_from_mention(mention))
+                                .unwrap_or((message_user_id.clone(), message_user_name.clone()));
+                            on_hatagenpei_rating(
+                                cli,
+                                &mut handler.hatagenpei_controller,
+                                chid,
+                                &target_id,
+                                &target_name,
+                            )?;
+                        }
+                        Some("統計") => {
+                            let (target_id, target_name) = args
+                                .next()
+                                .and_then(|mention| handler.retrieve_user_from_mention(mention))
+                                .unwrap_or((message_user_id.clone(), message_user_name.clone()));
+                            on_hatagenpei_stats(
+                                cli,
+                                &mut handler.hatagenpei_controller,
+                                chid,
+                                &target_id,
+                                &target_name,
+                            )?;
+                        }
+                        Some("履歴") => {
+                            let count = args
+                                .next()
+                                .and_then(|s| s.parse::<usize>().ok())
+                                .unwrap_or(1);
+                            on_hatagenpei_histories(
+                                cli,
+                                &mut handler.hatagenpei_controller,
+                                message_user_name,
+                                message_user_id,
+                                chid,
+                                std::cmp::min(count, 5),
+                            )?;
+                        }
+                        Some("検証") => {
+                            let game_seed = args.next().and_then(|s| s.parse::<u64>().ok());
+                            on_hatagenpei_verify(
+                                cli,
+                                &mut handler.hatagenpei_controller,
+                                message_user_name,
+                                message_user_id,
+                                chid,
+                                game_seed,
+                            )?;
+                        }
+                        _ => {
+                            on_hatagenpei(
+                                cli,
+                                &mut handler.hatagenpei_controller,
+                                message_user_name,
+                                message_user_id,
+                                chid,
+                                handler.board_token.as_ref(),
+                                None,
+                            )?;
+                        }
+                    }
+                    return Ok(());
+                }),
+            ),
+            (
+                "チンチロ",
+                "チンチロ [<賭け数>] - bot を親にして、チップを賭けてチンチロリンで遊ぶ",
+                Box::new(move |handler, arg| {
+                    let bet = arg
+                        .split_whitespace()
+                        .next()
+                        .and_then(|s| s.parse::<i32>().ok());
+                    on_chinchirorin(
                         cli,
                         &mut handler.hatagenpei_controller,
                         message_user_name,
                         message_user_id,
                         chid,
+                        bet,
+                    )?;
+                    return Ok(());
+                }),
+            ),
+            (
+                "対戦成績",
+                "対戦成績 [@相手 [@相手]] - 相手ごとの旗源平の勝敗を表示する。2人指定すると、1人目から見た2人目との勝敗を表示する",
+                Box::new(move |handler, arg| {
+                    let users = arg
+                        .split_whitespace()
+                        .filter_map(|mention| handler.retrieve_user_from_mention(mention))
+                        .collect::<Vec<_>>();
+                    // 1人だけ指定された場合は、自分から見たその相手との勝敗にする
+                    let (player, opponent) = match users.len() {
+                        0 => ((message_user_id.clone(), message_user_name.clone()), None),
+                        1 => (
+                            (message_user_id.clone(), message_user_name.clone()),
+                            Some(users[0].clone()),
+                        ),
+                        _ => (users[0].clone(), Some(users[1].clone())),
+                    };
+                    on_hatagenpei_head_to_head(
+                        cli,
+                        &mut handler.hatagenpei_controller,
+                        chid,
+                        (&player.0, &player.1),
+                        opponent.as_ref().map(|(id, name)| (id, name)),
                     )?;
                     return Ok(());
                 }),
             ),
             (
                 "勝敗",
-                "勝敗 - 旗源平の勝敗を表示する",
-                Box::new(move |handler, _| {
+                "勝敗 [勝率 | 勝数 | レート] [--page <ページ>] - 旗源平の順位表を表示する。勝率順は 5 戦以上遊んだプレイヤーだけが入る",
+                Box::new(move |handler, arg| {
+                    let mut order = LeaderboardOrder::WinRate;
+                    let mut page = 1;
+                    let mut args = arg.split_whitespace();
+                    while let Some(word) = args.next() {
+                        match word {
+                            "勝率" => order = LeaderboardOrder::WinRate,
+                            "勝数" => order = LeaderboardOrder::Wins,
+                            "レート" => order = LeaderboardOrder::Rating,
+                            "--page" => {
+                                page = args.next().and_then(|s| s.parse::<usize>().ok()).unwrap_or(1)
+                            }
+                            _ => {}
+                        }
+                    }
                     on_hatagenpei_winloses(
                         cli,
                         &mut handler.hatagenpei_controller,
                         message_user_name,
+                        message_user_id,
                         chid,
+                        order,
+                        page,
                     )?;
                     return Ok(());
                 }),
@@ -230,6 +658,32 @@ impl MyHandler {
     }
 }
 
+/// user の username を取得する。表示名が空の場合は、本名を使う
+fn username(user: &User) -> Option<String> {
+    let display_name = match &user.profile {
+        Some(profile) => match &profile.display_name {
+            Some(name) => name.clone(),
+            _ => "".to_string(),
+        },
+        _ => "".to_string(),
+    };
+    // もし diaplay_name が "" なら、user.real_name を使う
+    let name = if display_name == "" {
+        user.real_name.clone()
+    } else {
+        Some(display_name)
+    };
+    return name;
+}
+
+/// "<@U12345>" や "<@U12345|name>" の形式のメンションから、user_id を取り出す
+fn extract_user_id(mention: &str) -> Option<String> {
+    use regex::Regex;
+    let re = Regex::new(r"^<@(?P<id>[0-9A-Za-z]+)(\|[^>]*)?>$").unwrap();
+    let caps = re.captures(mention)?;
+    return Some(caps["id"].to_string());
+}
+
 #[allow(unused_variables)]
 impl slack::EventHandler for MyHandler {
     fn on_event(&mut self, cli: &RtmClient, event: Event) {
@@ -273,10 +727,33 @@ impl slack::EventHandler for MyHandler {
         self.start_response = Some(cli.start_response().clone());
         self.myuid = uid;
         self.myname = myname.clone();
-        self.hatagenpei_controller = Some(HatagenpeiController::new(
-            factor_operater(&self.data_store),
-            &self.myname,
-        ));
+        let mut controller =
+            HatagenpeiController::new(factor_operater(&self.data_store), &self.myuid, &self.myname);
+        // 表示名をキーにして記録していた頃のデータがあれば、user_id をキーにして記録し直す
+        if self.migrate_player_ids {
+            controller.migrate_player_ids(&self.retrieve_player_ids());
+            self.migrate_player_ids = false;
+        }
+        // 相手ごとの勝敗を記録する前の bot の勝敗を、相手ごとの勝敗に書く
+        // 前回の起動までに書いてあれば、bot の相手ごとの勝敗があるので何もしない
+        if self.backfill_bot_head_to_heads {
+            let backfilled = controller.backfill_bot_head_to_heads();
+            if backfilled > 0 {
+                info!(
+                    "backfilled head to heads with the bot, players = {}",
+                    backfilled
+                );
+            }
+            self.backfill_bot_head_to_heads = false;
+        }
+        for rule_set in &self.rule_sets {
+            controller.add_rule_set(rule_set.clone());
+        }
+        for persona in &self.personas {
+            controller.add_persona(persona.clone());
+        }
+        controller.set_expire_after(self.expire_days.map(|days| days * 24 * 60 * 60));
+        self.hatagenpei_controller = Some(controller);
 
         // Send a message over the real time api websocket
     }
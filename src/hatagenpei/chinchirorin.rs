@@ -0,0 +1,281 @@
+//!
+//! bot を親にして、チップを賭けて遊ぶチンチロリン
+//! 親（bot）から先にサイコロを3つ振り、役が決まらなければ子（プレイヤー）も振って勝負する
+//!
+
+use super::dice::DiceSource;
+use super::dice_game::{DiceGame, DiceGameState};
+use serde::{Deserialize, Serialize};
+
+/// 役がつくまでにサイコロを振れる回数
+const MAX_ROLL_COUNT: usize = 3;
+
+/// チンチロリンの役
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum Hand {
+    /// 1・1・1。賭け数の 5 倍をもらう
+    Pinzoro,
+    /// 同じ目が3つ。賭け数の 3 倍をもらう
+    Zorome(u8),
+    /// 4・5・6。賭け数の 2 倍をもらう
+    Shigoro,
+    /// 同じ目が2つと、残りの1つの目
+    Me(u8),
+    /// 3回振っても役がつかない。賭け数を払う
+    Menashi,
+    /// 1・2・3。賭け数の 2 倍を払う
+    Hifumi,
+}
+
+impl Hand {
+    /// 3つのサイコロの目から役を決める
+    pub fn from_dice(dice: [u8; 3]) -> Hand {
+        let mut d = dice;
+        d.sort();
+        return match d {
+            [1, 1, 1] => Hand::Pinzoro,
+            [a, b, c] if a == b && b == c => Hand::Zorome(a),
+            [4, 5, 6] => Hand::Shigoro,
+            [1, 2, 3] => Hand::Hifumi,
+            [a, b, c] if a == b => Hand::Me(c),
+            [a, b, c] if b == c => Hand::Me(a),
+            _ => Hand::Menashi,
+        };
+    }
+
+    /// 役の強さ。大きいほど強い
+    fn strength(&self) -> i32 {
+        return match self {
+            Hand::Pinzoro => 30,
+            Hand::Zorome(n) => 20 + *n as i32,
+            Hand::Shigoro => 10,
+            Hand::Me(n) => *n as i32,
+            Hand::Menashi => 0,
+            Hand::Hifumi => -1,
+        };
+    }
+
+    /// 勝ったときにもらう（負けたときに払う）、賭け数の倍率
+    fn multiplier(&self) -> i32 {
+        return match self {
+            Hand::Pinzoro => 5,
+            Hand::Zorome(_) => 3,
+            Hand::Shigoro | Hand::Hifumi => 2,
+            Hand::Me(_) | Hand::Menashi => 1,
+        };
+    }
+
+    /// 親が出した時点で勝負が決まる役かどうか
+    fn is_decisive(&self) -> bool {
+        return match self {
+            Hand::Me(_) => false,
+            _ => true,
+        };
+    }
+
+    pub fn to_string(&self) -> String {
+        return match self {
+            Hand::Pinzoro => "ピンゾロ".to_string(),
+            Hand::Zorome(n) => format!("{} のゾロ目", n),
+            Hand::Shigoro => "シゴロ".to_string(),
+            Hand::Me(n) => format!("{} の目", n),
+            Hand::Menashi => "目なし".to_string(),
+            Hand::Hifumi => "ヒフミ".to_string(),
+        };
+    }
+}
+
+/// ストレージに保存しておく、チンチロリンの進行状態
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChinchiroProgress {
+    /// 子（プレイヤー）の名前
+    pub player: String,
+    /// 親（bot）の名前
+    pub bot: String,
+    /// プレイヤーの持ちチップ
+    pub player_chips: i32,
+    /// bot の持ちチップ
+    pub bot_chips: i32,
+    /// 次の勝負の賭け数
+    pub bet: i32,
+}
+
+impl ChinchiroProgress {
+    pub fn new(player: &str, bot: &str, chips: i32) -> ChinchiroProgress {
+        return ChinchiroProgress {
+            player: player.to_string(),
+            bot: bot.to_string(),
+            player_chips: chips,
+            bot_chips: chips,
+            bet: 1,
+        };
+    }
+}
+
+/// 1人が役を決めるまでに振った目と、ついた役
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChinchiroRoll {
+    pub rolls: Vec<[u8; 3]>,
+    pub hand: Hand,
+}
+
+/// 1回の勝負のログ
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChinchiroLog {
+    pub bet: i32,
+    pub bot_roll: ChinchiroRoll,
+    /// 親の役で勝負が決まった場合は、子は振らないので None
+    pub player_roll: Option<ChinchiroRoll>,
+    /// プレイヤーがもらったチップの数（払った場合はマイナス）
+    pub payout: i32,
+    /// 勝負の後の、プレイヤーの持ちチップ
+    pub player_chips: i32,
+    /// 勝負の後の、bot の持ちチップ
+    pub bot_chips: i32,
+}
+
+pub struct Chinchirorin {
+    progress: ChinchiroProgress,
+    dice: Box<dyn DiceSource>,
+}
+
+impl Chinchirorin {
+    /// 役がつくまで、最大 MAX_ROLL_COUNT 回サイコロを3つ振る
+    fn roll_hand(&mut self) -> ChinchiroRoll {
+        let mut rolls = vec![];
+        let mut hand = Hand::Menashi;
+        for _ in 0..MAX_ROLL_COUNT {
+            let dice = [self.dice.roll(), self.dice.roll(), self.dice.roll()];
+            rolls.push(dice);
+            hand = Hand::from_dice(dice);
+            if hand != Hand::Menashi {
+                break;
+            }
+        }
+        return ChinchiroRoll {
+            rolls: rolls,
+            hand: hand,
+        };
+    }
+}
+
+impl DiceGame for Chinchirorin {
+    type Progress = ChinchiroProgress;
+    type Config = ();
+    type Log = ChinchiroLog;
+
+    const GAME_ID: &'static str = "chinchirorin";
+
+    fn start(progress: ChinchiroProgress, _: (), dice: Box<dyn DiceSource>) -> Chinchirorin {
+        return Chinchirorin {
+            progress: progress,
+            dice: dice,
+        };
+    }
+
+    fn step(&mut self) -> Option<ChinchiroLog> {
+        if self.state() != DiceGameState::YetPlaying {
+            return None;
+        }
+        let bet = self.progress.bet;
+
+        // 親が先に振り、勝負が決まる役なら子は振らない
+        let bot_roll = self.roll_hand();
+        let (player_roll, payout) = if bot_roll.hand.is_decisive() {
+            let payout = if bot_roll.hand.strength() > 0 {
+                -bet * bot_roll.hand.multiplier()
+            } else {
+                bet * bot_roll.hand.multiplier()
+            };
+            (None, payout)
+        } else {
+            let player_roll = self.roll_hand();
+            let payout = if player_roll.hand.is_decisive() {
+                if player_roll.hand.strength() > 0 {
+                    bet * player_roll.hand.multiplier()
+                } else {
+                    -bet * player_roll.hand.multiplier()
+                }
+            } else {
+                // 目同士の勝負は、大きい方が賭け数をもらい、同じなら分け
+                bet * (player_roll.hand.strength() - bot_roll.hand.strength()).signum()
+            };
+            (Some(player_roll), payout)
+        };
+
+        // 持っている以上のチップは払えない
+        let payout = std::cmp::max(
+            -self.progress.player_chips,
+            std::cmp::min(payout, self.progress.bot_chips),
+        );
+        self.progress.player_chips += payout;
+        self.progress.bot_chips -= payout;
+
+        return Some(ChinchiroLog {
+            bet: bet,
+            bot_roll: bot_roll,
+            player_roll: player_roll,
+            payout: payout,
+            player_chips: self.progress.player_chips,
+            bot_chips: self.progress.bot_chips,
+        });
+    }
+
+    /// どちらかのチップがなくなったら終わり
+    fn state(&self) -> DiceGameState {
+        if self.progress.player_chips <= 0 {
+            return DiceGameState::Finished {
+                winner: self.progress.bot.clone(),
+            };
+        } else if self.progress.bot_chips <= 0 {
+            return DiceGameState::Finished {
+                winner: self.progress.player.clone(),
+            };
+        } else {
+            return DiceGameState::YetPlaying;
+        }
+    }
+
+    fn serialize(&self) -> ChinchiroProgress {
+        return self.progress.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::dice::ScriptedDice;
+    use super::*;
+
+    #[test]
+    fn hand_tests() {
+        assert_eq!(Hand::from_dice([1, 1, 1]), Hand::Pinzoro);
+        assert_eq!(Hand::from_dice([3, 3, 3]), Hand::Zorome(3));
+        assert_eq!(Hand::from_dice([6, 4, 5]), Hand::Shigoro);
+        assert_eq!(Hand::from_dice([2, 3, 1]), Hand::Hifumi);
+        assert_eq!(Hand::from_dice([2, 5, 2]), Hand::Me(5));
+        assert_eq!(Hand::from_dice([1, 2, 4]), Hand::Menashi);
+    }
+
+    #[test]
+    fn chinchirorin_tests() {
+        // 親は 2 の目、子は 1 回目が目なしで、2 回目に 5 の目 => 子が賭け数をもらう
+        // 次の勝負は、親がヒフミ => 親が賭け数の 2 倍を払う
+        let dice = ScriptedDice::new(&[3, 3, 2, 1, 2, 4, 6, 5, 6, 1, 2, 3]);
+        let mut progress = ChinchiroProgress::new("alice", "hatagenpeikun", 3);
+        progress.bet = 1;
+        let mut game = Chinchirorin::start(progress, (), Box::new(dice));
+
+        let log = game.step().unwrap();
+        assert_eq!(log.bot_roll.hand, Hand::Me(2));
+        assert_eq!(log.player_roll.as_ref().unwrap().rolls.len(), 2);
+        assert_eq!(log.payout, 1);
+        assert_eq!(game.winner(), None);
+
+        // 親は残り 2 枚しかないので、2 枚だけ払って終わる
+        let log = game.step().unwrap();
+        assert!(log.player_roll.is_none());
+        assert_eq!(log.payout, 2);
+        assert_eq!(game.winner(), Some("alice".to_string()));
+        assert!(game.step().is_none());
+    }
+}
@@ -2,12 +2,34 @@
 //! 旗源平をbotで実現するモジュール
 //!
 
+use super::chinchirorin::*;
+use super::dice::*;
+use super::dice_game::*;
 use super::game::*;
+use super::multi::*;
+use super::persona::*;
+use super::rating::*;
+use super::render::render_board_svg;
+use super::replay::*;
+use super::rule_set::*;
 use super::score_operator::map::*;
 use super::score_operator::postgre::*;
 use super::score_operator::*;
+use super::stats::*;
+use super::team::*;
+use log::warn;
+use std::collections::BTreeMap;
+
+pub use super::score_operator::HANDICAP_MAX;
 
 const HATAGENPEI_INIT_SCORE: i32 = 29; // 小旗が両替できるように10x(x>=0) + 9 本持ちで開始すること
+const HANDICAP_MIN_GAMES: i32 = 5; // 勝率からハンデを決めるのに必要な対戦数
+const AUTO_PLAY_SWING_COUNT: usize = 3; // 一気に進めたゲームの要約に載せる、大きく旗が動いた手番の数
+const CHINCHIRO_INIT_CHIPS: i32 = 20; // チンチロリンで、プレイヤーと bot が最初に持つチップ
+const TABLE_MIN_PLAYERS: usize = 3; // 2人なら 旗源平 挑戦 で遊べるので、卓は3人から
+const EXPIRY_CHECK_INTERVAL: i64 = 60 * 60; // 期限切れのゲームを探す間隔（秒）
+pub const LEADERBOARD_MIN_GAMES: i32 = 5; // 勝率の順位に入るのに必要な対戦数
+pub const LEADERBOARD_PAGE_SIZE: usize = 10; // 順位表の 1 ページに載せる人数
 
 pub fn factor_operater(data_store: &DataStore) -> Box<dyn ScoreOperator> {
     let score_operator: Box<dyn ScoreOperator> = match data_store {
@@ -23,8 +45,102 @@ pub enum DataStore {
 }
 
 pub struct HatagenpeiController {
+    /// bot の Slack のユーザー id
+    bot_id: String,
     bot_name: String,
     score_operator: Box<dyn ScoreOperator>,
+    /// 遊べるルールの一覧（key は RuleSet の id）
+    rule_sets: BTreeMap<String, RuleSet>,
+    /// 模範試合に出せる bot の一覧（key は Persona の name）
+    personas: BTreeMap<String, Persona>,
+    /// 最後にサイコロを振ってからこの秒数がたった bot とのゲームは、自動で破棄する。None なら破棄しない
+    expire_after: Option<i64>,
+    /// 最後に期限切れのゲームを探した時刻（UNIX 時間の秒）
+    last_expiry_check: Option<i64>,
+}
+
+#[derive(Debug, Fail)]
+pub enum MatchError {
+    #[fail(display = "cannot challenge yourself")]
+    SelfChallenge,
+    #[fail(display = "cannot challenge bot")]
+    BotChallenge,
+    #[fail(display = "{} is already in a match", _0)]
+    AlreadyInMatch(String),
+    #[fail(display = "{} has already been challenged", _0)]
+    AlreadyChallenged(String),
+    #[fail(display = "rule set {} is not found", _0)]
+    RuleSetNotFound(String),
+    #[fail(display = "challenge to {} is not found", _0)]
+    ChallengeNotFound(String),
+    #[fail(display = "{} is not in a match", _0)]
+    MatchNotFound(String),
+    #[fail(display = "it is {}'s turn", _0)]
+    NotYourTurn(String),
+}
+
+#[derive(Debug, Fail)]
+pub enum TableError {
+    #[fail(display = "table is not found")]
+    TableNotFound,
+    #[fail(display = "table has already started")]
+    AlreadyStarted,
+    #[fail(display = "table has not started yet")]
+    NotStarted,
+    #[fail(display = "{} has already joined the table", _0)]
+    AlreadyJoined(String),
+    #[fail(display = "{} has not joined the table", _0)]
+    NotJoined(String),
+    #[fail(display = "at least {} players are required", _0)]
+    NotEnoughPlayers(usize),
+    #[fail(display = "{} cannot be a target", _0)]
+    InvalidTarget(String),
+    #[fail(display = "rule set {} is not found", _0)]
+    RuleSetNotFound(String),
+    #[fail(display = "it is {}'s turn", _0)]
+    NotYourTurn(String),
+}
+
+#[derive(Debug, Fail)]
+pub enum TeamError {
+    #[fail(display = "team match is not found")]
+    TeamMatchNotFound,
+    #[fail(display = "team match has already started")]
+    AlreadyStarted,
+    #[fail(display = "team match has not started yet")]
+    NotStarted,
+    #[fail(display = "{} has already joined the team match", _0)]
+    AlreadyJoined(String),
+    #[fail(display = "{} has not joined the team match", _0)]
+    NotJoined(String),
+    #[fail(display = "{} has no members", _0)]
+    EmptySide(String),
+    #[fail(display = "rule set {} is not found", _0)]
+    RuleSetNotFound(String),
+    #[fail(display = "it is {}'s turn", _0)]
+    NotYourTurn(String),
+}
+
+#[derive(Debug, Fail)]
+pub enum ExhibitionError {
+    #[fail(display = "persona {} is not found", _0)]
+    PersonaNotFound(String),
+    #[fail(display = "{} cannot play against itself", _0)]
+    SamePersona(String),
+    #[fail(display = "rule set {} is not found", _0)]
+    RuleSetNotFound(String),
+}
+
+#[derive(Debug, Fail)]
+pub enum ChinchiroError {
+    #[fail(display = "bet must be between 1 and {}", _0)]
+    InvalidBet(i32),
+}
+
+#[derive(Debug, Fail)]
+pub enum HandicapError {
+    #[fail(display = "handicap must be between 0 and {}", _0)]
+    OutOfRange(i32),
 }
 
 pub struct StepResult {
@@ -34,145 +150,2696 @@ pub struct StepResult {
     pub is_over: bool,
     /// この step 呼び出しで、ゲームが開始したかどうか
     pub is_start: bool,
+    /// step 後の盤面の SVG。盤面を描画できないゲームでは None
+    pub board: Option<String>,
+}
+
+/// 順位表の並べ方
+#[derive(Debug, Clone, PartialEq)]
+pub enum LeaderboardOrder {
+    /// 勝率の高い順（LEADERBOARD_MIN_GAMES 戦以上遊んだプレイヤーだけ）
+    WinRate,
+    /// 勝った数の多い順
+    Wins,
+    /// レーティングの高い順（レーティングの記録があるプレイヤーだけ）
+    Rating,
+}
+
+/// 順位表の 1 行
+#[derive(Debug, Clone)]
+pub struct LeaderboardEntry {
+    /// 順位（1 始まり）。同じ成績のプレイヤーは同じ順位になり、次の順位はその人数分飛ぶ
+    pub rank: usize,
+    /// Slack のユーザー id（bot の行では bot の id）
+    pub id: String,
+    pub name: String,
+    pub win: i32,
+    pub lose: i32,
+    /// 最新のレーティング。記録がなければ None
+    pub rating: Option<f64>,
+}
+
+/// HatagenpeiController::get_leaderboard の結果
+pub struct Leaderboard {
+    /// 指定したページに載る行（順位順）
+    pub entries: Vec<LeaderboardEntry>,
+    /// 呼び出したプレイヤーの行。指定したページに載っていない場合だけ Some になる
+    pub own: Option<LeaderboardEntry>,
+    /// 呼び出したプレイヤーが順位に入っているかどうか
+    pub is_ranked: bool,
+    /// 表示したページ（1 始まり）
+    pub page: usize,
+    /// 全体のページ数
+    pub page_count: usize,
+}
+
+/// HatagenpeiController::auto_play の結果
+pub struct AutoPlayResult {
+    /// ゲームの要約（手番数、大きく旗が動いた手番、まといを取った手番）
+    pub summary: Vec<String>,
+    /// 全手番のゲームログ
+    pub logs: Vec<String>,
+    /// この auto_play 呼び出しで、ゲームが開始したかどうか
+    pub is_start: bool,
+}
+
+/// HatagenpeiController::exhibition の結果
+pub struct ExhibitionResult {
+    /// 使ったルールの名前
+    pub rule_set_name: String,
+    /// 手番ごとのゲームログ（手番順）
+    pub turns: Vec<Vec<String>>,
+    /// 勝った bot の名前
+    pub winner: String,
 }
 
 impl HatagenpeiController {
-    pub fn new(operator: Box<dyn ScoreOperator>, bot_name: &String) -> HatagenpeiController {
+    pub fn new(
+        operator: Box<dyn ScoreOperator>,
+        bot_id: &String,
+        bot_name: &String,
+    ) -> HatagenpeiController {
+        let default_rule_set = RuleSet::default();
+        let mut rule_sets = BTreeMap::new();
+        rule_sets.insert(default_rule_set.id.clone(), default_rule_set);
         return HatagenpeiController {
+            bot_id: bot_id.clone(),
             bot_name: bot_name.clone(),
             score_operator: operator,
+            rule_sets: rule_sets,
+            personas: BTreeMap::new(),
+            expire_after: None,
+            last_expiry_check: None,
         };
     }
 
-    /// 過去の勝敗を取得
-    pub fn get_win_loses(&self) -> Vec<WinLose> {
-        let mut res = self.score_operator.get_win_loses().clone();
-        // hatagenpeikun の勝敗を追加で登録する
-        let mut hatagenpeikun_win = 0;
-        let mut hatagenpeikun_lose = 0;
-        for r in &res {
-            hatagenpeikun_lose += r.win;
-            hatagenpeikun_win += r.lose;
+    /// 表示名をキーにして記録していた頃のデータを、Slack のユーザー id をキーにして記録し直す
+    /// ids は表示名から id への対応表。何度実行してもよい
+    pub fn migrate_player_ids(&mut self, ids: &BTreeMap<String, String>) {
+        self.score_operator.migrate_player_ids(ids);
+    }
+
+    /// 最後にサイコロを振ってから expire_after 秒たった bot とのゲームを、自動で破棄するようにする
+    /// None を指定すると破棄しない
+    pub fn set_expire_after(&mut self, expire_after: Option<i64>) {
+        self.expire_after = expire_after;
+    }
+
+    /// 遊べるルールを追加する。同じ id のルールがある場合は上書きする
+    pub fn add_rule_set(&mut self, rule_set: RuleSet) {
+        self.rule_sets.insert(rule_set.id.clone(), rule_set);
+    }
+
+    /// id で指定されたルールを取得する
+    pub fn get_rule_set(&self, rule_set_id: &str) -> Option<&RuleSet> {
+        return self.rule_sets.get(rule_set_id);
+    }
+
+    /// 遊べるルールの一覧を取得する
+    pub fn get_rule_sets(&self) -> Vec<&RuleSet> {
+        return self.rule_sets.values().collect();
+    }
+
+    /// 模範試合に出せる bot を追加する。同じ名前の bot がいる場合は上書きする
+    pub fn add_persona(&mut self, persona: Persona) {
+        self.personas.insert(persona.name.clone(), persona);
+    }
+
+    /// 模範試合に出せる bot の一覧を取得する
+    pub fn get_personas(&self) -> Vec<&Persona> {
+        return self.personas.values().collect();
+    }
+
+    /// name1 と name2 の bot 同士で、模範試合を決着がつくまで行う
+    /// rule_set_id が指定されない場合は、先手の name1 のルールを使う
+    /// 結果は人間の勝敗とは別に、模範試合の勝敗として記録する
+    pub fn exhibition(
+        &mut self,
+        name1: &str,
+        name2: &str,
+        rule_set_id: Option<&str>,
+    ) -> Result<ExhibitionResult, ExhibitionError> {
+        if name1 == name2 {
+            return Err(ExhibitionError::SamePersona(name1.to_string()));
         }
-        res.push(WinLose::new(
-            hatagenpeikun_win,
-            hatagenpeikun_lose,
-            &self.bot_name,
-        ));
-        return res;
+        let persona1 = self
+            .personas
+            .get(name1)
+            .cloned()
+            .ok_or(ExhibitionError::PersonaNotFound(name1.to_string()))?;
+        let persona2 = self
+            .personas
+            .get(name2)
+            .cloned()
+            .ok_or(ExhibitionError::PersonaNotFound(name2.to_string()))?;
+        let rule_set_id = rule_set_id.unwrap_or(&persona1.rule_set_id);
+        let rule_set = self
+            .rule_sets
+            .get(rule_set_id)
+            .cloned()
+            .ok_or(ExhibitionError::RuleSetNotFound(rule_set_id.to_string()))?;
+
+        let rule_set_name = rule_set.name.clone();
+        let mut game = Hatagenpei::new(
+            new_player("", &persona1.name, persona1.handicap),
+            new_player("", &persona2.name, persona2.handicap),
+            PlayerTurn::Player1,
+            Box::new(EntropyDice::new()),
+            rule_set,
+        );
+
+        let mut turns = vec![];
+        let winner = loop {
+            // unwrap できない場合、予期しない状態になっている可能性があるので panic する
+            let game_log = game.next().unwrap();
+            turns.push(format_game_log(&game_log));
+            match game_log.game_state {
+                GameState::YetPlaying => {}
+                GameState::Player1Win => break persona1.name.clone(),
+                GameState::Player2Win => break persona2.name.clone(),
+            }
+        };
+
+        self.score_operator
+            .update_exhibition_winloses(&persona1.name, winner == persona1.name);
+        self.score_operator
+            .update_exhibition_winloses(&persona2.name, winner == persona2.name);
+
+        return Ok(ExhibitionResult {
+            rule_set_name: rule_set_name,
+            turns: turns,
+            winner: winner,
+        });
     }
 
-    /// 2step旗源平の実行を行う（player -> bot）
-    pub fn step(&mut self, player_name: &str) -> StepResult {
-        let seed = rand::random::<u64>();
-        let mut is_start = false;
-        // 現在の状態でゲームを行う
-        let progress = match self.score_operator.get_progress(player_name) {
-            Some(progress) => progress,
+    /// 過去の模範試合の、bot ごとの勝敗を勝ち数の多い順に取得する
+    pub fn get_exhibition_win_loses(&self) -> Vec<WinLose> {
+        let mut win_loses = self.score_operator.get_exhibition_win_loses();
+        win_loses.sort_by(|a, b| b.win.cmp(&a.win));
+        return win_loses;
+    }
+
+    /// id で指定されたルールを取得する。見つからない場合（ルールファイルを外して再起動した場合など）は標準ルールを返す
+    fn rule_set_or_default(&self, rule_set_id: &str) -> RuleSet {
+        return match self.rule_sets.get(rule_set_id) {
+            Some(rule_set) => rule_set.clone(),
             None => {
-                // 初期 progress を作成
-                let progress = Progress::new(
-                    &Player::new(
-                        player_name.to_string(),
-                        Score {
-                            score: HATAGENPEI_INIT_SCORE,
-                            matoi: true,
-                        },
-                        Score {
-                            score: 0,
-                            matoi: false,
-                        },
-                    ),
-                    &Player::new(
-                        self.bot_name.clone(),
-                        Score {
-                            score: HATAGENPEI_INIT_SCORE,
-                            matoi: true,
-                        },
-                        Score {
-                            score: 0,
-                            matoi: false,
-                        },
-                    ),
+                warn!(
+                    "rule set {} is not found, so use default rule set",
+                    rule_set_id
                 );
-                is_start = true;
-                // 登録
-                self.score_operator.insert_progress(&progress);
-                progress
+                RuleSet::default()
             }
         };
+    }
 
-        let mut game = Hatagenpei::new(progress.user, progress.bot, PlayerTurn::Player1, seed);
-        // game.next() の戻り値から、ゲームログ文字列を構築する
-        let mut logstr = vec![];
-        let mut is_over = false;
+    /// bot とのゲーム progress で使うルール
+    /// 始めたときのルールが保存されていなければ（ルールを保存する前に始まったゲーム）、id から探す
+    fn progress_rule_set(&self, progress: &Progress) -> RuleSet {
+        return match &progress.rule_set {
+            Some(rule_set) => rule_set.clone(),
+            None => self.rule_set_or_default(&progress.rule_set_id),
+        };
+    }
 
-        // (i == 0) => user play, (i == 1) => bot play
-        for i in 0..2 {
-            // unwrap できない場合、予期しない状態になっている可能性があるので panic する
-            let game_log = game.next().unwrap();
+    /// challenger から challenged への対戦の挑戦を登録する
+    pub fn challenge(
+        &mut self,
+        challenger_id: &str,
+        challenger_name: &str,
+        challenged_id: &str,
+        challenged_name: &str,
+        rule_set_id: Option<&str>,
+    ) -> Result<(), MatchError> {
+        if challenger_id == challenged_id {
+            return Err(MatchError::SelfChallenge);
+        }
+        if challenged_id == self.bot_id {
+            return Err(MatchError::BotChallenge);
+        }
+        for (id, name) in [
+            (challenger_id, challenger_name),
+            (challenged_id, challenged_name),
+        ]
+        .iter()
+        {
+            if self.score_operator.get_match_progress(id).is_some() {
+                return Err(MatchError::AlreadyInMatch(name.to_string()));
+            }
+        }
+        if self.score_operator.get_challenge(challenged_id).is_some() {
+            return Err(MatchError::AlreadyChallenged(challenged_name.to_string()));
+        }
+        let rule_set_id = rule_set_id.unwrap_or(DEFAULT_RULE_SET_ID);
+        if !self.rule_sets.contains_key(rule_set_id) {
+            return Err(MatchError::RuleSetNotFound(rule_set_id.to_string()));
+        }
 
-            let turn_player_name = match game_log.player_turn {
-                PlayerTurn::Player1 => game_log.player1.name.clone(),
-                PlayerTurn::Player2 => game_log.player2.name.clone(),
-            };
+        self.score_operator.insert_challenge(&Challenge {
+            challenger: challenger_name.to_string(),
+            challenged: challenged_name.to_string(),
+            challenger_id: challenger_id.to_string(),
+            challenged_id: challenged_id.to_string(),
+            rule_set_id: rule_set_id.to_string(),
+        });
+        return Ok(());
+    }
+
+    /// challenged への挑戦を受け、対戦を開始する
+    pub fn accept_challenge(
+        &mut self,
+        challenged_id: &str,
+        challenged_name: &str,
+    ) -> Result<MatchProgress, MatchError> {
+        let challenge = self
+            .score_operator
+            .get_challenge(challenged_id)
+            .ok_or_else(|| MatchError::ChallengeNotFound(challenged_name.to_string()))?;
+        // 挑戦してから受けるまでの間に、別の対戦を始めている場合がある
+        for (id, name) in [
+            (&challenge.challenger_id, &challenge.challenger),
+            (&challenge.challenged_id, &challenge.challenged),
+        ]
+        .iter()
+        {
+            if self.score_operator.get_match_progress(id).is_some() {
+                return Err(MatchError::AlreadyInMatch(name.to_string()));
+            }
+        }
+
+        // 弱い方だけがハンデを持つように、2人のハンデの差をつける
+        let challenger_handicap = self.get_handicap(&challenge.challenger_id);
+        let challenged_handicap = self.get_handicap(&challenge.challenged_id);
+        let base = std::cmp::min(challenger_handicap, challenged_handicap);
+
+        let match_progress = MatchProgress {
+            player1: new_player(
+                &challenge.challenger_id,
+                &challenge.challenger,
+                challenger_handicap - base,
+            ),
+            // 挑戦を受けた側は、挑戦されたときから名前を変えているかもしれない
+            player2: new_player(challenged_id, challenged_name, challenged_handicap - base),
+            turn: PlayerTurn::Player1,
+            rule_set_id: challenge.rule_set_id.clone(),
+            logs: vec![],
+            seeds: vec![],
+        };
+        self.score_operator.delete_challenge(challenged_id);
+        self.score_operator.insert_match_progress(&match_progress);
+        return Ok(match_progress);
+    }
+
+    /// challenged への挑戦を断る
+    pub fn decline_challenge(
+        &mut self,
+        challenged_id: &str,
+        challenged_name: &str,
+    ) -> Result<Challenge, MatchError> {
+        let challenge = self
+            .score_operator
+            .get_challenge(challenged_id)
+            .ok_or_else(|| MatchError::ChallengeNotFound(challenged_name.to_string()))?;
+        self.score_operator.delete_challenge(challenged_id);
+        return Ok(challenge);
+    }
+
+    /// player_id のハンデ（開始時に追加で持つ中旗の本数）を取得する
+    /// 管理者が設定していればその値、そうでなければ勝率から決める
+    pub fn get_handicap(&mut self, player_id: &str) -> i32 {
+        let win_lose = match self.score_operator.get_win_lose(player_id) {
+            Some(win_lose) => win_lose,
+            None => return 0,
+        };
+        if let Some(handicap) = win_lose.handicap {
+            return handicap;
+        }
+
+        let games = win_lose.win + win_lose.lose;
+        if games < HANDICAP_MIN_GAMES {
+            return 0;
+        }
+        let win_rate = win_lose.win as f64 / games as f64;
+        return if win_rate < 0.3 {
+            2
+        } else if win_rate < 0.4 {
+            1
+        } else {
+            0
+        };
+    }
+
+    /// player_id のハンデを設定する。None を指定すると、勝率から決めるように戻す
+    /// ハンデは 0 から HANDICAP_MAX までの中旗の本数で指定する
+    pub fn set_handicap(
+        &mut self,
+        player_id: &str,
+        player_name: &str,
+        handicap: Option<i32>,
+    ) -> Result<(), HandicapError> {
+        if !is_valid_handicap(handicap) {
+            return Err(HandicapError::OutOfRange(HANDICAP_MAX));
+        }
+        self.score_operator
+            .set_handicap(player_id, player_name, handicap);
+        return Ok(());
+    }
+
+    /// player_id が人間同士の対戦に参加しているか
+    pub fn has_match(&mut self, player_id: &str) -> bool {
+        return self.score_operator.get_match_progress(player_id).is_some();
+    }
+
+    /// 人間同士の対戦で、player_id の手番を1回進める
+    pub fn match_step(
+        &mut self,
+        player_id: &str,
+        player_name: &str,
+    ) -> Result<StepResult, MatchError> {
+        let mut match_progress = self
+            .score_operator
+            .get_match_progress(player_id)
+            .ok_or_else(|| MatchError::MatchNotFound(player_name.to_string()))?;
+
+        let (turn_player, next_turn) = match match_progress.turn {
+            PlayerTurn::Player1 => (&match_progress.player1, PlayerTurn::Player2),
+            PlayerTurn::Player2 => (&match_progress.player2, PlayerTurn::Player1),
+        };
+        if turn_player.id != player_id {
+            return Err(MatchError::NotYourTurn(turn_player.name.clone()));
+        }
 
-            logstr.push(format!("# {} の番", turn_player_name).to_string());
-            logstr.push("## サイコロの結果".to_string());
+        // 出目をあとから再現できるように、手番ごとのシードを決めて記録する
+        let seed = rand::random::<u64>();
+        match_progress.seeds.push(seed);
+        let mut game = Hatagenpei::new(
+            match_progress.player1.clone(),
+            match_progress.player2.clone(),
+            match_progress.turn.clone(),
+            Box::new(SeededDice::new(seed)),
+            self.rule_set_or_default(&match_progress.rule_set_id),
+        );
+        // unwrap できない場合、予期しない状態になっている可能性があるので panic する
+        let game_log = game.next().unwrap();
+        let mut logstr = format_game_log(&game_log);
+        let board = render_board_svg(&game_log);
+        match_progress.logs.push(game_log.clone());
 
-            for cmd in &game_log.commands {
-                logstr.push(format!("- {}", cmd.explain.to_string()));
+        let is_over = match game_log.game_state {
+            GameState::YetPlaying => {
+                self.score_operator.insert_match_progress(&MatchProgress {
+                    player1: game_log.player1.clone(),
+                    player2: game_log.player2.clone(),
+                    turn: next_turn,
+                    ..match_progress
+                });
+                let next_player_name = match game_log.player_turn {
+                    PlayerTurn::Player1 => &game_log.player2.name,
+                    PlayerTurn::Player2 => &game_log.player1.name,
+                };
+                logstr.push(format!("次は {} の番", next_player_name));
+                false
             }
+            win_player => {
+                let player1_win = win_player == GameState::Player1Win;
+                let win_player_name = if player1_win {
+                    &game_log.player1.name
+                } else {
+                    &game_log.player2.name
+                };
+                logstr.push(format!("{} の勝ち", win_player_name));
 
-            logstr.push("".to_string());
-            logstr.push("## 旗状況".to_string());
+                // ゲームが終わったので、進行状態を削除し、2人の勝敗と履歴を書く
+                self.finish_match(&match_progress, player1_win);
+                true
+            }
+        };
+
+        return Ok(StepResult {
+            logs: logstr,
+            is_over: is_over,
+            is_start: false,
+            board: Some(board),
+        });
+    }
+
+    /// channel の卓に player_id を参加させる。卓がなければ新しく開く
+    /// rule_set_id は新しく卓を開くときだけ使われ、None の場合は標準ルールになる
+    pub fn join_table(
+        &mut self,
+        channel: &str,
+        player_id: &str,
+        player_name: &str,
+        rule_set_id: Option<&str>,
+    ) -> Result<TableProgress, TableError> {
+        let mut table = match self.score_operator.get_table(channel) {
+            Some(table) => table,
+            None => {
+                let rule_set_id = rule_set_id.unwrap_or(DEFAULT_RULE_SET_ID);
+                if !self.rule_sets.contains_key(rule_set_id) {
+                    return Err(TableError::RuleSetNotFound(rule_set_id.to_string()));
+                }
+                TableProgress {
+                    channel: channel.to_string(),
+                    seats: vec![],
+                    turn: 0,
+                    is_started: false,
+                    rule_set_id: rule_set_id.to_string(),
+                    logs: vec![],
+                    seeds: vec![],
+                }
+            }
+        };
+        if table.is_started {
+            return Err(TableError::AlreadyStarted);
+        }
+        if table.seats.iter().any(|s| s.player.id == player_id) {
+            return Err(TableError::AlreadyJoined(player_name.to_string()));
+        }
+
+        table
+            .seats
+            .push(Seat::new(new_player(player_id, player_name, 0)));
+        self.score_operator.insert_table(&table);
+        return Ok(table);
+    }
+
+    /// channel の卓のゲームを開始する。開始できるのは卓に参加しているプレイヤーだけ
+    pub fn start_table(
+        &mut self,
+        channel: &str,
+        player_id: &str,
+        player_name: &str,
+    ) -> Result<TableProgress, TableError> {
+        let mut table = self
+            .score_operator
+            .get_table(channel)
+            .ok_or(TableError::TableNotFound)?;
+        if table.is_started {
+            return Err(TableError::AlreadyStarted);
+        }
+        if !table.seats.iter().any(|s| s.player.id == player_id) {
+            return Err(TableError::NotJoined(player_name.to_string()));
+        }
+        if table.seats.len() < TABLE_MIN_PLAYERS {
+            return Err(TableError::NotEnoughPlayers(TABLE_MIN_PLAYERS));
+        }
+
+        table.is_started = true;
+        table.turn = 0;
+        self.score_operator.insert_table(&table);
+        return Ok(table);
+    }
+
+    /// channel の卓で、player_id の手番を1回進める
+    /// target で旗をやり取りする相手を (id, 名前) で指定する。None の場合は順番に回す
+    pub fn table_step(
+        &mut self,
+        channel: &str,
+        player_id: &str,
+        player_name: &str,
+        target: Option<(&str, &str)>,
+    ) -> Result<StepResult, TableError> {
+        let mut table = self
+            .score_operator
+            .get_table(channel)
+            .ok_or(TableError::TableNotFound)?;
+        if !table.is_started {
+            return Err(TableError::NotStarted);
+        }
+        let turn_player = &table.seats[table.turn].player;
+        if turn_player.id != player_id {
+            if table.seats.iter().any(|s| s.player.id == player_id) {
+                return Err(TableError::NotYourTurn(turn_player.name.clone()));
+            } else {
+                return Err(TableError::NotJoined(player_name.to_string()));
+            }
+        }
+        let target = match target {
+            None => None,
+            Some((target_id, target_name)) => {
+                let target = table.seats.iter().position(|s| {
+                    s.player.id == target_id && s.is_alive() && s.player.id != player_id
+                });
+                match target {
+                    Some(target) => Some(target),
+                    None => return Err(TableError::InvalidTarget(target_name.to_string())),
+                }
+            }
+        };
 
-            for player in [&(game_log.player1), &(game_log.player2)].iter() {
-                logstr.push(format!("- {}", player.name));
-                logstr.push(format!("   - 自分の旗 【{}】", player.my_score.to_string()));
+        // 出目をあとから再現できるように、手番ごとのシードを決めて記録する
+        let seed = rand::random::<u64>();
+        table.seeds.push(seed);
+        let mut game = MultiHatagenpei::new(
+            table.seats.clone(),
+            table.turn,
+            Box::new(SeededDice::new(seed)),
+            self.rule_set_or_default(&table.rule_set_id),
+        );
+        // unwrap できない場合、予期しない状態になっている可能性があるので panic する
+        let game_log = game.next(target).unwrap();
+        let mut logstr = format_multi_game_log(&game_log);
+        table.logs.push(game_log.clone());
+
+        let is_over = match game_log.winner {
+            None => {
+                self.score_operator.insert_table(&TableProgress {
+                    seats: game_log.seats.clone(),
+                    turn: game.turn(),
+                    ..table
+                });
                 logstr.push(format!(
-                    "   - 取った旗 【{}】",
-                    player.got_score.to_string()
+                    "次は {} の番",
+                    game_log.seats[game.turn()].player.name
                 ));
+                false
             }
+            Some(winner) => {
+                logstr.push(format!("{} の勝ち", game_log.seats[winner].player.name));
 
-            logstr.push("".to_string());
+                // ゲームが終わったので、卓を片付け、参加者全員の勝敗と履歴を書く
+                self.score_operator.delete_table(channel);
+                for (i, seat) in game_log.seats.iter().enumerate() {
+                    self.score_operator.update_winloses(
+                        &seat.player.id,
+                        &seat.player.name,
+                        i == winner,
+                    );
+                }
+                // 勝った1人が、ほかの参加者全員に勝ったとしてレーティングを更新する
+                let players: Vec<(&str, &str)> = game_log
+                    .seats
+                    .iter()
+                    .map(|seat| (seat.player.id.as_str(), seat.player.name.as_str()))
+                    .collect();
+                let losers: Vec<(&str, &str)> = players
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| *i != winner)
+                    .map(|(_, player)| *player)
+                    .collect();
+                self.update_group_ratings(&[players[winner]], &losers);
+                self.score_operator.insert_game_history(
+                    &GameHistory {
+                        game_id: Hatagenpei::GAME_ID.to_string(),
+                        players: table.seats.iter().map(|s| s.player.name.clone()).collect(),
+                        player_ids: table.seats.iter().map(|s| s.player.id.clone()).collect(),
+                        winner: game_log.seats[winner].player.name.clone(),
+                        winner_id: game_log.seats[winner].player.id.clone(),
+                        rule_set_id: table.rule_set_id.clone(),
+                        logs: vec![],
+                        table_logs: table.logs.clone(),
+                        seeds: table.seeds.clone(),
+                    },
+                    &self.bot_id,
+                );
+                true
+            }
+        };
 
-            match game_log.game_state {
-                GameState::YetPlaying => {
-                    // ループ終了時
-                    if i == 1 {
-                        // スコアの再登録
-                        self.score_operator
-                            .insert_progress(&Progress::new(&game_log.player1, &game_log.player2));
-                    }
+        return Ok(StepResult {
+            logs: logstr,
+            is_over: is_over,
+            is_start: false,
+            board: None,
+        });
+    }
+
+    /// channel のチーム戦の side 側に player_id を参加させる。チーム戦がなければ新しく開く
+    /// rule_set_id は新しくチーム戦を開くときだけ使われ、None の場合は標準ルールになる
+    pub fn join_team(
+        &mut self,
+        channel: &str,
+        player_id: &str,
+        player_name: &str,
+        side: Side,
+        rule_set_id: Option<&str>,
+    ) -> Result<TeamMatchProgress, TeamError> {
+        let mut team_match = match self.score_operator.get_team_match(channel) {
+            Some(team_match) => team_match,
+            None => {
+                let rule_set_id = rule_set_id.unwrap_or(DEFAULT_RULE_SET_ID);
+                if !self.rule_sets.contains_key(rule_set_id) {
+                    return Err(TeamError::RuleSetNotFound(rule_set_id.to_string()));
                 }
-                win_player => {
-                    let win_player_name = match win_player {
-                        GameState::Player1Win => player_name.to_string(),
-                        GameState::Player2Win => self.bot_name.clone(),
-                        GameState::YetPlaying => panic!("unexpected!"),
-                    };
+                TeamMatchProgress {
+                    channel: channel.to_string(),
+                    genji: Team::new(Side::Genji, new_player("", &Side::Genji.to_string(), 0)),
+                    heike: Team::new(Side::Heike, new_player("", &Side::Heike.to_string(), 0)),
+                    turn: Side::Genji,
+                    is_started: false,
+                    rule_set_id: rule_set_id.to_string(),
+                    logs: vec![],
+                    seeds: vec![],
+                }
+            }
+        };
+        if team_match.is_started {
+            return Err(TeamError::AlreadyStarted);
+        }
+        if team_match.genji.has_member(player_id) || team_match.heike.has_member(player_id) {
+            return Err(TeamError::AlreadyJoined(player_name.to_string()));
+        }
 
-                    logstr.push(format!("{} の勝ち", win_player_name));
-                    logstr.push("".to_string());
+        team_match
+            .team_mut(side)
+            .members
+            .push(Contribution::new(player_id, player_name));
+        self.score_operator.insert_team_match(&team_match);
+        return Ok(team_match);
+    }
 
-                    // ゲームが終わったので、進行状態を削除する
-                    self.score_operator.delete_progress(player_name);
+    /// channel のチーム戦を開始する。開始できるのはチーム戦に参加しているプレイヤーだけ
+    pub fn start_team_match(
+        &mut self,
+        channel: &str,
+        player_id: &str,
+        player_name: &str,
+    ) -> Result<TeamMatchProgress, TeamError> {
+        let mut team_match = self
+            .score_operator
+            .get_team_match(channel)
+            .ok_or(TeamError::TeamMatchNotFound)?;
+        if team_match.is_started {
+            return Err(TeamError::AlreadyStarted);
+        }
+        if !team_match.genji.has_member(player_id) && !team_match.heike.has_member(player_id) {
+            return Err(TeamError::NotJoined(player_name.to_string()));
+        }
+        for team in [&team_match.genji, &team_match.heike].iter() {
+            if team.members.is_empty() {
+                return Err(TeamError::EmptySide(team.side.to_string()));
+            }
+        }
 
-                    // 勝敗を書く
-                    self.score_operator
-                        .update_winloses(player_name, win_player == GameState::Player1Win);
+        team_match.is_started = true;
+        self.score_operator.insert_team_match(&team_match);
+        return Ok(team_match);
+    }
 
-                    is_over = true;
-                    break;
+    /// channel のチーム戦で、player_id がチームの代表としてサイコロを振る
+    pub fn team_step(
+        &mut self,
+        channel: &str,
+        player_id: &str,
+        player_name: &str,
+    ) -> Result<StepResult, TeamError> {
+        let mut team_match = self
+            .score_operator
+            .get_team_match(channel)
+            .ok_or(TeamError::TeamMatchNotFound)?;
+        if !team_match.is_started {
+            return Err(TeamError::NotStarted);
+        }
+        let side = team_match.turn;
+        let roller = team_match.team_mut(side).roller().clone();
+        let roller_name = roller.name.clone();
+        if roller.id != player_id {
+            if team_match.genji.has_member(player_id) || team_match.heike.has_member(player_id) {
+                return Err(TeamError::NotYourTurn(roller_name));
+            } else {
+                return Err(TeamError::NotJoined(player_name.to_string()));
+            }
+        }
+
+        let before = team_match.team(side).value();
+        // 出目をあとから再現できるように、手番ごとのシードを決めて記録する
+        let seed = rand::random::<u64>();
+        team_match.seeds.push(seed);
+        let mut game = Hatagenpei::new(
+            team_match.genji.player.clone(),
+            team_match.heike.player.clone(),
+            side.to_player_turn(),
+            Box::new(SeededDice::new(seed)),
+            self.rule_set_or_default(&team_match.rule_set_id),
+        );
+        // unwrap できない場合、予期しない状態になっている可能性があるので panic する
+        let game_log = game.next().unwrap();
+        team_match.genji.player = game_log.player1.clone();
+        team_match.heike.player = game_log.player2.clone();
+        team_match.logs.push(game_log.clone());
+
+        // 自分の手番で相手のまといを取れるのは、サイコロを振った側だけ
+        let is_over = game_log.game_state != GameState::YetPlaying;
+        let gained = team_match.team(side).value() - before;
+        {
+            let team = team_match.team_mut(side);
+            let roller = team.roller();
+            roller.rolls += 1;
+            roller.flags += gained;
+            if is_over {
+                roller.matoi_captures += 1;
+            }
+            team.rotate();
+        }
+
+        let mut logstr = format_game_log(&game_log);
+        logstr[0] = format!("# {} の {} の番", side.to_string(), roller_name);
+
+        if is_over {
+            logstr.push(format!("{} の勝ち", side.to_string()));
+            logstr.push("".to_string());
+            logstr.push("## 貢献度".to_string());
+
+            // ゲームが終わったので、チーム戦を片付け、陣営の勝敗とメンバーの貢献度、履歴を書く
+            self.score_operator.delete_team_match(channel);
+            self.score_operator.insert_game_history(
+                &GameHistory {
+                    game_id: Hatagenpei::GAME_ID.to_string(),
+                    players: team_match
+                        .genji
+                        .members
+                        .iter()
+                        .chain(team_match.heike.members.iter())
+                        .map(|m| m.name.clone())
+                        .collect(),
+                    player_ids: team_match
+                        .genji
+                        .members
+                        .iter()
+                        .chain(team_match.heike.members.iter())
+                        .map(|m| m.id.clone())
+                        .collect(),
+                    winner: side.to_string(),
+                    winner_id: String::new(),
+                    rule_set_id: team_match.rule_set_id.clone(),
+                    logs: team_match.logs.clone(),
+                    table_logs: vec![],
+                    seeds: team_match.seeds.clone(),
+                },
+                &self.bot_id,
+            );
+            // 勝った陣営の各メンバーが、負けた陣営の全員に勝ったとしてレーティングを更新する
+            let (winners, losers): (Vec<_>, Vec<_>) = [&team_match.genji, &team_match.heike]
+                .iter()
+                .flat_map(|team| team.members.iter().map(move |m| (team.side, m)))
+                .partition(|(member_side, _)| *member_side == side);
+            self.update_group_ratings(
+                &winners
+                    .iter()
+                    .map(|(_, m)| (m.id.as_str(), m.name.as_str()))
+                    .collect::<Vec<_>>(),
+                &losers
+                    .iter()
+                    .map(|(_, m)| (m.id.as_str(), m.name.as_str()))
+                    .collect::<Vec<_>>(),
+            );
+            for team in [&team_match.genji, &team_match.heike].iter() {
+                let is_win = team.side == side;
+                self.score_operator.update_team_winloses(team.side, is_win);
+                for member in &team.members {
+                    logstr.push(format!(
+                        "- {} ({}) : 旗 {:+} 本",
+                        member.name,
+                        team.side.to_string(),
+                        member.flags
+                    ));
+                    self.score_operator.update_contribution(&Contribution {
+                        games: 1,
+                        wins: is_win as i32,
+                        ..member.clone()
+                    });
                 }
             }
+        } else {
+            team_match.turn = side.opposite();
+            let next_roller_name = team_match.team_mut(side.opposite()).roller().name.clone();
+            logstr.push(format!(
+                "次は {} の {} の番",
+                side.opposite().to_string(),
+                next_roller_name
+            ));
+            self.score_operator.insert_team_match(&team_match);
         }
-        return StepResult {
+
+        return Ok(StepResult {
             logs: logstr,
             is_over: is_over,
-            is_start: is_start,
+            is_start: false,
+            board: Some(render_board_svg(&game_log)),
+        });
+    }
+
+    /// 過去のチーム戦の、陣営ごとの勝敗を取得
+    pub fn get_team_win_loses(&self) -> Vec<WinLose> {
+        return self.score_operator.get_team_win_loses();
+    }
+
+    /// 過去のチーム戦の、メンバーごとの貢献度を取得（増やした旗の本数が多い順）
+    pub fn get_contributions(&self) -> Vec<Contribution> {
+        let mut contributions = self.score_operator.get_contributions();
+        contributions.sort_by(|a, b| b.flags.cmp(&a.flags));
+        return contributions;
+    }
+
+    /// player_id が参加した、終了したゲームの記録を新しい順に count 件まで取得する
+    pub fn get_game_histories(&mut self, player_id: &str, count: usize) -> Vec<GameHistory> {
+        return self
+            .score_operator
+            .get_recent_game_histories(player_id, count);
+    }
+
+    /// player_id の終了したゲームの履歴から、統計を計算する
+    pub fn get_player_stats(&mut self, player_id: &str) -> PlayerStats {
+        let histories = self.score_operator.get_game_histories(player_id);
+        return PlayerStats::from_histories(player_id, &histories);
+    }
+
+    /// player_id のレーティングの履歴を新しい順に count 件まで取得する
+    pub fn get_ratings(&mut self, player_id: &str, count: usize) -> Vec<RatingRecord> {
+        let mut ratings = self.score_operator.get_ratings(player_id);
+        ratings.reverse();
+        ratings.truncate(count);
+        return ratings;
+    }
+
+    /// 過去の勝敗を取得
+    pub fn get_win_loses(&self) -> Vec<WinLose> {
+        let mut res = self.score_operator.get_win_loses().clone();
+        // hatagenpeikun の勝敗を、相手ごとの勝敗から集計して追加する
+        let mut hatagenpeikun_win = 0;
+        let mut hatagenpeikun_lose = 0;
+        for head_to_head in self.score_operator.get_head_to_heads(&self.bot_id) {
+            hatagenpeikun_win += head_to_head.win;
+            hatagenpeikun_lose += head_to_head.lose;
+        }
+        res.push(WinLose::new(
+            hatagenpeikun_win,
+            hatagenpeikun_lose,
+            &self.bot_name,
+        ));
+        return res;
+    }
+
+    /// 相手ごとの勝敗を記録する前の bot とのゲームの勝敗を、相手ごとの勝敗として書く
+    /// 各プレイヤーの勝敗から、履歴に残っている人間同士の対戦と卓の結果を引いたものを bot とのゲームの勝敗とみなす
+    /// bot の相手ごとの勝敗がまだ 1 件もない場合だけ書く。bot とのゲームが終わるたびに bot の相手ごとの勝敗も書くので、
+    /// 1 度書いた後や、相手ごとの勝敗を記録し始めた後に呼んでも何もしない。戻り値は書いたプレイヤーの数
+    pub fn backfill_bot_head_to_heads(&mut self) -> usize {
+        if !self
+            .score_operator
+            .get_head_to_heads(&self.bot_id)
+            .is_empty()
+        {
+            return 0;
+        }
+
+        let mut count = 0;
+        for win_lose in self.score_operator.get_win_loses() {
+            // id のない勝敗（表示名をキーにして記録していた頃の勝敗）は、相手ごとの勝敗のキーにできない
+            if win_lose.id.is_empty() || win_lose.id == self.bot_id {
+                continue;
+            }
+            let (mut win, mut lose) = (win_lose.win, win_lose.lose);
+            for history in self.score_operator.get_game_histories(&win_lose.id) {
+                // bot とのゲームは player_ids に bot の id が入る
+                // チーム戦は陣営の勝敗だけを書き、プレイヤーの勝敗は書かない（勝者の id も空になる）
+                if history.player_ids.contains(&self.bot_id) || history.winner_id.is_empty() {
+                    continue;
+                }
+                let index = match history.player_ids.iter().position(|id| *id == win_lose.id) {
+                    Some(index) => index,
+                    None => continue,
+                };
+                if history.is_winner(index) {
+                    win -= 1;
+                } else {
+                    lose -= 1;
+                }
+            }
+            let (win, lose) = (std::cmp::max(win, 0), std::cmp::max(lose, 0));
+            if win == 0 && lose == 0 {
+                continue;
+            }
+
+            self.score_operator.insert_head_to_head(&HeadToHead {
+                win: win,
+                lose: lose,
+                ..HeadToHead::new(&win_lose.id, &win_lose.name, &self.bot_id, &self.bot_name)
+            });
+            self.score_operator.insert_head_to_head(&HeadToHead {
+                win: lose,
+                lose: win,
+                ..HeadToHead::new(&self.bot_id, &self.bot_name, &win_lose.id, &win_lose.name)
+            });
+            count += 1;
+        }
+        return count;
+    }
+
+    /// player_id の、相手ごとの勝敗を対戦数の多い順に取得する
+    pub fn get_head_to_heads(&self, player_id: &str) -> Vec<HeadToHead> {
+        let mut head_to_heads = self.score_operator.get_head_to_heads(player_id);
+        head_to_heads.sort_by(|a, b| {
+            return (b.win + b.lose)
+                .cmp(&(a.win + a.lose))
+                .then(a.opponent_name.cmp(&b.opponent_name));
+        });
+        return head_to_heads;
+    }
+
+    /// player_id の、opponent_id との勝敗を取得する。まだ対戦していなければ None を返す
+    pub fn get_head_to_head(&self, player_id: &str, opponent_id: &str) -> Option<HeadToHead> {
+        return self
+            .score_operator
+            .get_head_to_heads(player_id)
+            .into_iter()
+            .find(|head_to_head| head_to_head.opponent_id == opponent_id);
+    }
+
+    /// order の順に並べた順位表の page ページ目（1 始まり）を取得する
+    /// player_id の行がそのページに載らない場合は、別に own として返す
+    pub fn get_leaderboard(
+        &self,
+        order: &LeaderboardOrder,
+        player_id: &str,
+        page: usize,
+    ) -> Leaderboard {
+        let ratings = self
+            .score_operator
+            .get_latest_ratings()
+            .into_iter()
+            .map(|record| (record.id, record.rating.rating))
+            .collect::<BTreeMap<_, _>>();
+        // get_win_loses の最後の行は bot の勝敗なので、bot の id を入れておく
+        let mut win_loses = self.get_win_loses();
+        if let Some(bot_win_lose) = win_loses.last_mut() {
+            bot_win_lose.id = self.bot_id.clone();
+        }
+
+        // 並べるための値を計算し、順位に入らないプレイヤーを除く
+        let mut scored = win_loses
+            .into_iter()
+            .filter_map(|win_lose| {
+                let rating = ratings.get(&win_lose.id).cloned();
+                let games = win_lose.win + win_lose.lose;
+                let score = match order {
+                    LeaderboardOrder::WinRate if games >= LEADERBOARD_MIN_GAMES => {
+                        win_lose.win as f64 / games as f64
+                    }
+                    LeaderboardOrder::WinRate => return None,
+                    LeaderboardOrder::Wins => win_lose.win as f64,
+                    LeaderboardOrder::Rating => rating?,
+                };
+                let entry = LeaderboardEntry {
+                    rank: 0,
+                    id: win_lose.id,
+                    name: win_lose.name,
+                    win: win_lose.win,
+                    lose: win_lose.lose,
+                    rating: rating,
+                };
+                return Some((score, entry));
+            })
+            .collect::<Vec<_>>();
+        // 同じ成績なら、対戦数の多い順、名前順に並べる
+        scored.sort_by(|(score1, entry1), (score2, entry2)| {
+            return score2
+                .partial_cmp(score1)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then((entry2.win + entry2.lose).cmp(&(entry1.win + entry1.lose)))
+                .then(entry1.name.cmp(&entry2.name));
+        });
+
+        // 同じ成績のプレイヤーは同じ順位にする
+        let mut entries: Vec<LeaderboardEntry> = vec![];
+        for (i, (score, mut entry)) in scored.iter().cloned().enumerate() {
+            entry.rank = if i > 0 && scored[i - 1].0 == score {
+                entries[i - 1].rank
+            } else {
+                i + 1
+            };
+            entries.push(entry);
+        }
+
+        let page_count = std::cmp::max(
+            (entries.len() + LEADERBOARD_PAGE_SIZE - 1) / LEADERBOARD_PAGE_SIZE,
+            1,
+        );
+        let page = std::cmp::min(std::cmp::max(page, 1), page_count);
+        let own_index = entries.iter().position(|entry| entry.id == player_id);
+        let page_range = (page - 1) * LEADERBOARD_PAGE_SIZE
+            ..std::cmp::min(page * LEADERBOARD_PAGE_SIZE, entries.len());
+        let own = match own_index {
+            Some(i) if !page_range.contains(&i) => Some(entries[i].clone()),
+            _ => None,
         };
+
+        return Leaderboard {
+            entries: entries[page_range].to_vec(),
+            own: own,
+            is_ranked: own_index.is_some(),
+            page: page,
+            page_count: page_count,
+        };
+    }
+
+    /// player_id の進行中のゲーム（bot とのゲームか人間同士の対戦）について、player_id の勝率を計算する
+    /// 返す WinProbability の player1 は、人間同士の対戦で player_id が後手でも player_id の勝率になる
+    /// 進行中のゲームがない場合は None を返す
+    pub fn get_win_probability(&mut self, player_id: &str) -> Option<WinProbability> {
+        if let Some(match_progress) = self.score_operator.get_match_progress(player_id) {
+            let rule_set = self.rule_set_or_default(&match_progress.rule_set_id);
+            // player_id が後手の場合は、先手と後手を入れ替えて player_id から見た勝率にする
+            let (player, opponent, turn) = if match_progress.player1.id == player_id {
+                (
+                    &match_progress.player1,
+                    &match_progress.player2,
+                    match_progress.turn.clone(),
+                )
+            } else {
+                (
+                    &match_progress.player2,
+                    &match_progress.player1,
+                    match match_progress.turn {
+                        PlayerTurn::Player1 => PlayerTurn::Player2,
+                        PlayerTurn::Player2 => PlayerTurn::Player1,
+                    },
+                )
+            };
+            let progress = Progress {
+                turn: turn,
+                ..Progress::new(player, opponent, &rule_set)
+            };
+            return Some(win_probability(&progress, &rule_set));
+        }
+
+        let progress = self.load_game_progress::<Hatagenpei>(player_id)?;
+        let rule_set = self.progress_rule_set(&progress);
+        return Some(win_probability(&progress, &rule_set));
+    }
+
+    /// player_id の直近に終了したゲームを、リプレイから再実行して検証する
+    /// game_seed が指定された場合は、公開されたシードから各 step のシードを計算し直して検証する
+    /// 終了したゲームがない場合は None を返す
+    pub fn verify_latest_replay(
+        &mut self,
+        player_id: &str,
+        game_seed: Option<u64>,
+    ) -> Option<(Replay, Result<(), ReplayError>)> {
+        let replay = self.score_operator.get_latest_finished_replay(player_id)?;
+        let rule_set = replay
+            .initial
+            .rule_set
+            .as_ref()
+            .or_else(|| self.rule_sets.get(&replay.initial.rule_set_id));
+        let result = match rule_set {
+            Some(rule_set) => match game_seed {
+                Some(game_seed) => replay.verify_with_seed(rule_set, game_seed),
+                None => replay.verify(rule_set),
+            },
+            None => Err(ReplayError::RuleSetNotFound(
+                replay.initial.rule_set_id.clone(),
+            )),
+        };
+        return Some((replay, result));
+    }
+
+    /// 2step旗源平の実行を行う（player -> bot）
+    /// rule_set_id は新しくゲームを始めるときだけ使われ、None の場合は標準ルールになる
+    pub fn step(
+        &mut self,
+        player_id: &str,
+        player_name: &str,
+        rule_set_id: Option<&str>,
+    ) -> StepResult {
+        return self.run_step(player_id, player_name, rule_set_id, None);
+    }
+
+    /// player が実物のサイコロを振って出た目 (dice1, dice2) で、2step旗源平の実行を行う
+    /// 入力された目で振り直しになった場合は、bot の番にならずに戻る
+    /// rule_set_id は新しくゲームを始めるときだけ使われ、None の場合は標準ルールになる
+    /// 1 から 6 でない目が入力された場合は、ゲームを進めずにその旨をログに入れて返す
+    pub fn step_with_dice(
+        &mut self,
+        player_id: &str,
+        player_name: &str,
+        dice1: u8,
+        dice2: u8,
+        rule_set_id: Option<&str>,
+    ) -> StepResult {
+        if [dice1, dice2].iter().any(|face| *face < 1 || 6 < *face) {
+            return StepResult {
+                logs: vec![format!(
+                    "サイコロの目 {}, {} は 1 から 6 の数字ではない",
+                    dice1, dice2
+                )],
+                is_over: false,
+                is_start: false,
+                board: None,
+            };
+        }
+        return self.run_step(player_id, player_name, rule_set_id, Some((dice1, dice2)));
+    }
+
+    /// player と bot の旗源平を、決着がつくまで一気に進める
+    /// 進行中のゲームがある場合は、その続きから進める
+    pub fn auto_play(
+        &mut self,
+        player_id: &str,
+        player_name: &str,
+        rule_set_id: Option<&str>,
+    ) -> AutoPlayResult {
+        let mut logs = vec![];
+        let mut is_start = false;
+        loop {
+            let mut res = self.step(player_id, player_name, rule_set_id);
+            is_start |= res.is_start;
+            logs.append(&mut res.logs);
+            if res.is_over {
+                break;
+            }
+        }
+
+        // 終わったゲームの履歴から、途中までの手番も含めて要約を作る
+        let summary = match self.get_game_histories(player_id, 1).first() {
+            Some(history) => summarize_game_history(history),
+            None => vec![],
+        };
+
+        return AutoPlayResult {
+            summary: summary,
+            logs: logs,
+            is_start: is_start,
+        };
+    }
+
+    /// player_id が進行中の旗源平を降参し、負けとして記録する
+    /// 人間同士の対戦中なら相手の、そうでなければ bot の勝ちになる
+    /// 進行中のゲームがない場合は None を返す
+    pub fn surrender(&mut self, player_id: &str, player_name: &str) -> Option<Vec<String>> {
+        let mut logstr = vec![format!("{} が降参した", player_name)];
+
+        if let Some(match_progress) = self.score_operator.get_match_progress(player_id) {
+            let player1_win = match_progress.player2.id == player_id;
+            logstr.push(format!(
+                "{} の勝ち",
+                if player1_win {
+                    &match_progress.player1.name
+                } else {
+                    &match_progress.player2.name
+                }
+            ));
+            self.finish_match(&match_progress, player1_win);
+            return Some(logstr);
+        }
+
+        let progress = self.load_game_progress::<Hatagenpei>(player_id)?;
+        // リプレイがない場合（リプレイ導入前に始めたゲーム）は、初期状態だけのリプレイとして扱う
+        let replay = self
+            .score_operator
+            .get_replay(player_id)
+            .unwrap_or_else(|| Replay {
+                initial: progress.clone(),
+                steps: vec![],
+                game_seed: None,
+                commitment: None,
+            });
+        let bot_id = self.bot_id.clone();
+        let bot_name = self.bot_name.clone();
+        logstr.push(format!("{} の勝ち", bot_name));
+        logstr.append(&mut self.finish_bot_game(
+            player_id,
+            player_name,
+            &progress,
+            &replay,
+            (&bot_id, &bot_name),
+        ));
+        return Some(logstr);
+    }
+
+    /// player_id が進行中の旗源平（bot とのゲームと人間同士の対戦）を、勝敗を記録せずに破棄する
+    /// 破棄するゲームがなかった場合は false を返す
+    pub fn abandon(&mut self, player_id: &str) -> bool {
+        let mut is_abandoned = self.discard_bot_game(player_id);
+        if self.score_operator.get_match_progress(player_id).is_some() {
+            self.score_operator.delete_match_progress(player_id);
+            is_abandoned = true;
+        }
+        return is_abandoned;
+    }
+
+    /// 最後にサイコロを振ってから expire_after 秒以上たった bot とのゲームを、勝敗を記録せずに破棄する
+    /// 毎回すべての進行状態を読むのは重いので、EXPIRY_CHECK_INTERVAL 秒に1回だけ探す
+    /// 戻り値は破棄したゲームの進行状態
+    pub fn expire_stale_games(&mut self, now: i64) -> Vec<Progress> {
+        let expire_after = match self.expire_after {
+            Some(expire_after) => expire_after,
+            None => return vec![],
+        };
+        match self.last_expiry_check {
+            Some(last) if now - last < EXPIRY_CHECK_INTERVAL => return vec![],
+            _ => self.last_expiry_check = Some(now),
+        }
+
+        let mut expired = vec![];
+        for mut progress in self.load_game_progresses::<Hatagenpei>() {
+            match progress.updated_at {
+                Some(updated_at) if now - updated_at >= expire_after => {
+                    self.discard_bot_game(&progress.user.id);
+                    expired.push(progress);
+                }
+                Some(_) => {}
+                None => {
+                    // 時刻の記録がない古いゲームは、今から期限を数える
+                    progress.updated_at = Some(now);
+                    self.save_game_progress::<Hatagenpei>(&progress.user.id, &progress);
+                }
+            }
+        }
+        return expired;
+    }
+
+    /// player と bot のチンチロリンを 1 勝負進める
+    /// bet が指定されない場合は、前回と同じ賭け数（持ちチップが足りなければ全部）で勝負する
+    pub fn chinchiro_step(
+        &mut self,
+        player_id: &str,
+        player_name: &str,
+        bet: Option<i32>,
+    ) -> Result<StepResult, ChinchiroError> {
+        let (mut progress, is_start) = match self.load_game_progress::<Chinchirorin>(player_id) {
+            Some(progress) => (progress, false),
+            None => (
+                ChinchiroProgress::new(player_name, &self.bot_name, CHINCHIRO_INIT_CHIPS),
+                true,
+            ),
+        };
+        // 勝負ごとに結果が決まるので、途中で名前を変えていれば新しい名前で続ける
+        progress.player = player_name.to_string();
+        progress.bet = match bet {
+            Some(bet) if bet < 1 || bet > progress.player_chips => {
+                return Err(ChinchiroError::InvalidBet(progress.player_chips));
+            }
+            Some(bet) => bet,
+            None => std::cmp::min(progress.bet, progress.player_chips),
+        };
+
+        let mut game = Chinchirorin::start(progress, (), Box::new(EntropyDice::new()));
+        // unwrap できない場合、予期しない状態になっている可能性があるので panic する
+        let game_log = game.step().unwrap();
+        let mut logstr = format_chinchiro_log(&game_log, player_name, &self.bot_name);
+
+        let is_over = match game.winner() {
+            None => {
+                self.save_game_progress::<Chinchirorin>(player_id, &game.serialize());
+                false
+            }
+            Some(winner) => {
+                logstr.push(format!("{} の勝ち", winner));
+                self.score_operator
+                    .delete_game_progress(Chinchirorin::GAME_ID, player_id);
+                // 表示名は bot と同じにできるので、チップが残ったほうで勝者を決める
+                let is_win = game.serialize().player_chips > 0;
+                let (winner_id, winner_name) = if is_win {
+                    (player_id.to_string(), player_name.to_string())
+                } else {
+                    (self.bot_id.clone(), self.bot_name.clone())
+                };
+                let history = GameHistory {
+                    game_id: Chinchirorin::GAME_ID.to_string(),
+                    players: vec![player_name.to_string(), self.bot_name.clone()],
+                    player_ids: vec![player_id.to_string(), self.bot_id.clone()],
+                    winner: winner_name,
+                    winner_id: winner_id,
+                    rule_set_id: String::new(),
+                    logs: vec![],
+                    table_logs: vec![],
+                    seeds: vec![],
+                };
+                self.record_bot_game(player_id, player_name, is_win, &history);
+                true
+            }
+        };
+
+        return Ok(StepResult {
+            logs: logstr,
+            is_over: is_over,
+            is_start: is_start,
+            board: None,
+        });
+    }
+
+    /// G の進行状態を取得する
+    fn load_game_progress<G: DiceGame>(&mut self, player_id: &str) -> Option<G::Progress> {
+        let data = self
+            .score_operator
+            .get_game_progress(G::GAME_ID, player_id)?;
+        return Some(serde_json::from_str(&data).expect("failed to serde_json::from_str"));
+    }
+
+    /// 進行中の G のすべての進行状態を取得する
+    fn load_game_progresses<G: DiceGame>(&self) -> Vec<G::Progress> {
+        return self
+            .score_operator
+            .get_game_progresses(G::GAME_ID)
+            .iter()
+            .map(|data| serde_json::from_str(data).expect("failed to serde_json::from_str"))
+            .collect();
+    }
+
+    /// G の進行状態を保存する
+    fn save_game_progress<G: DiceGame>(&mut self, player_id: &str, progress: &G::Progress) {
+        let data = serde_json::to_string(progress).expect("failed to serde_json::to_string");
+        self.score_operator
+            .insert_game_progress(G::GAME_ID, player_id, &data);
+    }
+
+    fn run_step(
+        &mut self,
+        player_id: &str,
+        player_name: &str,
+        rule_set_id: Option<&str>,
+        player_dice: Option<(u8, u8)>,
+    ) -> StepResult {
+        let mut is_start = false;
+        // 現在の状態でゲームを行う
+        // 途中で名前を変えても、ゲーム中はリプレイと合うように始めたときの名前のまま進める
+        let progress = match self.load_game_progress::<Hatagenpei>(player_id) {
+            Some(progress) => progress,
+            None => {
+                // 初期 progress を作成
+                let mut progress = Progress::new(
+                    &new_player(player_id, player_name, self.get_handicap(player_id)),
+                    &new_player(&self.bot_id, &self.bot_name, 0),
+                    &self.rule_set_or_default(rule_set_id.unwrap_or(DEFAULT_RULE_SET_ID)),
+                );
+                progress.started_at = Some(now());
+                progress.updated_at = progress.started_at;
+                is_start = true;
+                // 登録
+                self.save_game_progress::<Hatagenpei>(player_id, &progress);
+                progress
+            }
+        };
+
+        // 途中でルールのファイルが変わっても、始めたときのルールで続ける
+        let rule_set = self.progress_rule_set(&progress);
+
+        // game.next() の戻り値から、ゲームログ文字列を構築する
+        let mut logstr = vec![];
+        let mut is_over = false;
+        let mut winner = None;
+        let mut board = None;
+
+        if is_start {
+            logstr.push(format!("ルール : {}", rule_set.name));
+            let handicap =
+                (progress.user.my_score.value() - HATAGENPEI_INIT_SCORE) / Flag::Chubata.worth();
+            if handicap > 0 {
+                logstr.push(format!("ハンデ : {} に中旗 {} 本", player_name, handicap));
+            }
+            logstr.push("".to_string());
+        }
+
+        // リプレイが見つからない場合（リプレイ導入前に始まったゲームなど）は、現在の状態から記録する
+        // 新しくリプレイを作るときにゲームのシードを決め、不正をしていないことを示すためにコミットメントだけを公開する
+        let mut replay = match self.score_operator.get_replay(player_id) {
+            Some(replay) => replay,
+            None => {
+                let replay = Replay::new(&progress, rand::random::<u64>());
+                logstr.push(format!(
+                    "シードのコミットメント : {}",
+                    replay.commitment.clone().unwrap_or_default()
+                ));
+                logstr.push("".to_string());
+                replay
+            }
+        };
+        let mut replay_step = ReplayStep {
+            seed: replay.next_step_seed(),
+            dice: match player_dice {
+                Some((dice1, dice2)) => vec![dice1, dice2],
+                None => vec![],
+            },
+            logs: vec![],
+        };
+
+        let mut game = Hatagenpei::start(progress.clone(), rule_set, replay_step.dice_source());
+
+        // (i == 0) => user play, (i == 1) => bot play
+        for i in 0..2 {
+            // 実物のサイコロの目が入力された場合、user はその目で1回だけ振る
+            let game_log = if i == 0 && player_dice.is_some() {
+                game.next_roll()
+            } else {
+                game.step()
+            };
+            // unwrap できない場合、予期しない状態になっている可能性があるので panic する
+            let game_log = game_log.unwrap();
+            replay_step.logs.push(game_log.clone());
+            board = Some(render_board_svg(&game_log));
+
+            logstr.append(&mut format_game_log(&game_log));
+
+            match game.winner() {
+                None => {
+                    // 入力された目で振り直しになった場合は、続きの目を入力してもらう
+                    let is_reroll = i == 0 && player_dice.is_some() && game_log.commands[0].again;
+                    // ループ終了時
+                    if i == 1 || is_reroll {
+                        // スコアの再登録
+                        self.save_game_progress::<Hatagenpei>(
+                            player_id,
+                            &Progress {
+                                step: progress.step + 1,
+                                started_at: progress.started_at,
+                                updated_at: Some(now()),
+                                ..game.serialize()
+                            },
+                        );
+                    }
+                    if is_reroll {
+                        logstr.push(
+                            "もう一度サイコロを振って `旗源平 出目 <目> <目>` で入力するげん"
+                                .to_string(),
+                        );
+                        break;
+                    }
+                }
+                Some(win_player_name) => {
+                    logstr.push(format!("{} の勝ち", win_player_name));
+                    logstr.push("".to_string());
+                    is_over = true;
+                    // 表示名は bot と同じにできるので、まといを取ったほうの id で勝者を決める
+                    let winner_id = if game.serialize().user.got_score.matoi {
+                        player_id.to_string()
+                    } else {
+                        self.bot_id.clone()
+                    };
+                    winner = Some((winner_id, win_player_name));
+                    break;
+                }
+            }
+        }
+
+        // リプレイを記録し、ゲームが終わっていれば終了したゲームとして保存する
+        replay.steps.push(replay_step);
+        self.score_operator.insert_replay(&replay);
+        if let Some((winner_id, winner_name)) = winner {
+            logstr.append(&mut self.finish_bot_game(
+                player_id,
+                player_name,
+                &progress,
+                &replay,
+                (&winner_id, &winner_name),
+            ));
+        }
+
+        return StepResult {
+            logs: logstr,
+            is_over: is_over,
+            is_start: is_start,
+            board: board,
+        };
+    }
+
+    /// player_id と bot のゲームを winner（(id, 名前) の組）の勝ちで終わらせ、勝敗と履歴を書く
+    /// 戻り値は、終了時に表示するログ（シードの公開）
+    fn finish_bot_game(
+        &mut self,
+        player_id: &str,
+        player_name: &str,
+        progress: &Progress,
+        replay: &Replay,
+        winner: (&str, &str),
+    ) -> Vec<String> {
+        let mut logstr = vec![];
+        let is_win = winner.0 == player_id;
+
+        // ゲームが終わったので、進行状態を削除する
+        self.score_operator
+            .delete_game_progress(Hatagenpei::GAME_ID, player_id);
+
+        // シードを公開する
+        if let Some(game_seed) = replay.game_seed {
+            logstr.push(format!(
+                "シード : {} （`旗源平 検証 {}` で、すべての出目を検証できる）",
+                game_seed, game_seed
+            ));
+        }
+        self.score_operator.finish_replay(player_id);
+        // 勝敗を書き、終了したゲームの履歴として、リプレイに記録した全手番のログを保存する
+        self.record_bot_game(
+            player_id,
+            player_name,
+            is_win,
+            &GameHistory {
+                game_id: Hatagenpei::GAME_ID.to_string(),
+                players: vec![progress.user.name.clone(), self.bot_name.clone()],
+                player_ids: vec![player_id.to_string(), self.bot_id.clone()],
+                winner: winner.1.to_string(),
+                winner_id: winner.0.to_string(),
+                rule_set_id: progress.rule_set_id.clone(),
+                logs: replay
+                    .steps
+                    .iter()
+                    .flat_map(|step| step.logs.iter().cloned())
+                    .collect(),
+                table_logs: vec![],
+                seeds: vec![],
+            },
+        );
+
+        return logstr;
+    }
+
+    /// player_id と bot のゲームの結果を、勝敗、レーティング、相手ごとの勝敗と履歴に書く
+    fn record_bot_game(
+        &mut self,
+        player_id: &str,
+        player_name: &str,
+        is_win: bool,
+        history: &GameHistory,
+    ) {
+        self.score_operator
+            .update_winloses(player_id, player_name, is_win);
+        let bot_id = self.bot_id.clone();
+        let bot_name = self.bot_name.clone();
+        self.update_ratings((player_id, player_name), (&bot_id, &bot_name), is_win);
+        self.update_head_to_heads((player_id, player_name), (&bot_id, &bot_name), is_win);
+        self.score_operator
+            .insert_game_history(history, &self.bot_id);
+    }
+
+    /// 人間同士の対戦を終わらせ、2人の勝敗と履歴を書く
+    fn finish_match(&mut self, match_progress: &MatchProgress, player1_win: bool) {
+        let (player1, player2) = (&match_progress.player1, &match_progress.player2);
+        self.score_operator.delete_match_progress(&player1.id);
+        self.score_operator
+            .update_winloses(&player1.id, &player1.name, player1_win);
+        self.score_operator
+            .update_winloses(&player2.id, &player2.name, !player1_win);
+        self.update_ratings(
+            (&player1.id, &player1.name),
+            (&player2.id, &player2.name),
+            player1_win,
+        );
+        self.update_head_to_heads(
+            (&player1.id, &player1.name),
+            (&player2.id, &player2.name),
+            player1_win,
+        );
+        self.score_operator.insert_game_history(
+            &GameHistory {
+                game_id: Hatagenpei::GAME_ID.to_string(),
+                players: vec![player1.name.clone(), player2.name.clone()],
+                player_ids: vec![player1.id.clone(), player2.id.clone()],
+                winner: if player1_win {
+                    player1.name.clone()
+                } else {
+                    player2.name.clone()
+                },
+                winner_id: if player1_win {
+                    player1.id.clone()
+                } else {
+                    player2.id.clone()
+                },
+                rule_set_id: match_progress.rule_set_id.clone(),
+                logs: match_progress.logs.clone(),
+                table_logs: vec![],
+                seeds: match_progress.seeds.clone(),
+            },
+            &self.bot_id,
+        );
+    }
+
+    /// player_id の現在のレーティング。まだ記録がなければ初期値
+    fn current_rating(&mut self, player_id: &str) -> Rating {
+        return match self.score_operator.get_ratings(player_id).pop() {
+            Some(record) => record.rating,
+            None => Rating::default(),
+        };
+    }
+
+    /// 1対1のゲームの結果で、2人のレーティングを更新する（player は (id, 名前) の組）
+    fn update_ratings(&mut self, player1: (&str, &str), player2: (&str, &str), player1_win: bool) {
+        if player1_win {
+            self.update_group_ratings(&[player1], &[player2]);
+        } else {
+            self.update_group_ratings(&[player2], &[player1]);
+        }
+    }
+
+    /// 複数人のゲームの結果で、参加者全員のレーティングを更新する（player は (id, 名前) の組）
+    /// 勝った側の各プレイヤーは負けた側の全員に勝ち、負けた側の各プレイヤーは勝った側の全員に負けたとして扱う
+    /// 負けた側どうしの順位はつかないので、負けた側どうしは対戦しなかったものとする
+    fn update_group_ratings(&mut self, winners: &[(&str, &str)], losers: &[(&str, &str)]) {
+        // 更新前のレーティングで全員の結果を計算してから書き込む
+        let winner_ratings: Vec<Rating> =
+            winners.iter().map(|p| self.current_rating(p.0)).collect();
+        let loser_ratings: Vec<Rating> = losers.iter().map(|p| self.current_rating(p.0)).collect();
+        let mut updated = vec![];
+        for (player, rating) in winners.iter().zip(winner_ratings.iter()) {
+            let results: Vec<(Rating, f64)> =
+                loser_ratings.iter().map(|r| (r.clone(), 1.0)).collect();
+            updated.push((player, rating.update(&results)));
+        }
+        for (player, rating) in losers.iter().zip(loser_ratings.iter()) {
+            let results: Vec<(Rating, f64)> =
+                winner_ratings.iter().map(|r| (r.clone(), 0.0)).collect();
+            updated.push((player, rating.update(&results)));
+        }
+
+        let recorded_at = now();
+        for ((id, name), rating) in updated {
+            self.score_operator.insert_rating(&RatingRecord {
+                id: id.to_string(),
+                name: name.to_string(),
+                rating: rating,
+                recorded_at: recorded_at,
+            });
+        }
+    }
+
+    /// 1対1のゲームの結果を、2人それぞれから見た相手との勝敗として書く（player は (id, 名前) の組）
+    fn update_head_to_heads(
+        &mut self,
+        player1: (&str, &str),
+        player2: (&str, &str),
+        player1_win: bool,
+    ) {
+        self.score_operator.update_head_to_head(
+            player1.0,
+            player1.1,
+            player2.0,
+            player2.1,
+            player1_win,
+        );
+        self.score_operator.update_head_to_head(
+            player2.0,
+            player2.1,
+            player1.0,
+            player1.1,
+            !player1_win,
+        );
+    }
+
+    /// player_id の bot とのゲームを、勝敗を記録せずに破棄する。破棄するゲームがなかった場合は false を返す
+    fn discard_bot_game(&mut self, player_id: &str) -> bool {
+        if self.load_game_progress::<Hatagenpei>(player_id).is_none() {
+            return false;
+        }
+        self.score_operator
+            .delete_game_progress(Hatagenpei::GAME_ID, player_id);
+        self.score_operator.delete_replay(player_id);
+        return true;
+    }
+}
+
+/// 現在時刻（UNIX 時間の秒）
+fn now() -> i64 {
+    return chrono::Utc::now().timestamp();
+}
+
+/// ゲーム開始時のプレイヤーを作成する。handicap はハンデとして追加で持つ中旗の本数
+/// id は Slack のユーザー id で、Slack のユーザーでない場合は空にする
+fn new_player(id: &str, name: &str, handicap: i32) -> Player {
+    let mut player = Player::new(
+        name.to_string(),
+        Score::new(
+            HATAGENPEI_INIT_SCORE + handicap * Flag::Chubata.worth(),
+            true,
+        ),
+        Score::new(0, false),
+    );
+    player.id = id.to_string();
+    return player;
+}
+
+/// Hatagenpei::next の実行ログを、表示用の文字列にする
+fn format_game_log(game_log: &GameLog) -> Vec<String> {
+    let mut logstr = vec![];
+    let turn_player_name = &game_log.player(&game_log.player_turn).name;
+
+    logstr.push(format!("# {} の番", turn_player_name).to_string());
+    logstr.push("## サイコロの結果".to_string());
+
+    // サイコロの目の説明文はルールごとに違うので、振ったコマンドのものを順番に使う
+    let mut commands = game_log.commands.iter();
+    for event in &game_log.events {
+        match event {
+            GameEvent::DiceRolled { .. } => {
+                if let Some(cmd) = commands.next() {
+                    logstr.push(format!("- {}", cmd.explain));
+                }
+            }
+            GameEvent::MatoiCaptured { player } => {
+                logstr.push(format!(
+                    "- {} が {} のまといを取った",
+                    game_log.player(player).name,
+                    game_log.opponent(player).name
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    if !game_log.exchanges.is_empty() {
+        logstr.push("".to_string());
+        logstr.push("## 両替".to_string());
+        for exchange in &game_log.exchanges {
+            let exchange_player_name = match exchange.player {
+                PlayerTurn::Player1 => &game_log.player1.name,
+                PlayerTurn::Player2 => &game_log.player2.name,
+            };
+            logstr.push(format!(
+                "- {} が {} 1 本を {} {} 本に両替",
+                exchange_player_name,
+                exchange.from.to_string(),
+                exchange.to.to_string(),
+                exchange.count
+            ));
+        }
+    }
+
+    logstr.push("".to_string());
+    logstr.push("## 旗状況".to_string());
+
+    for player in [&(game_log.player1), &(game_log.player2)].iter() {
+        logstr.push(format!("- {}", player.name));
+        logstr.push(format!("   - 自分の旗 【{}】", player.my_score.to_string()));
+        logstr.push(format!(
+            "   - 取った旗 【{}】",
+            player.got_score.to_string()
+        ));
+    }
+
+    logstr.push("".to_string());
+
+    return logstr;
+}
+
+/// 終了したゲームの記録から、手番数、大きく旗が動いた手番、まといを取った手番の要約を作る
+fn summarize_game_history(history: &GameHistory) -> Vec<String> {
+    let mut logstr = vec![];
+    logstr.push(format!(
+        "{} の勝ち（{} 手番）",
+        history.winner,
+        history.logs.len()
+    ));
+
+    // 手番ごとに、やり取りされた旗の本数（小旗何本分か）を数える
+    let mut swings = vec![];
+    for (i, game_log) in history.logs.iter().enumerate() {
+        let swing: i32 = game_log
+            .events
+            .iter()
+            .map(|event| match event {
+                GameEvent::FlagsTaken { value, .. } => *value,
+                GameEvent::FlagsReturned { value, .. } => *value,
+                _ => 0,
+            })
+            .sum();
+        if swing > 0 {
+            swings.push((i, swing));
+        }
+    }
+    // 大きい順に選び、手番順に並べ直す
+    swings.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    swings.truncate(AUTO_PLAY_SWING_COUNT);
+    swings.sort();
+
+    if !swings.is_empty() {
+        logstr.push("".to_string());
+        logstr.push("## 大きく動いた手番".to_string());
+        for (i, swing) in swings {
+            let game_log = &history.logs[i];
+            logstr.push(format!(
+                "- {} 手番目 {} : 小旗 {} 本分の旗が動いた",
+                i + 1,
+                game_log.player(&game_log.player_turn).name,
+                swing
+            ));
+        }
+    }
+
+    for (i, game_log) in history.logs.iter().enumerate() {
+        for event in &game_log.events {
+            if let GameEvent::MatoiCaptured { player } = event {
+                logstr.push("".to_string());
+                logstr.push(format!(
+                    "{} 手番目で {} が {} のまといを取った",
+                    i + 1,
+                    game_log.player(player).name,
+                    game_log.opponent(player).name
+                ));
+            }
+        }
+    }
+
+    return logstr;
+}
+
+/// Chinchirorin::step の実行ログを、表示用の文字列にする
+fn format_chinchiro_log(game_log: &ChinchiroLog, player_name: &str, bot_name: &str) -> Vec<String> {
+    let mut logstr = vec![];
+    logstr.push(format!("賭け数 : {}", game_log.bet));
+
+    let mut rolls = vec![(bot_name, &game_log.bot_roll)];
+    if let Some(player_roll) = &game_log.player_roll {
+        rolls.push((player_name, player_roll));
+    }
+    for (name, roll) in rolls {
+        logstr.push("".to_string());
+        logstr.push(format!("# {} の番", name));
+        for dice in &roll.rolls {
+            logstr.push(format!("- {} {} {}", dice[0], dice[1], dice[2]));
+        }
+        logstr.push(format!("役 : {}", roll.hand.to_string()));
+    }
+
+    logstr.push("".to_string());
+    logstr.push(if game_log.payout > 0 {
+        format!("{} がチップを {} 枚もらった", player_name, game_log.payout)
+    } else if game_log.payout < 0 {
+        format!("{} がチップを {} 枚払った", player_name, -game_log.payout)
+    } else {
+        "分け".to_string()
+    });
+    logstr.push("".to_string());
+    logstr.push("## 持ちチップ".to_string());
+    logstr.push(format!("- {} : {} 枚", player_name, game_log.player_chips));
+    logstr.push(format!("- {} : {} 枚", bot_name, game_log.bot_chips));
+    logstr.push("".to_string());
+
+    return logstr;
+}
+
+/// MultiHatagenpei::next の実行ログを、表示用の文字列にする
+fn format_multi_game_log(game_log: &MultiGameLog) -> Vec<String> {
+    let name = |seat: usize| &game_log.seats[seat].player.name;
+    let mut logstr = vec![];
+
+    logstr.push(format!("# {} の番", name(game_log.roller)));
+    logstr.push("## サイコロの結果".to_string());
+    for roll in &game_log.rolls {
+        logstr.push(format!(
+            "- 相手 {} : {}",
+            name(roll.target),
+            roll.command.explain
+        ));
+    }
+
+    if !game_log.exchanges.is_empty() {
+        logstr.push("".to_string());
+        logstr.push("## 両替".to_string());
+        for exchange in &game_log.exchanges {
+            logstr.push(format!(
+                "- {} が {} 1 本を {} {} 本に両替",
+                name(exchange.seat),
+                exchange.from.to_string(),
+                exchange.to.to_string(),
+                exchange.count
+            ));
+        }
+    }
+
+    for eliminated in &game_log.eliminated {
+        logstr.push("".to_string());
+        logstr.push(format!("{} がまといを取られて敗退", name(*eliminated)));
+    }
+
+    logstr.push("".to_string());
+    logstr.push("## 旗状況".to_string());
+    for seat in &game_log.seats {
+        if !seat.is_alive() {
+            logstr.push(format!("- {} （敗退）", seat.player.name));
+            continue;
+        }
+        logstr.push(format!("- {}", seat.player.name));
+        logstr.push(format!(
+            "   - 自分の旗 【{}】",
+            seat.player.my_score.to_string()
+        ));
+        logstr.push(format!(
+            "   - 取った旗 【{}】",
+            seat.player.got_score.to_string()
+        ));
+    }
+
+    logstr.push("".to_string());
+
+    return logstr;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exhibition_tests() {
+        let mut controller = HatagenpeiController::new(
+            factor_operater(&DataStore::OnMemory),
+            &"UBOT".to_string(),
+            &"hatagenpeikun".to_string(),
+        );
+        for (name, handicap) in [("tsuwamono", 0), ("minarai", 2)].iter() {
+            controller.add_persona(Persona {
+                name: name.to_string(),
+                handicap: *handicap,
+                rule_set_id: DEFAULT_RULE_SET_ID.to_string(),
+            });
+        }
+
+        assert!(controller
+            .exhibition("tsuwamono", "tsuwamono", None)
+            .is_err());
+        assert!(controller.exhibition("tsuwamono", "nobody", None).is_err());
+        assert!(controller
+            .exhibition("tsuwamono", "minarai", Some("nothing"))
+            .is_err());
+
+        let res = controller.exhibition("tsuwamono", "minarai", None).unwrap();
+        assert!(!res.turns.is_empty());
+
+        // 模範試合の勝敗だけに記録され、人間の勝敗には入らない
+        let win_loses = controller.get_exhibition_win_loses();
+        assert_eq!(win_loses.len(), 2);
+        assert_eq!(win_loses[0].name, res.winner);
+        assert_eq!(win_loses[0].win, 1);
+        assert!(controller.score_operator.get_win_loses().is_empty());
+    }
+
+    #[test]
+    fn chinchiro_tests() {
+        let mut controller = HatagenpeiController::new(
+            factor_operater(&DataStore::OnMemory),
+            &"UBOT".to_string(),
+            &"hatagenpeikun".to_string(),
+        );
+
+        assert!(controller
+            .chinchiro_step("UALICE", "alice", Some(0))
+            .is_err());
+        assert!(controller
+            .chinchiro_step("UALICE", "alice", Some(CHINCHIRO_INIT_CHIPS + 1))
+            .is_err());
+
+        // どちらかのチップがなくなるまで続け、終わったら進行状態は消える
+        let res = controller
+            .chinchiro_step("UALICE", "alice", Some(5))
+            .unwrap();
+        assert!(res.is_start);
+        let mut is_over = res.is_over;
+        while !is_over {
+            let res = controller.chinchiro_step("UALICE", "alice", None).unwrap();
+            assert!(!res.is_start);
+            is_over = res.is_over;
+        }
+        assert!(controller
+            .load_game_progress::<Chinchirorin>("UALICE")
+            .is_none());
+        // 旗源平の進行状態とは別に保存される
+        assert!(controller
+            .load_game_progress::<Hatagenpei>("UALICE")
+            .is_none());
+
+        // 結果は bot とのゲームとして、勝敗、相手ごとの勝敗と履歴に書かれる
+        let win_lose = controller.score_operator.get_win_lose("UALICE").unwrap();
+        assert_eq!(win_lose.win + win_lose.lose, 1);
+        let vs_bot = controller.get_head_to_head("UALICE", "UBOT").unwrap();
+        assert_eq!((vs_bot.win, vs_bot.lose), (win_lose.win, win_lose.lose));
+        let history = controller.get_game_histories("UALICE", 1).pop().unwrap();
+        assert_eq!(history.game_id, Chinchirorin::GAME_ID);
+        assert_eq!(history.winner_id == "UALICE", win_lose.win == 1);
+        // 旗源平の統計には入らない
+        assert_eq!(controller.get_player_stats("UALICE").duel_games, 0);
+    }
+
+    #[test]
+    fn auto_play_tests() {
+        let mut controller = HatagenpeiController::new(
+            factor_operater(&DataStore::OnMemory),
+            &"UBOT".to_string(),
+            &"hatagenpeikun".to_string(),
+        );
+
+        // 1回の呼び出しで決着がつき、要約と履歴が残る
+        let res = controller.auto_play("UALICE", "alice", None);
+        assert!(res.is_start);
+        assert!(controller
+            .load_game_progress::<Hatagenpei>("UALICE")
+            .is_none());
+        let histories = controller.get_game_histories("UALICE", 5);
+        assert_eq!(histories.len(), 1);
+        // player_ids は players と同じ順に bot の id も入るが、bot の履歴は記録しない
+        assert_eq!(histories[0].player_ids, vec!["UALICE", "UBOT"]);
+        assert!(histories[0].is_winner(0) != histories[0].is_winner(1));
+        assert!(controller.get_game_histories("UBOT", 5).is_empty());
+        assert_eq!(
+            res.summary[0],
+            format!(
+                "{} の勝ち（{} 手番）",
+                histories[0].winner,
+                histories[0].logs.len()
+            )
+        );
+
+        // 終了時に公開されたシードで、開始時に公開したコミットメントとすべての出目を検証できる
+        let commitment = res
+            .logs
+            .iter()
+            .find_map(|line| line.strip_prefix("シードのコミットメント : "))
+            .unwrap();
+        let game_seed = res
+            .logs
+            .iter()
+            .find_map(|line| line.strip_prefix("シード : "))
+            .and_then(|line| line.split_whitespace().next())
+            .and_then(|seed| seed.parse::<u64>().ok())
+            .unwrap();
+        assert_eq!(seed_commitment(game_seed), commitment);
+        let (_, result) = controller
+            .verify_latest_replay("UALICE", Some(game_seed))
+            .unwrap();
+        assert!(result.is_ok());
+        let (_, result) = controller
+            .verify_latest_replay("UALICE", Some(game_seed.wrapping_add(1)))
+            .unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn progress_tests() {
+        let mut controller = HatagenpeiController::new(
+            factor_operater(&DataStore::OnMemory),
+            &"UBOT".to_string(),
+            &"hatagenpeikun".to_string(),
+        );
+
+        // step ごとに手番の数と時刻が記録され、開始時刻とルールは始めたときのまま残る
+        controller.step("UALICE", "alice", None);
+        let progress = controller
+            .load_game_progress::<Hatagenpei>("UALICE")
+            .unwrap();
+        assert_eq!(progress.version, PROGRESS_VERSION);
+        assert_eq!(progress.step, 1);
+        assert_eq!(progress.turn, PlayerTurn::Player1);
+        assert_eq!(progress.rule_set, Some(RuleSet::default()));
+        assert!(progress.started_at.is_some());
+        controller.step("UALICE", "alice", None);
+        let next_progress = controller
+            .load_game_progress::<Hatagenpei>("UALICE")
+            .unwrap();
+        assert_eq!(next_progress.step, 2);
+        assert_eq!(next_progress.started_at, progress.started_at);
+
+        // 途中で同じ id のルールが変わっても、始めたときのルールで続ける
+        let original = controller.rule_set_or_default(DEFAULT_RULE_SET_ID);
+        controller.save_game_progress::<Hatagenpei>(
+            "UCAROL",
+            &Progress::new(
+                &new_player("UCAROL", "carol", 0),
+                &new_player("UBOT", "hatagenpeikun", 0),
+                &original,
+            ),
+        );
+        controller.add_rule_set(RuleSet {
+            commands: original
+                .commands
+                .iter()
+                .map(|cmd| HatagenpeiCommand {
+                    explain: "変更後のルール".to_string(),
+                    ..cmd.clone()
+                })
+                .collect(),
+            ..original.clone()
+        });
+        let result = controller.step("UCAROL", "carol", None);
+        assert!(result
+            .logs
+            .iter()
+            .all(|log| !log.contains("変更後のルール")));
+        if let Some(progress) = controller.load_game_progress::<Hatagenpei>("UCAROL") {
+            assert_eq!(progress.rule_set, Some(original.clone()));
+        }
+
+        // 勝率は保存した手番から計算する
+        let mut progress = Progress::new(
+            &new_player("UDAVE", "dave", 0),
+            &new_player("UBOT", "hatagenpeikun", 0),
+            &original,
+        );
+        progress.turn = PlayerTurn::Player2;
+        controller.save_game_progress::<Hatagenpei>("UDAVE", &progress);
+        let probability = controller.get_win_probability("UDAVE").unwrap();
+        let expected = win_probability(&progress, &original);
+        assert!((probability.player1 - expected.player1).abs() < 1e-9);
+        let player1_turn = win_probability(
+            &Progress {
+                turn: PlayerTurn::Player1,
+                ..progress.clone()
+            },
+            &original,
+        );
+        assert!((probability.player1 - player1_turn.player1).abs() > 1e-9);
+
+        // バージョンを記録する前の形式でも読み込める
+        let mut value = serde_json::to_value(&progress).unwrap();
+        for key in ["version", "turn", "step", "rule_set", "started_at"].iter() {
+            value.as_object_mut().unwrap().remove(*key);
+        }
+        let old_progress: Progress = serde_json::from_value(value).unwrap();
+        assert_eq!(old_progress.version, 0);
+        assert_eq!(old_progress.turn, PlayerTurn::Player1);
+        assert_eq!(old_progress.step, 0);
+        assert!(old_progress.rule_set.is_none());
+        assert!(old_progress.started_at.is_none());
+
+        // 実物のサイコロで始めるゲームでも、ルールを選べる
+        controller.add_rule_set(RuleSet {
+            id: "other".to_string(),
+            ..RuleSet::default()
+        });
+        controller.step_with_dice("UBOB", "bob", 3, 4, Some("other"));
+        let progress = controller.load_game_progress::<Hatagenpei>("UBOB").unwrap();
+        assert_eq!(progress.rule_set_id, "other");
+
+        // 1 から 6 でない目では、ゲームを進めない
+        let result = controller.step_with_dice("UERIN", "erin", 0, 7, None);
+        assert!(!result.is_start && !result.is_over);
+        assert_eq!(result.logs.len(), 1);
+        assert!(controller
+            .load_game_progress::<Hatagenpei>("UERIN")
+            .is_none());
+    }
+
+    #[test]
+    fn leaderboard_tests() {
+        let mut controller = HatagenpeiController::new(
+            factor_operater(&DataStore::OnMemory),
+            &"UBOT".to_string(),
+            &"hatagenpeikun".to_string(),
+        );
+        // 1 人目から順に (勝ち, 負け) = (4, 1), (2, 3), (8, 2), (1, 0)、最後の 1 人は対戦数が足りない
+        let records = [(4, 1), (2, 3), (8, 2), (1, 0)];
+        for (i, (win, lose)) in records.iter().enumerate() {
+            let id = format!("U{}", i);
+            let name = format!("player{}", i);
+            for j in 0..(win + lose) {
+                controller
+                    .score_operator
+                    .update_winloses(&id, &name, j < *win);
+                controller.update_head_to_heads((&id, &name), ("UBOT", "hatagenpeikun"), j < *win);
+            }
+        }
+
+        // 勝率 0.8 の 2 人は同じ順位になり、次は 3 位になる。bot は 6 勝 15 敗
+        let leaderboard = controller.get_leaderboard(&LeaderboardOrder::WinRate, "U3", 1);
+        let ranks = leaderboard
+            .entries
+            .iter()
+            .map(|entry| (entry.rank, entry.id.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(ranks, vec![(1, "U2"), (1, "U0"), (3, "U1"), (4, "UBOT")]);
+        assert!(!leaderboard.is_ranked);
+        assert!(leaderboard.own.is_none());
+
+        // 勝数順では全員が入る。ページに載らないプレイヤーは own で返る
+        let leaderboard = controller.get_leaderboard(&LeaderboardOrder::Wins, "U3", 1);
+        assert_eq!(leaderboard.entries.len(), 5);
+        assert_eq!(leaderboard.entries[0].id, "U2");
+        assert_eq!(leaderboard.page_count, 1);
+        assert!(leaderboard.is_ranked);
+        assert!(leaderboard.own.is_none());
+
+        // レーティング順では、記録のあるプレイヤーだけが入る
+        controller.step("U0", "player0", None);
+        controller.surrender("U0", "player0");
+        let leaderboard = controller.get_leaderboard(&LeaderboardOrder::Rating, "U1", 1);
+        let ids = leaderboard
+            .entries
+            .iter()
+            .map(|entry| entry.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["UBOT", "U0"]);
+        assert!(!leaderboard.is_ranked);
+    }
+
+    #[test]
+    fn stats_tests() {
+        let mut controller = HatagenpeiController::new(
+            factor_operater(&DataStore::OnMemory),
+            &"UBOT".to_string(),
+            &"hatagenpeikun".to_string(),
+        );
+        assert_eq!(controller.get_player_stats("UALICE").games, 0);
+
+        for _ in 0..5 {
+            controller.auto_play("UALICE", "alice", None);
+        }
+        let stats = controller.get_player_stats("UALICE");
+        let win_lose = controller.score_operator.get_win_lose("UALICE").unwrap();
+        // bot とのゲームでは、いつも先手になる
+        assert_eq!(stats.duel_games, 5);
+        assert_eq!(stats.first, (win_lose.win, win_lose.lose));
+        assert_eq!(stats.second, (0, 0));
+        assert_eq!(stats.average_turns_to_win.is_some(), win_lose.win > 0);
+        assert!(stats.longest_win_streak + stats.longest_lose_streak <= 5);
+        assert!(stats.longest_win_streak as i32 <= win_lose.win);
+        assert!(!stats.frequent_dice.is_empty());
+
+        // 挑戦を受けた対戦では後手になる
+        assert!(controller
+            .challenge("UBOB", "bob", "UALICE", "alice", None)
+            .is_ok());
+        assert!(controller.accept_challenge("UALICE", "alice").is_ok());
+        controller.surrender("UALICE", "alice");
+        let stats = controller.get_player_stats("UALICE");
+        assert_eq!(stats.games, 6);
+        assert_eq!(stats.second, (0, 1));
+        assert!(stats.longest_lose_streak >= 1);
+    }
+
+    #[test]
+    fn head_to_head_tests() {
+        let mut controller = HatagenpeiController::new(
+            factor_operater(&DataStore::OnMemory),
+            &"UBOT".to_string(),
+            &"hatagenpeikun".to_string(),
+        );
+        controller.auto_play("UALICE", "alice", None);
+        controller.auto_play("UALICE", "alice", None);
+        assert!(controller
+            .challenge("UALICE", "alice", "UBOB", "bob", None)
+            .is_ok());
+        assert!(controller.accept_challenge("UBOB", "bob").is_ok());
+        controller.surrender("UBOB", "bob");
+
+        // 相手ごとに、両方から見た勝敗が記録される
+        let vs_bob = controller.get_head_to_head("UALICE", "UBOB").unwrap();
+        assert_eq!((vs_bob.win, vs_bob.lose), (1, 0));
+        let vs_alice = controller.get_head_to_head("UBOB", "UALICE").unwrap();
+        assert_eq!((vs_alice.win, vs_alice.lose), (0, 1));
+        assert!(controller.get_head_to_head("UBOB", "UBOT").is_none());
+        assert_eq!(controller.get_head_to_heads("UALICE").len(), 2);
+
+        // bot の勝敗には、人間同士の対戦は入らない
+        let vs_bot = controller.get_head_to_head("UALICE", "UBOT").unwrap();
+        let bot_win_lose = controller.get_win_loses().pop().unwrap();
+        assert_eq!(
+            (bot_win_lose.win, bot_win_lose.lose),
+            (vs_bot.lose, vs_bot.win)
+        );
+        assert_eq!(bot_win_lose.win + bot_win_lose.lose, 2);
+    }
+
+    #[test]
+    fn backfill_bot_head_to_heads_tests() {
+        let mut controller = HatagenpeiController::new(
+            factor_operater(&DataStore::OnMemory),
+            &"UBOT".to_string(),
+            &"hatagenpeikun".to_string(),
+        );
+        // 相手ごとの勝敗を記録する前に、alice が bot に 3 勝 1 敗していた
+        for is_win in [true, true, true, false].iter() {
+            controller
+                .score_operator
+                .update_winloses("UALICE", "alice", *is_win);
+        }
+        // 人間同士の対戦と卓の結果は、bot の勝敗に入らない
+        assert!(controller
+            .challenge("UALICE", "alice", "UBOB", "bob", None)
+            .is_ok());
+        assert!(controller.accept_challenge("UBOB", "bob").is_ok());
+        controller.surrender("UBOB", "bob");
+        for (id, name) in [("UALICE", "alice"), ("UBOB", "bob"), ("UCAROL", "carol")].iter() {
+            assert!(controller.join_table("C1", id, name, None).is_ok());
+        }
+        assert!(controller.start_table("C1", "UALICE", "alice").is_ok());
+        loop {
+            let table = controller.score_operator.get_table("C1").unwrap();
+            let player = table.seats[table.turn].player.clone();
+            if controller
+                .table_step("C1", &player.id, &player.name, None)
+                .unwrap()
+                .is_over
+            {
+                break;
+            }
+        }
+        // チーム戦はプレイヤーの勝敗を書かないので、履歴にあっても引かない
+        assert!(controller
+            .join_team("C2", "UALICE", "alice", Side::Genji, None)
+            .is_ok());
+        assert!(controller
+            .join_team("C2", "UBOB", "bob", Side::Heike, None)
+            .is_ok());
+        assert!(controller.start_team_match("C2", "UALICE", "alice").is_ok());
+        let order = [("UALICE", "alice"), ("UBOB", "bob")];
+        let mut turns = 0;
+        while !controller
+            .team_step("C2", order[turns % 2].0, order[turns % 2].1)
+            .unwrap()
+            .is_over
+        {
+            turns += 1;
+        }
+        assert_eq!(controller.get_game_histories("UALICE", 5).len(), 3);
+
+        assert_eq!(controller.backfill_bot_head_to_heads(), 1);
+        let vs_bot = controller.get_head_to_head("UALICE", "UBOT").unwrap();
+        assert_eq!((vs_bot.win, vs_bot.lose), (3, 1));
+        assert!(controller.get_head_to_head("UBOB", "UBOT").is_none());
+        assert!(controller.get_head_to_head("UCAROL", "UBOT").is_none());
+        let bot_win_lose = controller.get_win_loses().pop().unwrap();
+        assert_eq!((bot_win_lose.win, bot_win_lose.lose), (1, 3));
+
+        // 1 度書いたら、もう書かない
+        assert_eq!(controller.backfill_bot_head_to_heads(), 0);
+        let vs_bot = controller.get_head_to_head("UALICE", "UBOT").unwrap();
+        assert_eq!((vs_bot.win, vs_bot.lose), (3, 1));
+    }
+
+    #[test]
+    fn player_id_tests() {
+        let mut controller = HatagenpeiController::new(
+            factor_operater(&DataStore::OnMemory),
+            &"UBOT".to_string(),
+            &"hatagenpeikun".to_string(),
+        );
+
+        // 名前を変えても、同じ id なら同じゲームの続きになる
+        assert!(controller.step("UALICE", "alice", None).is_start);
+        assert!(!controller.step("UALICE", "alice2", None).is_start);
+        // 同じ名前でも、id が違えば別のプレイヤーになる
+        assert!(controller.step("UALICE2", "alice", None).is_start);
+
+        // 勝敗は id ごとに記録され、名前は新しいものになる
+        // 最初のゲームが step の間に終わっていることもあるので、ゲームの数は履歴と比べる
+        controller.auto_play("UALICE", "alice2", None);
+        controller.auto_play("UALICE", "alice3", None);
+        let win_lose = controller.score_operator.get_win_lose("UALICE").unwrap();
+        let histories = controller.get_game_histories("UALICE", 5);
+        assert_eq!(win_lose.name, "alice3");
+        assert!(histories.len() >= 2);
+        assert_eq!((win_lose.win + win_lose.lose) as usize, histories.len());
+    }
+
+    #[test]
+    fn surrender_tests() {
+        let mut controller = HatagenpeiController::new(
+            factor_operater(&DataStore::OnMemory),
+            &"UBOT".to_string(),
+            &"hatagenpeikun".to_string(),
+        );
+
+        // 降参すると負けとして記録され、進行状態は消える
+        assert!(controller.surrender("UALICE", "alice").is_none());
+        controller.step("UALICE", "alice", None);
+        assert!(controller.surrender("UALICE", "alice").is_some());
+        assert!(controller
+            .load_game_progress::<Hatagenpei>("UALICE")
+            .is_none());
+        let win_lose = controller.score_operator.get_win_lose("UALICE").unwrap();
+        assert_eq!((win_lose.win, win_lose.lose), (0, 1));
+        assert_eq!(
+            controller.get_game_histories("UALICE", 5)[0].winner,
+            "hatagenpeikun"
+        );
+        assert!(controller.surrender("UALICE", "alice").is_none());
+        // bot とのゲームでは、bot のレーティングも更新される
+        assert!(controller.get_ratings("UALICE", 5)[0].rating.rating < INIT_RATING);
+        assert!(controller.get_ratings("UBOT", 5)[0].rating.rating > INIT_RATING);
+
+        // 破棄すると勝敗は記録されない
+        assert!(!controller.abandon("UALICE"));
+        controller.step("UALICE", "alice", None);
+        assert!(controller.abandon("UALICE"));
+        assert!(controller
+            .load_game_progress::<Hatagenpei>("UALICE")
+            .is_none());
+        let win_lose = controller.score_operator.get_win_lose("UALICE").unwrap();
+        assert_eq!((win_lose.win, win_lose.lose), (0, 1));
+        assert_eq!(controller.get_ratings("UALICE", 5).len(), 1);
+    }
+
+    #[test]
+    fn expire_tests() {
+        let mut controller = HatagenpeiController::new(
+            factor_operater(&DataStore::OnMemory),
+            &"UBOT".to_string(),
+            &"hatagenpeikun".to_string(),
+        );
+        controller.step("UALICE", "alice", None);
+
+        // 期限が設定されていなければ破棄しない
+        assert!(controller.expire_stale_games(now() + 200).is_empty());
+
+        controller.set_expire_after(Some(100));
+        assert!(controller.expire_stale_games(now()).is_empty());
+        controller.step("UBOB", "bob", None);
+        // 前回探してから EXPIRY_CHECK_INTERVAL 秒たつまでは探さない
+        assert!(controller.expire_stale_games(now() + 200).is_empty());
+        let expired = controller.expire_stale_games(now() + EXPIRY_CHECK_INTERVAL + 200);
+        assert_eq!(expired.len(), 2);
+        assert!(controller
+            .load_game_progress::<Hatagenpei>("UALICE")
+            .is_none());
+        assert!(controller.score_operator.get_win_loses().is_empty());
+    }
+
+    #[test]
+    fn handicap_tests() {
+        let mut controller = HatagenpeiController::new(
+            factor_operater(&DataStore::OnMemory),
+            &"UBOT".to_string(),
+            &"hatagenpeikun".to_string(),
+        );
+        assert_eq!(controller.get_handicap("UALICE"), 0);
+
+        // 範囲外のハンデは設定できず、前の設定のまま残る
+        assert!(controller.set_handicap("UALICE", "alice", Some(3)).is_ok());
+        assert!(controller
+            .set_handicap("UALICE", "alice", Some(-1))
+            .is_err());
+        assert!(controller
+            .set_handicap("UALICE", "alice", Some(HANDICAP_MAX + 1))
+            .is_err());
+        assert!(!controller
+            .score_operator
+            .set_handicap("UALICE", "alice", Some(HANDICAP_MAX + 1)));
+        assert_eq!(controller.get_handicap("UALICE"), 3);
+
+        // まだ遊んでいないプレイヤーにハンデを設定しても、勝敗の記録には出てこない
+        assert!(controller.get_win_loses().iter().all(|w| w.id != "UALICE"));
+        assert!(controller.set_handicap("UALICE", "alice", None).is_ok());
+        assert!(controller.score_operator.get_win_lose("UALICE").is_none());
+
+        // 自動に戻すと、勝率から決まる
+        for _ in 0..HANDICAP_MIN_GAMES {
+            controller
+                .score_operator
+                .update_winloses("UBOB", "bob", false);
+        }
+        assert_eq!(controller.get_handicap("UBOB"), 2);
+        assert!(controller.set_handicap("UBOB", "bob", Some(0)).is_ok());
+        assert_eq!(controller.get_handicap("UBOB"), 0);
+        assert!(controller.set_handicap("UBOB", "bob", None).is_ok());
+        assert_eq!(controller.get_handicap("UBOB"), 2);
+    }
+
+    #[test]
+    fn match_tests() {
+        let mut controller = HatagenpeiController::new(
+            factor_operater(&DataStore::OnMemory),
+            &"UBOT".to_string(),
+            &"hatagenpeikun".to_string(),
+        );
+
+        assert!(controller
+            .challenge("alice", "alice", "alice", "alice", None)
+            .is_err());
+        assert!(controller
+            .challenge("alice", "alice", "UBOT", "hatagenpeikun", None)
+            .is_err());
+        assert!(controller
+            .challenge("alice", "alice", "bob", "bob", None)
+            .is_ok());
+        assert!(controller
+            .challenge("carol", "carol", "bob", "bob", None)
+            .is_err());
+        assert!(controller.accept_challenge("bob", "bob").is_ok());
+        assert!(controller.has_match("alice") && controller.has_match("bob"));
+
+        // 人間同士の対戦でも、それぞれから見た勝率が求まる
+        let alice = controller.get_win_probability("alice").unwrap();
+        let bob = controller.get_win_probability("bob").unwrap();
+        assert!((alice.player1 - bob.player2()).abs() < 1e-6);
+        assert!((alice.player1 + bob.player1 - 1.0).abs() < 1e-6);
+        assert!(controller.get_win_probability("carol").is_none());
+
+        // 交互にサイコロを振り、手番でないプレイヤーは振れない
+        let mut players = ["alice", "bob"].iter().cycle();
+        loop {
+            let player = players.next().unwrap();
+            let other = if *player == "alice" { "bob" } else { "alice" };
+            assert!(controller.match_step(other, other).is_err());
+            if controller.match_step(player, player).unwrap().is_over {
+                break;
+            }
+        }
+
+        // 終わったら対戦は消え、2人とも勝敗が記録される
+        assert!(!controller.has_match("alice") && !controller.has_match("bob"));
+        let win_loses = controller.score_operator.get_win_loses();
+        assert_eq!(win_loses.len(), 2);
+        assert_eq!(win_loses.iter().map(|w| w.win + w.lose).sum::<i32>(), 2);
+
+        // 2人とも、同じ対戦の全手番が履歴に残る
+        let histories = controller.get_game_histories("alice", 5);
+        assert_eq!(histories.len(), 1);
+        assert!(controller.get_game_histories("alice", 0).is_empty());
+        assert_eq!(
+            histories[0].logs.len(),
+            controller.get_game_histories("bob", 5)[0].logs.len()
+        );
+        assert!(histories[0].logs.last().unwrap().game_state != GameState::YetPlaying);
+        // 手番ごとにサイコロのシードが記録される
+        assert_eq!(histories[0].seeds.len(), histories[0].logs.len());
+    }
+
+    #[test]
+    fn table_tests() {
+        let mut controller = HatagenpeiController::new(
+            factor_operater(&DataStore::OnMemory),
+            &"UBOT".to_string(),
+            &"hatagenpeikun".to_string(),
+        );
+
+        assert!(controller.join_table("C1", "alice", "alice", None).is_ok());
+        assert!(controller.join_table("C1", "alice", "alice", None).is_err());
+        assert!(controller.join_table("C1", "bob", "bob", None).is_ok());
+        // 2人では始められない
+        assert!(controller.start_table("C1", "alice", "alice").is_err());
+        assert!(controller.join_table("C1", "carol", "carol", None).is_ok());
+        assert!(controller.start_table("C1", "dave", "dave").is_err());
+        assert!(controller.start_table("C1", "alice", "alice").is_ok());
+        assert!(controller.join_table("C1", "dave", "dave", None).is_err());
+
+        // 自分や卓にいないプレイヤーは相手に選べない
+        assert!(controller
+            .table_step("C1", "alice", "alice", Some(("alice", "alice")))
+            .is_err());
+        assert!(controller
+            .table_step("C1", "alice", "alice", Some(("dave", "dave")))
+            .is_err());
+
+        // 手番のプレイヤーだけが振れる
+        let mut steps = 0;
+        loop {
+            let table = controller.score_operator.get_table("C1").unwrap();
+            let turn_player = table.seats[table.turn].player.id.clone();
+            for seat in &table.seats {
+                if seat.player.id != turn_player {
+                    assert!(controller
+                        .table_step("C1", &seat.player.id, &seat.player.name, None)
+                        .is_err());
+                }
+            }
+            steps += 1;
+            if controller
+                .table_step("C1", &turn_player, &turn_player, None)
+                .unwrap()
+                .is_over
+            {
+                break;
+            }
+        }
+        assert!(steps > 1);
+
+        // 終わったら卓は消え、3人とも勝敗が記録される
+        assert!(controller.score_operator.get_table("C1").is_none());
+        let win_loses = controller.score_operator.get_win_loses();
+        assert_eq!(win_loses.len(), 3);
+        assert_eq!(win_loses.iter().map(|w| w.win).sum::<i32>(), 1);
+        assert_eq!(win_loses.iter().map(|w| w.lose).sum::<i32>(), 2);
+
+        // 3人とも、同じ卓の全手番が履歴に残る
+        for id in ["alice", "bob", "carol"].iter() {
+            let histories = controller.get_game_histories(id, 5);
+            assert_eq!(histories.len(), 1);
+            assert_eq!(histories[0].player_ids, vec!["alice", "bob", "carol"]);
+            assert_eq!(histories[0].table_logs.len(), steps);
+            assert_eq!(histories[0].seeds.len(), steps);
+        }
+
+        // 3人ともレーティングが記録され、勝った1人だけが上がる
+        let winner = controller.get_game_histories("alice", 5)[0]
+            .winner_id
+            .clone();
+        for id in ["alice", "bob", "carol"].iter() {
+            let ratings = controller.get_ratings(id, 5);
+            assert_eq!(ratings.len(), 1);
+            assert_eq!(
+                ratings[0].rating.rating > Rating::default().rating,
+                *id == winner
+            );
+        }
+    }
+
+    #[test]
+    fn team_match_tests() {
+        let mut controller = HatagenpeiController::new(
+            factor_operater(&DataStore::OnMemory),
+            &"UBOT".to_string(),
+            &"hatagenpeikun".to_string(),
+        );
+
+        assert!(controller
+            .join_team("C1", "alice", "alice", Side::Genji, None)
+            .is_ok());
+        assert!(controller
+            .join_team("C1", "alice", "alice", Side::Heike, None)
+            .is_err());
+        // 平家に誰もいないと始められない
+        assert!(controller.start_team_match("C1", "alice", "alice").is_err());
+        assert!(controller
+            .join_team("C1", "bob", "bob", Side::Heike, None)
+            .is_ok());
+        assert!(controller
+            .join_team("C1", "carol", "carol", Side::Heike, None)
+            .is_ok());
+        assert!(controller.start_team_match("C1", "alice", "alice").is_ok());
+
+        // 源氏は alice、平家は bob と carol が交互に振る
+        let order = ["alice", "bob", "alice", "carol"];
+        let mut turns = 0;
+        loop {
+            let player = order[turns % order.len()];
+            assert!(controller.team_step("C1", "dave", "dave").is_err());
+            turns += 1;
+            if controller.team_step("C1", player, player).unwrap().is_over {
+                break;
+            }
+        }
+
+        // 終わったら陣営の勝敗と、3人分の貢献度が記録される
+        assert!(controller.score_operator.get_team_match("C1").is_none());
+        let team_win_loses = controller.get_team_win_loses();
+        assert_eq!(team_win_loses.iter().map(|w| w.win).sum::<i32>(), 1);
+        assert_eq!(team_win_loses.iter().map(|w| w.lose).sum::<i32>(), 1);
+        let contributions = controller.get_contributions();
+        assert_eq!(contributions.len(), 3);
+        assert_eq!(
+            contributions.iter().map(|c| c.rolls).sum::<i32>(),
+            turns as i32
+        );
+        assert_eq!(
+            contributions.iter().map(|c| c.matoi_captures).sum::<i32>(),
+            1
+        );
+
+        // 3人ともレーティングが記録され、勝った陣営のメンバーだけが上がる
+        for contribution in &contributions {
+            let ratings = controller.get_ratings(&contribution.id, 5);
+            assert_eq!(ratings.len(), 1);
+            assert_eq!(
+                ratings[0].rating.rating > Rating::default().rating,
+                contribution.wins == 1
+            );
+        }
     }
 }
@@ -0,0 +1,89 @@
+//!
+//! 旗源平で使うサイコロ
+//!
+
+use rand::{Rng, SeedableRng};
+use std::collections::VecDeque;
+
+/// サイコロの目を出すもの
+pub trait DiceSource {
+    /// サイコロを1つ振り、1 から 6 の目を返す
+    fn roll(&mut self) -> u8;
+}
+
+/// シードから決まる疑似乱数で振るサイコロ。同じシードなら同じ目が出るので、リプレイで再現できる
+pub struct SeededDice {
+    rng: rand_xoshiro::Xoshiro256StarStar,
+}
+
+impl SeededDice {
+    pub fn new(seed: u64) -> SeededDice {
+        return SeededDice {
+            rng: rand_xoshiro::Xoshiro256StarStar::seed_from_u64(seed),
+        };
+    }
+}
+
+impl DiceSource for SeededDice {
+    /// gen::<u8>() % 6 だと 256 が 6 で割り切れず目に偏りが出るので、範囲指定で一様に選ぶ
+    fn roll(&mut self) -> u8 {
+        return self.rng.gen_range(1, 7);
+    }
+}
+
+/// OS の乱数で振るサイコロ。再現はできない
+pub struct EntropyDice {
+    rng: rand::rngs::OsRng,
+}
+
+impl EntropyDice {
+    pub fn new() -> EntropyDice {
+        return EntropyDice {
+            rng: rand::rngs::OsRng,
+        };
+    }
+}
+
+impl DiceSource for EntropyDice {
+    fn roll(&mut self) -> u8 {
+        return self.rng.gen_range(1, 7);
+    }
+}
+
+/// あらかじめ決めた目を順番に出すサイコロ
+/// テストで特定の展開を作る場合や、実物のサイコロを振って出た目を入力して遊ぶ場合に使う
+pub struct ScriptedDice {
+    rolls: VecDeque<u8>,
+    /// 決めた目を出し切った後に使うサイコロ。None の場合、出し切った後に振ると panic する
+    fallback: Option<Box<dyn DiceSource>>,
+}
+
+impl ScriptedDice {
+    pub fn new(rolls: &[u8]) -> ScriptedDice {
+        return ScriptedDice {
+            rolls: rolls.iter().cloned().collect(),
+            fallback: None,
+        };
+    }
+
+    /// rolls を出し切った後は fallback で振るサイコロを作る
+    pub fn with_fallback(rolls: &[u8], fallback: Box<dyn DiceSource>) -> ScriptedDice {
+        return ScriptedDice {
+            rolls: rolls.iter().cloned().collect(),
+            fallback: Some(fallback),
+        };
+    }
+}
+
+impl DiceSource for ScriptedDice {
+    fn roll(&mut self) -> u8 {
+        if let Some(face) = self.rolls.pop_front() {
+            return face;
+        }
+        return self
+            .fallback
+            .as_mut()
+            .expect("scripted dice has run out")
+            .roll();
+    }
+}
@@ -0,0 +1,47 @@
+//!
+//! bot と遊ぶサイコロゲームに共通の操作
+//! 進行状態をストレージに保存しておき、コマンドのたびに start で再開して step で進める
+//!
+
+use super::dice::DiceSource;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// ゲームの状態
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiceGameState {
+    YetPlaying,
+    /// winner の勝ちで終わった
+    Finished {
+        winner: String,
+    },
+}
+
+pub trait DiceGame: Sized {
+    /// ストレージに保存しておく進行状態
+    type Progress: Serialize + DeserializeOwned;
+    /// 進行状態とは別に渡す、ゲームの設定（旗源平のルールなど）
+    type Config;
+    /// step 1 回分のログ
+    type Log;
+
+    /// ゲームの id。進行状態を保存するときに、ゲームの区別に使う
+    const GAME_ID: &'static str;
+
+    /// 進行状態 progress から、ゲームを始める（途中から再開する）
+    fn start(progress: Self::Progress, config: Self::Config, dice: Box<dyn DiceSource>) -> Self;
+    /// 1 手番進める。ゲームが終わっている場合は None
+    fn step(&mut self) -> Option<Self::Log>;
+    /// 現在のゲームの状態
+    fn state(&self) -> DiceGameState;
+    /// 現在の進行状態を、保存できる形にする
+    fn serialize(&self) -> Self::Progress;
+
+    /// 勝ったプレイヤーの名前。まだ終わっていない場合は None
+    fn winner(&self) -> Option<String> {
+        return match self.state() {
+            DiceGameState::YetPlaying => None,
+            DiceGameState::Finished { winner } => Some(winner),
+        };
+    }
+}
@@ -2,27 +2,147 @@
 //! 旗源平を実現するモジュール
 //!
 
-extern crate rand;
-use rand::{Rng, SeedableRng};
+use super::dice::DiceSource;
+use super::dice_game::{DiceGame, DiceGameState};
+use super::rule_set::RuleSet;
+use super::score_operator::Progress;
 use serde::{Deserialize, Serialize};
-use std::mem;
+use std::collections::{BTreeMap, BTreeSet};
 use std::string::ToString;
 
+/// 旗の種類
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum Flag {
+    Obata,
+    Chubata,
+    Kobata,
+}
+
+impl Flag {
+    /// 小旗何本分にあたるか
+    pub fn worth(&self) -> i32 {
+        return match self {
+            Flag::Obata => 50,
+            Flag::Chubata => 10,
+            Flag::Kobata => 1,
+        };
+    }
+
+    /// 両替したときに、何の旗が何本になるか。小旗は両替できない
+    pub fn exchange_to(&self) -> Option<(Flag, i32)> {
+        return match self {
+            Flag::Obata => Some((Flag::Chubata, 5)),
+            Flag::Chubata => Some((Flag::Kobata, 10)),
+            Flag::Kobata => None,
+        };
+    }
+
+    pub fn to_string(&self) -> String {
+        return match self {
+            Flag::Obata => "大旗",
+            Flag::Chubata => "中旗",
+            Flag::Kobata => "小旗",
+        }
+        .to_string();
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(from = "ScoreRepr")]
 pub struct Score {
-    /// 現在所持している旗の本数
-    pub score: i32,
+    /// 現在所持している大旗の本数
+    pub obata: i32,
+    /// 現在所持している中旗の本数
+    pub chubata: i32,
+    /// 現在所持している小旗の本数
+    pub kobata: i32,
     /// 現在まといを所持しているか（まといがなくなると負ける）
     pub matoi: bool,
 }
 
+/// Score のデシリアライズ用の表現
+/// 旗の本数を合計値（score）だけで持っていた頃のデータも読めるようにしておく
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ScoreRepr {
+    Flags {
+        obata: i32,
+        chubata: i32,
+        kobata: i32,
+        matoi: bool,
+    },
+    Legacy {
+        score: i32,
+        matoi: bool,
+    },
+}
+
+impl From<ScoreRepr> for Score {
+    fn from(repr: ScoreRepr) -> Score {
+        return match repr {
+            ScoreRepr::Flags {
+                obata,
+                chubata,
+                kobata,
+                matoi,
+            } => Score {
+                obata: obata,
+                chubata: chubata,
+                kobata: kobata,
+                matoi: matoi,
+            },
+            ScoreRepr::Legacy { score, matoi } => Score::new(score, matoi),
+        };
+    }
+}
+
+/// 両替の記録
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Exchange {
+    /// 両替を行ったプレイヤー
+    pub player: PlayerTurn,
+    /// 両替に出した旗（1本）
+    pub from: Flag,
+    /// 両替で受け取った旗
+    pub to: Flag,
+    /// 両替で受け取った旗の本数
+    pub count: i32,
+}
+
+/// ゲームの進行中に起きた出来事
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum GameEvent {
+    /// player がサイコロを振った
+    DiceRolled {
+        player: PlayerTurn,
+        dice1: u8,
+        dice2: u8,
+    },
+    /// player が相手の旗を value 本分（小旗何本分か）もらった
+    FlagsTaken { player: PlayerTurn, value: i32 },
+    /// player が取った旗を value 本分（小旗何本分か）相手に返した
+    FlagsReturned { player: PlayerTurn, value: i32 },
+    /// player が相手のまといを取った
+    MatoiCaptured { player: PlayerTurn },
+    /// player がもう一度サイコロを振ることになった
+    Rerolled { player: PlayerTurn },
+    /// player が勝った
+    GameWon { player: PlayerTurn },
+}
+
 // ゲームのログ情報
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct GameLog {
     /// 今回ゲームを実行したプレイヤー
     pub player_turn: PlayerTurn,
     /// 実行したコマンド
     pub commands: Vec<HatagenpeiCommand>,
+    /// commands の実行中に発生した両替
+    pub exchanges: Vec<Exchange>,
+    /// commands の実行中に起きた出来事（起きた順）
+    /// イベント導入前に保存されたログでは空になる
+    #[serde(default)]
+    pub events: Vec<GameEvent>,
     /// commands をすべて実行した後の、player1の情報
     pub player1: Player,
     /// commands をすべて実行した後の、player2の情報
@@ -31,15 +151,106 @@ pub struct GameLog {
     pub game_state: GameState,
 }
 
+impl GameLog {
+    /// turn で指定されたプレイヤーの情報
+    pub fn player(&self, turn: &PlayerTurn) -> &Player {
+        return match turn {
+            PlayerTurn::Player1 => &self.player1,
+            PlayerTurn::Player2 => &self.player2,
+        };
+    }
+
+    /// turn で指定されたプレイヤーの相手の情報
+    pub fn opponent(&self, turn: &PlayerTurn) -> &Player {
+        return match turn {
+            PlayerTurn::Player1 => &self.player2,
+            PlayerTurn::Player2 => &self.player1,
+        };
+    }
+}
+
 impl Score {
+    /// 小旗 value 本分の旗を、大きい旗からできるだけ少ない本数になるように持った Score を作る
+    pub fn new(value: i32, matoi: bool) -> Score {
+        return Score {
+            obata: value / 50,
+            chubata: (value % 50) / 10,
+            kobata: (value % 50) % 10,
+            matoi: matoi,
+        };
+    }
+
+    /// 所持している旗の合計（小旗何本分か）
+    pub fn value(&self) -> i32 {
+        return self.obata * Flag::Obata.worth()
+            + self.chubata * Flag::Chubata.worth()
+            + self.kobata * Flag::Kobata.worth();
+    }
+
+    pub fn count(&self, flag: Flag) -> i32 {
+        return match flag {
+            Flag::Obata => self.obata,
+            Flag::Chubata => self.chubata,
+            Flag::Kobata => self.kobata,
+        };
+    }
+
+    fn count_mut(&mut self, flag: Flag) -> &mut i32 {
+        return match flag {
+            Flag::Obata => &mut self.obata,
+            Flag::Chubata => &mut self.chubata,
+            Flag::Kobata => &mut self.kobata,
+        };
+    }
+
+    /// 小旗 value 本分の旗を支払う（所持している分が足りない場合は、すべて支払う）
+    /// 大きい旗から支払い、ちょうど支払えない場合は、大きい旗を両替してから支払う
+    /// 戻り値は (支払った旗, 両替した旗とその本数) で、支払った旗の matoi は常に false になる
+    pub fn pay(&mut self, value: i32) -> (Score, Vec<(Flag, Flag, i32)>) {
+        let flags = [Flag::Obata, Flag::Chubata, Flag::Kobata];
+        let mut rest = std::cmp::min(value, self.value());
+        let mut paid = Score::new(0, false);
+        let mut exchanges = vec![];
+
+        loop {
+            for flag in flags.iter() {
+                let n = std::cmp::min(self.count(*flag), rest / flag.worth());
+                *self.count_mut(*flag) -= n;
+                *paid.count_mut(*flag) += n;
+                rest -= n * flag.worth();
+            }
+
+            if rest == 0 {
+                break;
+            }
+
+            // 残りより価値の小さい旗は使い切っているので、残りより大きい旗のうち最も小さいものを両替する
+            let from = *flags
+                .iter()
+                .rev()
+                .find(|flag| self.count(**flag) > 0)
+                .expect("no flag to exchange");
+            let (to, count) = from.exchange_to().expect("kobata can't be exchanged");
+            *self.count_mut(from) -= 1;
+            *self.count_mut(to) += count;
+            exchanges.push((from, to, count));
+        }
+
+        return (paid, exchanges);
+    }
+
+    /// 支払われた旗を受け取る
+    pub fn receive(&mut self, paid: &Score) {
+        self.obata += paid.obata;
+        self.chubata += paid.chubata;
+        self.kobata += paid.kobata;
+    }
+
     pub fn to_string(&self) -> String {
-        let obata = self.score / 50;
-        let chubata = (self.score % 50) / 10;
-        let kobata = (self.score % 50) % 10;
         let m = self.matoi as i32;
         return format!(
             "まとい : {} 本、 大旗 : {} 本、中旗 : {} 本、小旗 : {} 本",
-            m, obata, chubata, kobata
+            m, self.obata, self.chubata, self.kobata
         )
         .to_string();
     }
@@ -50,6 +261,10 @@ pub struct Player {
     pub my_score: Score,
     pub got_score: Score,
     pub name: String,
+    /// Slack のユーザー id。表示名を変えても同じプレイヤーとして記録するためのキーになる
+    /// bot やチーム戦の陣営など、Slack のユーザーでない場合は空
+    #[serde(default)]
+    pub id: String,
 }
 
 impl Player {
@@ -58,17 +273,18 @@ impl Player {
             my_score: my_score,
             got_score: got_score,
             name: name,
+            id: String::new(),
         };
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum PlayerTurn {
     Player1,
     Player2,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum GameState {
     Player1Win,
     Player2Win,
@@ -79,181 +295,34 @@ pub struct Hatagenpei {
     player1: Player,
     player2: Player,
     turn: PlayerTurn,
-    rng: rand_xoshiro::Xoshiro256StarStar,
+    dice: Box<dyn DiceSource>,
+    rule_set: RuleSet,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct HatagenpeiCommand {
     pub dice1: u8,
     pub dice2: u8,
-    pub point: i32,            // + なら もらう、- ならあげる
-    pub again: bool,           // true ならもういちどサイコロを
-    pub explain: &'static str, // 説明文
+    pub point: i32,      // + なら もらう、- ならあげる
+    pub again: bool,     // true ならもういちどサイコロを
+    pub explain: String, // 説明文
 }
 
-const HATAGENPEICOMMANDS: [HatagenpeiCommand; 21] = [
-    HatagenpeiCommand {
-        dice1: 1,
-        dice2: 1,
-        point: 2,
-        again: true,
-        explain: "１  １	ちんちんかもかも　　小旗２本もらう　さいころを続けて振れる",
-    },
-    HatagenpeiCommand {
-        dice1: 2,
-        dice2: 2,
-        point: 2,
-        again: true,
-        explain: "２  ２	にゃあにゃあ	　　小旗２本もらう　さいころを続けて振れる",
-    },
-    HatagenpeiCommand {
-        dice1: 3,
-        dice2: 3,
-        point: 2,
-        again: true,
-        explain: "３  ３	さざなみ	　　　　小旗２本もらう　さいころを続けて振れる",
-    },
-    HatagenpeiCommand {
-        dice1: 4,
-        dice2: 4,
-        point: 2,
-        again: true,
-        explain: "４  ４	しゅうじゅう	　　小旗２本もらう　さいころを続けて振れる",
-    },
-    HatagenpeiCommand {
-        dice1: 5,
-        dice2: 5,
-        point: 2,
-        again: true,
-        explain: "５  ５	ごんご	　　　　　　小旗２本もらう　さいころを続けて振れる",
-    },
-    HatagenpeiCommand {
-        dice1: 6,
-        dice2: 6,
-        point: 2,
-        again: true,
-        explain: "６  ６	じょうろく	　　　　小旗２本もらう　さいころを続けて振れる",
-    },
-    HatagenpeiCommand {
-        dice1: 1,
-        dice2: 2,
-        point: 1,
-        again: false,
-        explain: "１  ２	ちんに	　　　　　　小旗１本もらう",
-    },
-    HatagenpeiCommand {
-        dice1: 1,
-        dice2: 3,
-        point: 1,
-        again: false,
-        explain: "１  ３	ちんさん　　	　　小旗１本もらう",
-    },
-    HatagenpeiCommand {
-        dice1: 1,
-        dice2: 4,
-        point: 1,
-        again: false,
-        explain: "１  ４	ちんし	　　　　　　小旗１本もらう",
-    },
-    HatagenpeiCommand {
-        dice1: 1,
-        dice2: 5,
-        point: 10,
-        again: true,
-        explain: "１  ５	うめがいち　　	　　中旗１本もらう　さいころを続けて振れる",
-    },
-    HatagenpeiCommand {
-        dice1: 1,
-        dice2: 6,
-        point: 10,
-        again: true,
-        explain: "１  ６	ちんろく	　　　　中旗１本もらう　さいころを続けて振れる",
-    },
-    HatagenpeiCommand {
-        dice1: 2,
-        dice2: 3,
-        point: 0,
-        again: false,
-        explain: "２  ３	にさまのかんかんど	旗の移動なし",
-    },
-    HatagenpeiCommand {
-        dice1: 2,
-        dice2: 4,
-        point: -10,
-        again: false,
-        explain: "２  ４	しのに	　　　　　　中旗１本返す",
-    },
-    HatagenpeiCommand {
-        dice1: 2,
-        dice2: 5,
-        point: 0,
-        again: false,
-        explain: "２  ５	ごにごに	　　　　旗の移動なし",
-    },
-    HatagenpeiCommand {
-        dice1: 2,
-        dice2: 6,
-        point: 1,
-        again: false,
-        explain: "２  ６	ろくに	　　　　　　小旗１本もらう",
-    },
-    HatagenpeiCommand {
-        dice1: 3,
-        dice2: 4,
-        point: 0,
-        again: false,
-        explain: "３  ４	しさまのかんかんど　旗の移動なし",
-    },
-    HatagenpeiCommand {
-        dice1: 3,
-        dice2: 5,
-        point: 0,
-        again: false,
-        explain: "３  ５	ごさまのかんかんど　旗の移動なし",
-    },
-    HatagenpeiCommand {
-        dice1: 3,
-        dice2: 6,
-        point: 1,
-        again: false,
-        explain: "３  ６	ろくさん	　　　　小旗１本もらう",
-    },
-    HatagenpeiCommand {
-        dice1: 4,
-        dice2: 5,
-        point: 0,
-        again: false,
-        explain: "４  ５	ごっしりはなかみ　　旗の移動なし",
-    },
-    HatagenpeiCommand {
-        dice1: 4,
-        dice2: 6,
-        point: 1,
-        again: false,
-        explain: "４  ６	しろく	　　　　　　小旗１本もらう",
-    },
-    HatagenpeiCommand {
-        dice1: 5,
-        dice2: 6,
-        point: 1,
-        again: false,
-        explain: "５  ６	ごろく	　　　　　　小旗１本もらう",
-    },
-];
-
 impl Hatagenpei {
     /// Hatagenpei インスタンスを作成する
     pub fn new(
         player1: Player,
         player2: Player,
         first_player: PlayerTurn,
-        seed: u64,
+        dice: Box<dyn DiceSource>,
+        rule_set: RuleSet,
     ) -> Hatagenpei {
         return Hatagenpei {
             player1: player1,
             player2: player2,
             turn: first_player,
-            rng: rand_xoshiro::Xoshiro256StarStar::seed_from_u64(seed),
+            dice: dice,
+            rule_set: rule_set,
         };
     }
 
@@ -261,89 +330,138 @@ impl Hatagenpei {
     /// サイコロの振り直しが発生した場合、振り直しを行う。
     /// 戻り値で、実行ログを返す
     pub fn next(&mut self) -> Option<GameLog> {
-        let mut commands = Vec::new();
-        let next_turn;
+        // まだプレイ中でなければならない
+        if self.get_game_state() != GameState::YetPlaying {
+            return None;
+        }
 
-        {
-            match self.turn {
-                PlayerTurn::Player1 => {
-                    next_turn = PlayerTurn::Player2;
-                }
-                PlayerTurn::Player2 => {
-                    next_turn = PlayerTurn::Player1;
-                }
+        let mut commands = Vec::new();
+        let mut exchanges = Vec::new();
+        let mut events = Vec::new();
+        loop {
+            let again = self.roll(&mut commands, &mut exchanges, &mut events);
+            // もう一度振れないか、ゲームが終わったら終了
+            if !again || self.get_game_state() != GameState::YetPlaying {
+                break;
             }
+        }
 
-            // まだプレイ中でなければならない
-            match self.get_game_state() {
-                GameState::YetPlaying => {}
-                _ => {
-                    return None;
-                }
-            }
+        let game_log = self.make_game_log(commands, exchanges, events);
+        self.turn = Self::opposite(&self.turn);
+        return Some(game_log);
+    }
 
-            loop {
-                match self.get_game_state() {
-                    GameState::YetPlaying => {
-                        // まだプレイ中の場合のみダイスを振る
-                        let cmd = Self::diceroll(&mut self.rng);
-
-                        commands.push(cmd.clone());
-
-                        // TODO: スコア計算は関数に切り出して、テストしやすいようにしておきたい
-
-                        // 旗を返すプレイヤーを決定
-                        let (send_player, got_player) = if (cmd.point > 0) as i32
-                            ^ (self.turn == PlayerTurn::Player1) as i32
-                            > 0
-                        {
-                            (&mut self.player1, &mut self.player2)
-                        } else {
-                            (&mut self.player2, &mut self.player1)
-                        };
-
-                        if cmd.point < 0 {
-                            // send_player が取った got_player の旗を、got_player に返す
-                            let v = std::cmp::min(cmd.point.abs(), send_player.got_score.score);
-                            send_player.got_score.score -= v;
-                            got_player.my_score.score += v;
-                        } else {
-                            // TOOD: このあたりのやり取りをもうすこしきれいにしたい
-                            // まといのやり取り
-                            if cmd.point.abs() > send_player.my_score.score {
-                                send_player.my_score.matoi = false;
-                                got_player.got_score.matoi = true;
-                            }
-
-                            // 旗のやり取り
-                            let v = std::cmp::min(cmd.point.abs(), send_player.my_score.score);
-                            send_player.my_score.score -= v;
-                            got_player.got_score.score += v;
-                        }
-
-                        // もう一度振れないなら終了
-                        if !cmd.again {
-                            break;
-                        }
-                    }
-                    _ => {
-                        break;
-                    }
-                }
-            }
+    /// サイコロを1回だけ振る。
+    /// 振り直しが発生した場合は手番を変えずに戻るので、もう一度 next_roll か next を呼ぶと続きを振れる
+    /// （実物のサイコロの目を1回ずつ入力して遊ぶ場合に使う）
+    pub fn next_roll(&mut self) -> Option<GameLog> {
+        if self.get_game_state() != GameState::YetPlaying {
+            return None;
+        }
+
+        let mut commands = Vec::new();
+        let mut exchanges = Vec::new();
+        let mut events = Vec::new();
+        let again = self.roll(&mut commands, &mut exchanges, &mut events);
+
+        let game_log = self.make_game_log(commands, exchanges, events);
+        if !again || game_log.game_state != GameState::YetPlaying {
+            self.turn = Self::opposite(&self.turn);
         }
+        return Some(game_log);
+    }
 
-        let game_log = GameLog {
+    /// 手番のプレイヤーがサイコロを1回振り、旗をやり取りする
+    /// 振ったコマンド、発生した両替、起きた出来事を追記し、もう一度振れるかどうかを返す
+    fn roll(
+        &mut self,
+        commands: &mut Vec<HatagenpeiCommand>,
+        exchanges: &mut Vec<Exchange>,
+        events: &mut Vec<GameEvent>,
+    ) -> bool {
+        let cmd = Self::diceroll(self.dice.as_mut(), &self.rule_set);
+        commands.push(cmd.clone());
+        let player = self.turn.clone();
+        events.push(GameEvent::DiceRolled {
+            player: player.clone(),
+            dice1: cmd.dice1,
+            dice2: cmd.dice2,
+        });
+
+        let (roller, opponent) = match self.turn {
+            PlayerTurn::Player1 => (&mut self.player1, &mut self.player2),
+            PlayerTurn::Player2 => (&mut self.player2, &mut self.player1),
+        };
+        let got_before = roller.got_score.value();
+        let had_matoi = opponent.my_score.matoi;
+        let (roller_sent, exchanged) = transfer_flags(roller, opponent, cmd.point);
+        let moved = roller.got_score.value() - got_before;
+        let is_captured = had_matoi && !opponent.my_score.matoi;
+        let send_turn = if roller_sent {
+            self.turn.clone()
+        } else {
+            Self::opposite(&self.turn)
+        };
+
+        for (from, to, count) in exchanged {
+            exchanges.push(Exchange {
+                player: send_turn.clone(),
+                from: from,
+                to: to,
+                count: count,
+            });
+        }
+
+        if moved > 0 {
+            events.push(GameEvent::FlagsTaken {
+                player: player.clone(),
+                value: moved,
+            });
+        } else if moved < 0 {
+            events.push(GameEvent::FlagsReturned {
+                player: player.clone(),
+                value: -moved,
+            });
+        }
+        if is_captured {
+            events.push(GameEvent::MatoiCaptured {
+                player: player.clone(),
+            });
+            events.push(GameEvent::GameWon { player: player });
+        } else if cmd.again {
+            events.push(GameEvent::Rerolled { player: player });
+        }
+
+        return cmd.again;
+    }
+
+    fn make_game_log(
+        &self,
+        commands: Vec<HatagenpeiCommand>,
+        exchanges: Vec<Exchange>,
+        events: Vec<GameEvent>,
+    ) -> GameLog {
+        return GameLog {
             player1: self.player1.clone(),
             player2: self.player2.clone(),
             commands: commands,
+            exchanges: exchanges,
+            events: events,
             player_turn: self.turn.clone(),
             game_state: self.get_game_state(),
         };
+    }
 
-        self.turn = next_turn;
+    /// 次にサイコロを振るプレイヤー
+    pub fn get_turn(&self) -> PlayerTurn {
+        return self.turn.clone();
+    }
 
-        return Some(game_log);
+    fn opposite(turn: &PlayerTurn) -> PlayerTurn {
+        return match turn {
+            PlayerTurn::Player1 => PlayerTurn::Player2,
+            PlayerTurn::Player2 => PlayerTurn::Player1,
+        };
     }
 
     fn get_game_state(self: &Self) -> GameState {
@@ -356,57 +474,355 @@ impl Hatagenpei {
         }
     }
 
-    /// サイコロを振り、行うコマンドを返す
-    fn diceroll(rng: &mut rand_xoshiro::Xoshiro256StarStar) -> HatagenpeiCommand {
-        // 乱数でサイコロの目を決める
-        let mut d1 = (rng.gen::<u8>() % 6) + 1;
-        let mut d2 = (rng.gen::<u8>() % 6) + 1;
-        if d1 > d2 {
-            mem::swap(&mut d1, &mut d2);
-        }
+    /// サイコロを2つ振り、行うコマンドを返す
+    pub(super) fn diceroll(dice: &mut dyn DiceSource, rule_set: &RuleSet) -> HatagenpeiCommand {
+        let d1 = dice.roll();
+        let d2 = dice.roll();
 
-        let cmd = HATAGENPEICOMMANDS
-            .iter()
-            .find(|cmd| {
-                return cmd.dice1 == d1 && cmd.dice2 == d2;
-            })
-            .unwrap();
+        // RuleSet は読み込み時に 21 通りすべての目がそろっていることを検証済みなので、unwrap できる
+        let cmd = rule_set.find_command(d1, d2).unwrap();
 
         return cmd.clone();
     }
 }
 
+/// bot との旗源平。player1 がプレイヤー、player2 が bot で、保存した手番から再開する
+impl DiceGame for Hatagenpei {
+    type Progress = Progress;
+    type Config = RuleSet;
+    type Log = GameLog;
+
+    const GAME_ID: &'static str = "hatagenpei";
+
+    fn start(progress: Progress, rule_set: RuleSet, dice: Box<dyn DiceSource>) -> Hatagenpei {
+        return Hatagenpei::new(progress.user, progress.bot, progress.turn, dice, rule_set);
+    }
+
+    fn step(&mut self) -> Option<GameLog> {
+        return self.next();
+    }
+
+    fn state(&self) -> DiceGameState {
+        return match self.get_game_state() {
+            GameState::YetPlaying => DiceGameState::YetPlaying,
+            GameState::Player1Win => DiceGameState::Finished {
+                winner: self.player1.name.clone(),
+            },
+            GameState::Player2Win => DiceGameState::Finished {
+                winner: self.player2.name.clone(),
+            },
+        };
+    }
+
+    fn serialize(&self) -> Progress {
+        return Progress {
+            turn: self.turn.clone(),
+            ..Progress::new(&self.player1, &self.player2, &self.rule_set)
+        };
+    }
+}
+
+/// サイコロを振ったプレイヤー roller と、その相手 opponent の間で point に従って旗をやり取りする
+/// point が + なら opponent の旗を roller がもらい、 - なら roller が取った opponent の旗を opponent に返す
+/// opponent の旗が足りない場合は、roller が opponent のまといを取る
+/// 戻り値は (旗を渡したのが roller かどうか, 旗を渡したプレイヤーが行った両替)
+pub fn transfer_flags(
+    roller: &mut Player,
+    opponent: &mut Player,
+    point: i32,
+) -> (bool, Vec<(Flag, Flag, i32)>) {
+    if point < 0 {
+        let (paid, exchanged) = roller.got_score.pay(-point);
+        opponent.my_score.receive(&paid);
+        return (true, exchanged);
+    }
+
+    // まといのやり取り
+    if point > opponent.my_score.value() {
+        opponent.my_score.matoi = false;
+        roller.got_score.matoi = true;
+    }
+
+    // 旗のやり取り
+    let (paid, exchanged) = opponent.my_score.pay(point);
+    roller.got_score.receive(&paid);
+    return (false, exchanged);
+}
+
+/// 勝率の計算結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct WinProbability {
+    /// player1 が勝つ確率
+    pub player1: f64,
+    /// ゲームが終わるまでの手番数の期待値（現在の手番を含む）
+    pub expected_turns: f64,
+}
+
+impl WinProbability {
+    /// player2 が勝つ確率
+    pub fn player2(&self) -> f64 {
+        return 1.0 - self.player1;
+    }
+}
+
+/// progress の状態から progress.turn のプレイヤーがサイコロを振るときの、user（player1）の勝率と残り手番数の期待値を求める
+///
+/// 旗の移動は本数（小旗何本分か）だけで決まり、両替の有無は勝敗に影響しない。
+/// また player1 の旗は player1 の手元か player2 が取った旗のどちらかにあるので、
+/// 状態は (player1 の自分の旗, player2 の自分の旗, 手番) だけで表せる。
+/// この状態上の吸収マルコフ連鎖について、今の状態から到達できる状態の連立一次方程式 (I - Q) x = b を掃き出し法で厳密に解く。
+/// 1 回振るごとに変わるのは相手の旗だけなので、取りうる値の少ないほうの旗を内側にして状態を並べると、I - Q は帯行列になる。
+/// 決着がつきうる状態だけに絞った I - Q は正則な M 行列なので、ピボットを選ばずに帯の中だけで掃き出せる。
+/// 決着がつかなくなる状態に到達しうる場合（旗が動かない目ばかりのルールなど）は、手番数の期待値を無限大にする
+pub fn win_probability(progress: &Progress, rule_set: &RuleSet) -> WinProbability {
+    let (player1, player2) = (&progress.user, &progress.bot);
+    if player1.got_score.matoi || player2.got_score.matoi {
+        return WinProbability {
+            player1: player1.got_score.matoi as i32 as f64,
+            expected_turns: 0.0,
+        };
+    }
+
+    let total1 = player1.my_score.value() + player2.got_score.value();
+    let total2 = player2.my_score.value() + player1.got_score.value();
+
+    // (出る確率, コマンド)
+    let outcomes = rule_set
+        .commands
+        .iter()
+        .map(|cmd| {
+            let probability = if cmd.dice1 == cmd.dice2 {
+                1.0 / 36.0
+            } else {
+                2.0 / 36.0
+            };
+            (probability, cmd)
+        })
+        .collect::<Vec<_>>();
+    let next_state = |(score1, score2, turn): (i32, i32, usize), cmd: &HatagenpeiCommand| {
+        return roll_transition(score1, score2, turn, total1, total2, cmd.point)
+            .map(|(next1, next2)| (next1, next2, if cmd.again { turn } else { 1 - turn }));
+    };
+
+    // 今の状態から到達できる状態を集める
+    let start = (
+        player1.my_score.value(),
+        player2.my_score.value(),
+        match progress.turn {
+            PlayerTurn::Player1 => 0,
+            PlayerTurn::Player2 => 1,
+        },
+    );
+    let mut states = vec![start];
+    let mut visited = BTreeSet::new();
+    visited.insert(start);
+    let mut i = 0;
+    while i < states.len() {
+        for (_, cmd) in &outcomes {
+            if let Ok(next) = next_state(states[i], cmd) {
+                if visited.insert(next) {
+                    states.push(next);
+                }
+            }
+        }
+        i += 1;
+    }
+    if total2 <= total1 {
+        states.sort();
+    } else {
+        states.sort_by_key(|&(score1, score2, turn)| (score2, score1, turn));
+    }
+    let index = states
+        .iter()
+        .enumerate()
+        .map(|(i, state)| (*state, i))
+        .collect::<BTreeMap<_, _>>();
+
+    // 各状態からの遷移 (確率, 遷移先, 進む手番数)。決着がついた場合、遷移先は player1 が勝ったかどうかを Err で持つ
+    let transitions = states
+        .iter()
+        .map(|state| {
+            return outcomes
+                .iter()
+                .map(|(probability, cmd)| match next_state(*state, cmd) {
+                    Ok(next) => (
+                        *probability,
+                        Ok(index[&next]),
+                        if cmd.again { 0.0 } else { 1.0 },
+                    ),
+                    Err(player1_win) => (*probability, Err(player1_win), 1.0),
+                })
+                .collect::<Vec<_>>();
+        })
+        .collect::<Vec<_>>();
+
+    // 決着がつきうる状態を探す
+    let mut can_finish = vec![false; states.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in 0..states.len() {
+            if !can_finish[i]
+                && transitions[i].iter().any(|(_, next, _)| match next {
+                    Ok(j) => can_finish[*j],
+                    Err(_) => true,
+                })
+            {
+                can_finish[i] = true;
+                changed = true;
+            }
+        }
+    }
+    if !can_finish[index[&start]] {
+        return WinProbability {
+            player1: 0.0,
+            expected_turns: std::f64::INFINITY,
+        };
+    }
+
+    // 決着がつきうる状態だけで、(I - Q) x = b の帯行列を作る
+    let live = (0..states.len())
+        .filter(|i| can_finish[*i])
+        .collect::<Vec<_>>();
+    let mut position = vec![None; states.len()];
+    for (row, i) in live.iter().enumerate() {
+        position[*i] = Some(row);
+    }
+    let (mut lower, mut upper) = (0, 0);
+    for (row, i) in live.iter().enumerate() {
+        for (_, next, _) in &transitions[*i] {
+            if let Ok(j) = next {
+                if let Some(col) = position[*j] {
+                    lower = std::cmp::max(lower, row.saturating_sub(col));
+                    upper = std::cmp::max(upper, col.saturating_sub(row));
+                }
+            }
+        }
+    }
+    let n = live.len();
+    let width = lower + upper + 1;
+    // (row, col) 成分は matrix[row * width + col + lower - row] に置く
+    let at = |row: usize, col: usize| row * width + col + lower - row;
+    let mut matrix = vec![0.0; n * width];
+    // 掃き出した後も含めた、各行の 0 でない成分の最後の列
+    let mut row_end = vec![0; n];
+    let mut win = vec![0.0; n];
+    let mut turns = vec![0.0; n];
+    for (row, i) in live.iter().enumerate() {
+        matrix[at(row, row)] += 1.0;
+        row_end[row] = row;
+        for (probability, next, turn_cost) in &transitions[*i] {
+            turns[row] += probability * turn_cost;
+            match next {
+                Ok(j) => {
+                    // 決着がつかなくなる状態に進んだ場合は、player1 は勝てない
+                    if let Some(col) = position[*j] {
+                        matrix[at(row, col)] -= probability;
+                        row_end[row] = std::cmp::max(row_end[row], col);
+                    }
+                }
+                Err(player1_win) => win[row] += probability * (*player1_win as i32 as f64),
+            }
+        }
+    }
+
+    // 前進消去
+    for k in 0..n {
+        let pivot = matrix[at(k, k)];
+        for row in (k + 1)..std::cmp::min(n, k + lower + 1) {
+            let factor = matrix[at(row, k)] / pivot;
+            if factor == 0.0 {
+                continue;
+            }
+            // 行 k の (k..=row_end[k]) 列は、行 row の同じ列より前に連続して並んでいる
+            let (pivot_rows, rows) = matrix.split_at_mut(at(row, k));
+            let pivot_row = &pivot_rows[at(k, k)..=at(k, row_end[k])];
+            for (value, pivot_value) in rows.iter_mut().zip(pivot_row) {
+                *value -= factor * pivot_value;
+            }
+            row_end[row] = std::cmp::max(row_end[row], row_end[k]);
+            win[row] -= factor * win[k];
+            turns[row] -= factor * turns[k];
+        }
+    }
+    // 後退代入
+    for k in (0..n).rev() {
+        for col in (k + 1)..=row_end[k] {
+            win[k] -= matrix[at(k, col)] * win[col];
+            turns[k] -= matrix[at(k, col)] * turns[col];
+        }
+        win[k] /= matrix[at(k, k)];
+        turns[k] /= matrix[at(k, k)];
+    }
+
+    let start_row = position[index[&start]].unwrap();
+    return WinProbability {
+        player1: win[start_row],
+        expected_turns: if live.len() == states.len() {
+            turns[start_row]
+        } else {
+            std::f64::INFINITY
+        },
+    };
+}
+
+/// win_probability 用の、サイコロを1回振ったときの状態遷移
+/// (player1 の自分の旗, player2 の自分の旗) を返し、まといが取られた場合は player1 が勝ったかどうかを Err で返す
+fn roll_transition(
+    score1: i32,
+    score2: i32,
+    turn: usize,
+    total1: i32,
+    total2: i32,
+    point: i32,
+) -> Result<(i32, i32), bool> {
+    // roller : サイコロを振ったプレイヤー、 opponent : その相手
+    let (roller, mut opponent, opponent_total) = if turn == 0 {
+        (score1, score2, total2)
+    } else {
+        (score2, score1, total1)
+    };
+
+    if point > 0 {
+        if point > opponent {
+            return Err(turn == 0);
+        }
+        opponent -= point;
+    } else if point < 0 {
+        // roller が取った opponent の旗を返す
+        let roller_got = opponent_total - opponent;
+        opponent += std::cmp::min(-point, roller_got);
+    }
+
+    return Ok(if turn == 0 {
+        (roller, opponent)
+    } else {
+        (opponent, roller)
+    });
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
     fn hatagenpei_tests() {
+        use crate::hatagenpei::dice::SeededDice;
         use crate::hatagenpei::game::*;
+        use crate::hatagenpei::rule_set::RuleSet;
 
         let mut game = Hatagenpei::new(
             Player::new(
                 "alice".to_string(),
-                Score {
-                    score: 10,
-                    matoi: true,
-                },
-                Score {
-                    score: 0,
-                    matoi: false,
-                },
+                Score::new(10, true),
+                Score::new(0, false),
             ),
             Player::new(
                 "bob".to_string(),
-                Score {
-                    score: 10,
-                    matoi: true,
-                },
-                Score {
-                    score: 0,
-                    matoi: false,
-                },
+                Score::new(10, true),
+                Score::new(0, false),
             ),
             PlayerTurn::Player1,
-            123,
+            Box::new(SeededDice::new(123)),
+            RuleSet::default(),
         );
 
         {
@@ -416,39 +832,29 @@ mod tests {
             assert_eq!(
                 game_log.player1,
                 Player {
-                    my_score: Score {
-                        score: 10,
-                        matoi: true
-                    },
-                    got_score: Score {
-                        score: 0,
-                        matoi: false
-                    },
-                    name: "alice".to_string()
+                    my_score: Score::new(10, true),
+                    got_score: Score::new(0, false),
+                    name: "alice".to_string(),
+                    id: String::new()
                 }
             );
             assert_eq!(
                 game_log.player2,
                 Player {
-                    my_score: Score {
-                        score: 10,
-                        matoi: true
-                    },
-                    got_score: Score {
-                        score: 0,
-                        matoi: false
-                    },
-                    name: "bob".to_string()
+                    my_score: Score::new(10, true),
+                    got_score: Score::new(0, false),
+                    name: "bob".to_string(),
+                    id: String::new()
                 }
             );
             assert_eq!(
                 game_log.commands,
                 vec![HatagenpeiCommand {
-                    dice1: 4,
+                    dice1: 3,
                     dice2: 5,
                     point: 0,
                     again: false,
-                    explain: "４  ５\tごっしりはなかみ\u{3000}\u{3000}旗の移動なし"
+                    explain: "３  ５\tごさまのかんかんど\u{3000}旗の移動なし".to_string()
                 }]
             );
         }
@@ -456,38 +862,380 @@ mod tests {
         {
             let game_log = game.next().unwrap();
             assert_eq!(game_log.player_turn, PlayerTurn::Player2);
-            assert_eq!(game_log.game_state, GameState::Player2Win);
+            assert_eq!(game_log.game_state, GameState::YetPlaying);
+            // 中旗しか持っていないので、両替してから小旗を渡す
             assert_eq!(
                 game_log.player1,
                 Player {
-                    my_score: Score {
-                        score: 0,
-                        matoi: false
-                    },
-                    got_score: Score {
-                        score: 0,
-                        matoi: false
-                    },
-                    name: "alice".to_string()
+                    my_score: Score::new(8, true),
+                    got_score: Score::new(0, false),
+                    name: "alice".to_string(),
+                    id: String::new()
                 }
             );
             assert_eq!(
                 game_log.player2,
                 Player {
-                    my_score: Score {
-                        score: 10,
-                        matoi: true
-                    },
-                    got_score: Score {
-                        score: 10,
-                        matoi: true
-                    },
-                    name: "bob".to_string()
+                    my_score: Score::new(10, true),
+                    got_score: Score::new(2, false),
+                    name: "bob".to_string(),
+                    id: String::new()
                 }
             );
 
-            assert_eq!(game_log.commands, vec![HatagenpeiCommand { dice1: 1, dice2: 6, point: 10, again: true, explain: "１  ６\tちんろく\t\u{3000}\u{3000}\u{3000}\u{3000}中旗１本もらう\u{3000}さいころを続けて振れる" },
-                                               HatagenpeiCommand { dice1: 1, dice2: 5, point: 10, again: true, explain: "１  ５\tうめがいち\u{3000}\u{3000}\t\u{3000}\u{3000}中旗１本もらう\u{3000}さいころを続けて振れる" }]);
+            assert_eq!(game_log.commands, vec![HatagenpeiCommand { dice1: 5, dice2: 5, point: 2, again: true, explain: "５  ５\tごんご\t\u{3000}\u{3000}\u{3000}\u{3000}\u{3000}\u{3000}小旗２本もらう\u{3000}さいころを続けて振れる".to_string() },
+                                               HatagenpeiCommand { dice1: 3, dice2: 4, point: 0, again: false, explain: "３  ４\tしさまのかんかんど\u{3000}旗の移動なし".to_string() }]);
+        }
+
+        // 決着がつくまで進めると、最後は bob が alice のまといを取って勝つ
+        let mut last_log = None;
+        while let Some(game_log) = game.next() {
+            last_log = Some(game_log);
         }
+        let game_log = last_log.unwrap();
+        assert_eq!(game_log.player_turn, PlayerTurn::Player2);
+        assert_eq!(game_log.game_state, GameState::Player2Win);
+        assert_eq!(
+            game_log.player1,
+            Player {
+                my_score: Score::new(0, false),
+                got_score: Score::new(0, false),
+                name: "alice".to_string(),
+                id: String::new()
+            }
+        );
+        assert_eq!(
+            game_log.player2,
+            Player {
+                my_score: Score {
+                    obata: 0,
+                    chubata: 0,
+                    kobata: 10,
+                    matoi: true
+                },
+                got_score: Score {
+                    obata: 0,
+                    chubata: 0,
+                    kobata: 10,
+                    matoi: true
+                },
+                name: "bob".to_string(),
+                id: String::new()
+            }
+        );
+        assert_eq!(
+            game_log.commands,
+            vec![HatagenpeiCommand {
+                dice1: 3,
+                dice2: 6,
+                point: 1,
+                again: false,
+                explain: "３  ６\tろくさん\t\u{3000}\u{3000}\u{3000}\u{3000}小旗１本もらう"
+                    .to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn exchange_tests() {
+        use crate::hatagenpei::game::*;
+
+        // 小旗が足りない場合は、中旗を両替して支払う
+        let mut score = Score::new(10, true);
+        let (paid, exchanges) = score.pay(2);
+        assert_eq!(paid, Score::new(2, false));
+        assert_eq!(exchanges, vec![(Flag::Chubata, Flag::Kobata, 10)]);
+        assert_eq!(
+            score,
+            Score {
+                obata: 0,
+                chubata: 0,
+                kobata: 8,
+                matoi: true
+            }
+        );
+
+        // 大旗しかない場合は、中旗 -> 小旗の順に両替する
+        let mut score = Score::new(50, true);
+        let (paid, exchanges) = score.pay(1);
+        assert_eq!(paid, Score::new(1, false));
+        assert_eq!(
+            exchanges,
+            vec![
+                (Flag::Obata, Flag::Chubata, 5),
+                (Flag::Chubata, Flag::Kobata, 10)
+            ]
+        );
+        assert_eq!(score.value(), 49);
+
+        // 足りない場合は、持っている分だけ支払う
+        let mut score = Score::new(9, true);
+        let (paid, exchanges) = score.pay(10);
+        assert_eq!(paid, Score::new(9, false));
+        assert_eq!(exchanges, vec![]);
+        assert_eq!(score.value(), 0);
+
+        // 旗の合計だけを持っていた頃のデータも読める
+        let score: Score = serde_json::from_str(r#"{"score":29,"matoi":true}"#).unwrap();
+        assert_eq!(score, Score::new(29, true));
+    }
+
+    #[test]
+    fn scripted_dice_tests() {
+        use crate::hatagenpei::dice::ScriptedDice;
+        use crate::hatagenpei::game::*;
+        use crate::hatagenpei::rule_set::RuleSet;
+
+        // 1-5 (10本もらって振り直し) -> 1-5 -> 3-4 (移動なし) と続けて振る
+        // 相手の旗は 0 本でもまといは残っているので、次に旗をもらう目でまといを取る
+        let mut game = Hatagenpei::new(
+            Player::new(
+                "alice".to_string(),
+                Score::new(29, true),
+                Score::new(0, false),
+            ),
+            Player::new(
+                "bob".to_string(),
+                Score::new(20, true),
+                Score::new(0, false),
+            ),
+            PlayerTurn::Player1,
+            Box::new(ScriptedDice::new(&[1, 5, 5, 1, 3, 4, 1, 2, 1, 2])),
+            RuleSet::default(),
+        );
+
+        let game_log = game.next_roll().unwrap();
+        assert_eq!(game_log.commands.len(), 1);
+        assert_eq!(game_log.player2.my_score.value(), 10);
+        assert_eq!(game_log.game_state, GameState::YetPlaying);
+
+        // 振り直しなので、まだ alice の番
+        let game_log = game.next().unwrap();
+        assert_eq!(game_log.player_turn, PlayerTurn::Player1);
+        assert_eq!(game_log.commands.len(), 2);
+        assert_eq!(
+            game_log.events[1..3],
+            [
+                GameEvent::FlagsTaken {
+                    player: PlayerTurn::Player1,
+                    value: 10
+                },
+                GameEvent::Rerolled {
+                    player: PlayerTurn::Player1
+                },
+            ]
+        );
+        assert_eq!(game_log.player2.my_score.value(), 0);
+        assert!(game_log.player2.my_score.matoi);
+        assert_eq!(game_log.player1.got_score.value(), 20);
+
+        // bob の 1-2 で、alice から 1 本もらう
+        let game_log = game.next().unwrap();
+        assert_eq!(game_log.player_turn, PlayerTurn::Player2);
+        assert_eq!(game_log.player2.got_score.value(), 1);
+        assert_eq!(game_log.game_state, GameState::YetPlaying);
+
+        // alice の 1-2 で、旗が 0 本の bob のまといを取る
+        let game_log = game.next().unwrap();
+        assert!(!game_log.player2.my_score.matoi);
+        assert!(game_log.player1.got_score.matoi);
+        assert_eq!(game_log.game_state, GameState::Player1Win);
+        assert_eq!(
+            game_log.events,
+            vec![
+                GameEvent::DiceRolled {
+                    player: PlayerTurn::Player1,
+                    dice1: 1,
+                    dice2: 2
+                },
+                GameEvent::MatoiCaptured {
+                    player: PlayerTurn::Player1
+                },
+                GameEvent::GameWon {
+                    player: PlayerTurn::Player1
+                },
+            ]
+        );
+        assert!(game.next().is_none());
+    }
+}
+
+/// サイコロの目が偏っていないかを、カイ二乗適合度検定で確かめるテスト
+#[cfg(test)]
+mod fairness_tests {
+    use super::*;
+    use crate::hatagenpei::dice::SeededDice;
+
+    /// 1つのシードで振る回数
+    const ROLLS_PER_SEED: usize = 1_000_000;
+    const SEEDS: [u64; 3] = [1, 12345, 987654321];
+
+    // 有意水準 0.1% のカイ二乗分布の臨界値
+    const CHI2_CRITICAL_DF5: f64 = 20.515;
+    const CHI2_CRITICAL_DF20: f64 = 45.315;
+
+    fn chi_square(observed: &[usize], expected_probabilities: &[f64], total: usize) -> f64 {
+        return observed
+            .iter()
+            .zip(expected_probabilities.iter())
+            .map(|(o, p)| {
+                let expected = *p * total as f64;
+                let diff = *o as f64 - expected;
+                diff * diff / expected
+            })
+            .sum();
+    }
+
+    #[test]
+    fn single_face_fairness_test() {
+        for seed in SEEDS.iter() {
+            let mut dice = SeededDice::new(*seed);
+            let mut observed = [0; 6];
+            for _ in 0..ROLLS_PER_SEED {
+                let face = dice.roll();
+                assert!(1 <= face && face <= 6);
+                observed[(face - 1) as usize] += 1;
+            }
+            let chi2 = chi_square(&observed, &[1.0 / 6.0; 6], ROLLS_PER_SEED);
+            assert!(
+                chi2 < CHI2_CRITICAL_DF5,
+                "seed = {}, chi2 = {}, observed = {:?}",
+                seed,
+                chi2,
+                observed
+            );
+        }
+    }
+
+    #[test]
+    fn command_fairness_test() {
+        let rule_set = RuleSet::default();
+        // ゾロ目は 1/36、それ以外は 2/36 の確率で出るはず
+        let expected_probabilities = rule_set
+            .commands
+            .iter()
+            .map(|cmd| {
+                if cmd.dice1 == cmd.dice2 {
+                    1.0 / 36.0
+                } else {
+                    2.0 / 36.0
+                }
+            })
+            .collect::<Vec<f64>>();
+
+        for seed in SEEDS.iter() {
+            let mut dice = SeededDice::new(*seed);
+            let mut observed = vec![0; rule_set.commands.len()];
+            for _ in 0..ROLLS_PER_SEED {
+                let cmd = Hatagenpei::diceroll(&mut dice, &rule_set);
+                let index = rule_set
+                    .commands
+                    .iter()
+                    .position(|c| c.dice1 == cmd.dice1 && c.dice2 == cmd.dice2)
+                    .unwrap();
+                observed[index] += 1;
+            }
+            let chi2 = chi_square(&observed, &expected_probabilities, ROLLS_PER_SEED);
+            assert!(
+                chi2 < CHI2_CRITICAL_DF20,
+                "seed = {}, chi2 = {}, observed = {:?}",
+                seed,
+                chi2,
+                observed
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod win_probability_tests {
+    use super::*;
+    use crate::hatagenpei::simulation::*;
+
+    fn player(name: &str, score: i32) -> Player {
+        return Player::new(
+            name.to_string(),
+            Score::new(score, true),
+            Score::new(0, false),
+        );
+    }
+
+    fn progress(player1_score: i32, player2_score: i32, turn: PlayerTurn) -> Progress {
+        return Progress {
+            turn: turn,
+            ..Progress::new(
+                &player("alice", player1_score),
+                &player("bob", player2_score),
+                &RuleSet::default(),
+            )
+        };
+    }
+
+    #[test]
+    fn win_probability_test() {
+        let rule_set = RuleSet::default();
+
+        // 同じ本数なら、先手と後手の勝率は入れ替えても合計 1 になる
+        let first = win_probability(&progress(29, 29, PlayerTurn::Player1), &rule_set);
+        let second = win_probability(&progress(29, 29, PlayerTurn::Player2), &rule_set);
+        assert!((first.player1 + second.player1 - 1.0).abs() < 1e-9);
+        assert!((first.expected_turns - second.expected_turns).abs() < 1e-9);
+
+        // モンテカルロシミュレーションの結果とおおむね一致する
+        let exact = win_probability(&progress(10, 20, PlayerTurn::Player1), &rule_set);
+        let simulated = simulate(&SimulationConfig {
+            games: 20000,
+            threads: 2,
+            player1_init_score: 10,
+            player2_init_score: 20,
+            first_player: FirstPlayer::Player1,
+            rule_set: rule_set.clone(),
+            seed: 7,
+        });
+        assert!((exact.player1 - simulated.player1_win_rate()).abs() < 0.02);
+        let simulated_turns = simulated
+            .game_lengths
+            .iter()
+            .map(|(turns, count)| (turns * count) as f64)
+            .sum::<f64>()
+            / simulated.games as f64;
+        assert!((exact.expected_turns - simulated_turns).abs() / exact.expected_turns < 0.05);
+    }
+
+    #[test]
+    fn exact_win_probability_test() {
+        // ゾロ目は旗の移動なし、それ以外は 1 本もらうルールで、2人とも旗を持っていない場合
+        // ゾロ目以外（5/6）を出せばまといを取れるので、先手の勝率は 1 / (1 + 1/6) = 6/7、手番数の期待値は 6/5
+        let rule_set = RuleSet {
+            commands: RuleSet::default()
+                .commands
+                .iter()
+                .map(|cmd| HatagenpeiCommand {
+                    point: if cmd.dice1 == cmd.dice2 { 0 } else { 1 },
+                    again: false,
+                    ..cmd.clone()
+                })
+                .collect(),
+            ..RuleSet::default()
+        };
+        let result = win_probability(&progress(0, 0, PlayerTurn::Player1), &rule_set);
+        assert!((result.player1 - 6.0 / 7.0).abs() < 1e-12);
+        assert!((result.expected_turns - 6.0 / 5.0).abs() < 1e-12);
+
+        // 旗が動かないルールでは、決着がつかない
+        let rule_set = RuleSet {
+            commands: rule_set
+                .commands
+                .iter()
+                .map(|cmd| HatagenpeiCommand {
+                    point: 0,
+                    ..cmd.clone()
+                })
+                .collect(),
+            ..rule_set.clone()
+        };
+        let result = win_probability(&progress(0, 0, PlayerTurn::Player1), &rule_set);
+        assert_eq!(result.player1, 0.0);
+        assert!(result.expected_turns.is_infinite());
     }
 }
@@ -1,26 +1,102 @@
 //!
-//! Hatagenpeicontroller実行のサンプルコード
+//! 旗源平をたくさん自動で遊ばせ、勝率などの統計を CSV / JSON で出力するシミュレーションツール
 //!
 
+use getopts::Options;
+use hatagenpeikun::hatagenpei::rule_set::RuleSet;
+use hatagenpeikun::hatagenpei::simulation::*;
+use std::path::Path;
+
+fn print_usage(program: &str, opts: Options) {
+    let brief = format!("Usage : {} [options]", program);
+    println!("{}", opts.usage(&brief));
+}
+
 fn main() {
-    // controller を動作させ、ちゃんと状態が保存されているか見る
-    use hatagenpeikun::hatagenpei::controller::*;
+    let args: Vec<String> = std::env::args().collect();
 
-    let mut ins = HatagenpeiController::new(
-        factor_operater(&DataStore::OnMemory),
-        &"hatagenpeikun".to_string(),
+    let mut opts = Options::new();
+    opts.optopt("n", "games", "number of games (default: 10000)", "N");
+    opts.optopt("j", "threads", "number of threads (default: 4)", "N");
+    opts.optopt(
+        "",
+        "init1",
+        "initial score of player1 (default: 29)",
+        "SCORE",
+    );
+    opts.optopt(
+        "",
+        "init2",
+        "initial score of player2 (default: 29)",
+        "SCORE",
     );
-    for _ in 0..3 {
-        loop {
-            let res = ins.step(&"rust".to_string());
-            for l in &res.logs {
-                println!("{:?}", l);
-            }
-            if res.is_over {
-                break;
-            }
-        }
+    opts.optopt(
+        "f",
+        "first",
+        "first player (default: 1)",
+        "1 | 2 | alternate",
+    );
+    opts.optopt(
+        "r",
+        "rule",
+        "rule set file (default: standard rule)",
+        "rules/xxx.toml | rules/xxx.json",
+    );
+    opts.optopt("s", "seed", "base seed (default: random)", "SEED");
+    opts.optopt("o", "format", "output format (default: csv)", "csv | json");
+    opts.optflag("h", "help", "print this help menu");
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => panic!("{}", f.to_string()),
+    };
+
+    // help
+    if matches.opt_present("h") {
+        print_usage(&args[0], opts);
+        return;
     }
 
-    println!("{:?}", ins.get_win_loses());
+    let parse_or = |name: &str, default: u64| -> u64 {
+        return match matches.opt_str(name) {
+            Some(v) => v
+                .parse()
+                .unwrap_or_else(|_| panic!("invalid {} : {}", name, v)),
+            None => default,
+        };
+    };
+
+    let first_player = match matches.opt_str("f").as_ref().map(|s| s.as_str()) {
+        None | Some("1") => FirstPlayer::Player1,
+        Some("2") => FirstPlayer::Player2,
+        Some("alternate") => FirstPlayer::Alternate,
+        Some(other) => panic!("invalid first player : {}", other),
+    };
+
+    let rule_set = match matches.opt_str("r") {
+        Some(path) => RuleSet::load(Path::new(&path))
+            .unwrap_or_else(|err| panic!("failed to load rule set {} : {}", path, err)),
+        None => RuleSet::default(),
+    };
+
+    let config = SimulationConfig {
+        games: parse_or("n", 10000) as usize,
+        threads: parse_or("j", 4) as usize,
+        player1_init_score: parse_or("init1", 29) as i32,
+        player2_init_score: parse_or("init2", 29) as i32,
+        first_player: first_player,
+        rule_set: rule_set,
+        seed: parse_or("s", rand::random::<u64>()),
+    };
+
+    let result = simulate(&config);
+
+    match matches.opt_str("o").as_ref().map(|s| s.as_str()) {
+        None | Some("csv") => println!("{}", result.to_csv()),
+        Some("json") => println!(
+            "{}",
+            serde_json::to_string_pretty(&result).expect("failed to serde_json::to_string")
+        ),
+        Some(other) => panic!("invalid format : {}", other),
+    }
 }
@@ -1,3 +1,15 @@
+pub mod chinchirorin;
 pub mod controller;
-mod game;
+pub mod dice;
+pub mod dice_game;
+pub mod game;
+pub mod multi;
+pub mod persona;
+pub mod rating;
+pub mod render;
+pub mod replay;
+pub mod rule_set;
 mod score_operator;
+pub mod simulation;
+pub mod stats;
+pub mod team;
@@ -0,0 +1,259 @@
+//!
+//! 3人以上で遊ぶ旗源平
+//! サイコロを振るたびに相手を1人選び（選ばなければ順番に回す）、その相手と旗をやり取りする
+//! まといを取られたプレイヤーは敗退し、最後まで残ったプレイヤーの勝ち
+//!
+
+use super::dice::DiceSource;
+use super::game::*;
+use super::rule_set::RuleSet;
+use serde::{Deserialize, Serialize};
+
+/// 卓の席
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Seat {
+    pub player: Player,
+    /// 最後に旗をやり取りした相手の席番号。相手を選ばなかった場合は、この次の席から順番に回す
+    pub last_target: Option<usize>,
+}
+
+impl Seat {
+    pub fn new(player: Player) -> Seat {
+        return Seat {
+            player: player,
+            last_target: None,
+        };
+    }
+
+    /// まだ敗退していないかどうか
+    pub fn is_alive(&self) -> bool {
+        return self.player.my_score.matoi;
+    }
+}
+
+/// サイコロ1回分の記録
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MultiRoll {
+    /// 旗をやり取りした相手の席番号
+    pub target: usize,
+    pub command: HatagenpeiCommand,
+}
+
+/// 両替の記録
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MultiExchange {
+    /// 両替を行ったプレイヤーの席番号
+    pub seat: usize,
+    pub from: Flag,
+    pub to: Flag,
+    pub count: i32,
+}
+
+/// MultiHatagenpei::next の実行ログ
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MultiGameLog {
+    /// 今回サイコロを振ったプレイヤーの席番号
+    pub roller: usize,
+    /// 振ったサイコロと、その相手（振った順）
+    pub rolls: Vec<MultiRoll>,
+    /// rolls の実行中に発生した両替
+    pub exchanges: Vec<MultiExchange>,
+    /// このターンで敗退したプレイヤーの席番号
+    pub eliminated: Vec<usize>,
+    /// rolls をすべて実行した後の、全員の情報
+    pub seats: Vec<Seat>,
+    /// 勝者の席番号。まだプレイ中なら None
+    pub winner: Option<usize>,
+}
+
+pub struct MultiHatagenpei {
+    seats: Vec<Seat>,
+    turn: usize,
+    dice: Box<dyn DiceSource>,
+    rule_set: RuleSet,
+}
+
+impl MultiHatagenpei {
+    /// MultiHatagenpei インスタンスを作成する。turn は最初にサイコロを振るプレイヤーの席番号
+    pub fn new(
+        seats: Vec<Seat>,
+        turn: usize,
+        dice: Box<dyn DiceSource>,
+        rule_set: RuleSet,
+    ) -> MultiHatagenpei {
+        return MultiHatagenpei {
+            seats: seats,
+            turn: turn,
+            dice: dice,
+            rule_set: rule_set,
+        };
+    }
+
+    /// 次にサイコロを振るプレイヤーの席番号
+    pub fn turn(&self) -> usize {
+        return self.turn;
+    }
+
+    /// 1ターン進める。
+    /// target で旗をやり取りする相手の席番号を指定する。None や、自分・敗退済みのプレイヤーを指定した場合は順番に回す
+    /// サイコロの振り直しが発生した場合、同じ相手に対して振り直しを行う（相手が敗退した場合は次の相手に回す）
+    /// ゲームが既に終了している場合は None を返す
+    pub fn next(&mut self, target: Option<usize>) -> Option<MultiGameLog> {
+        if self.winner().is_some() {
+            return None;
+        }
+
+        let roller = self.turn;
+        let mut rolls = vec![];
+        let mut exchanges = vec![];
+        let mut eliminated = vec![];
+        let mut target = match target {
+            Some(target) if self.is_valid_target(roller, target) => target,
+            _ => self.rotate_target(roller),
+        };
+
+        loop {
+            let cmd = Hatagenpei::diceroll(self.dice.as_mut(), &self.rule_set);
+            rolls.push(MultiRoll {
+                target: target,
+                command: cmd.clone(),
+            });
+            self.seats[roller].last_target = Some(target);
+
+            let (roller_seat, target_seat) = Self::two_seats(&mut self.seats, roller, target);
+            let (roller_sent, exchanged) =
+                transfer_flags(&mut roller_seat.player, &mut target_seat.player, cmd.point);
+            let send_seat = if roller_sent { roller } else { target };
+            for (from, to, count) in exchanged {
+                exchanges.push(MultiExchange {
+                    seat: send_seat,
+                    from: from,
+                    to: to,
+                    count: count,
+                });
+            }
+
+            // まといを取られたら敗退し、それまでに取った旗はまといを取ったプレイヤーのものになる
+            if !target_seat.is_alive() {
+                let got_score =
+                    std::mem::replace(&mut target_seat.player.got_score, Score::new(0, false));
+                roller_seat.player.got_score.receive(&got_score);
+                eliminated.push(target);
+            }
+
+            if !cmd.again || self.winner().is_some() {
+                break;
+            }
+            if !self.seats[target].is_alive() {
+                target = self.rotate_target(roller);
+            }
+        }
+
+        let winner = self.winner();
+        if winner.is_none() {
+            self.turn = self.next_alive(roller);
+        }
+
+        return Some(MultiGameLog {
+            roller: roller,
+            rolls: rolls,
+            exchanges: exchanges,
+            eliminated: eliminated,
+            seats: self.seats.clone(),
+            winner: winner,
+        });
+    }
+
+    /// 残っているプレイヤーが1人になっていれば、その席番号を返す
+    pub fn winner(&self) -> Option<usize> {
+        let mut alives = (0..self.seats.len()).filter(|i| self.seats[*i].is_alive());
+        return match (alives.next(), alives.next()) {
+            (Some(winner), None) => Some(winner),
+            _ => None,
+        };
+    }
+
+    fn is_valid_target(&self, roller: usize, target: usize) -> bool {
+        return target != roller && target < self.seats.len() && self.seats[target].is_alive();
+    }
+
+    /// roller が前回旗をやり取りした相手の、次に残っているプレイヤーを返す
+    fn rotate_target(&self, roller: usize) -> usize {
+        let mut target = self.seats[roller].last_target.unwrap_or(roller);
+        loop {
+            target = self.next_alive(target);
+            if target != roller {
+                return target;
+            }
+        }
+    }
+
+    /// seat の次の席に座っている、残っているプレイヤーを返す
+    fn next_alive(&self, seat: usize) -> usize {
+        let mut next = seat;
+        loop {
+            next = (next + 1) % self.seats.len();
+            if self.seats[next].is_alive() || next == seat {
+                return next;
+            }
+        }
+    }
+
+    /// seats から、異なる2つの席を同時に可変参照で取り出す
+    fn two_seats(seats: &mut Vec<Seat>, i: usize, j: usize) -> (&mut Seat, &mut Seat) {
+        if i < j {
+            let (left, right) = seats.split_at_mut(j);
+            return (&mut left[i], &mut right[0]);
+        } else {
+            let (left, right) = seats.split_at_mut(i);
+            return (&mut right[0], &mut left[j]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hatagenpei::dice::SeededDice;
+
+    #[test]
+    fn multi_hatagenpei_tests() {
+        let seats = ["alice", "bob", "carol", "dave"]
+            .iter()
+            .map(|name| {
+                Seat::new(Player::new(
+                    name.to_string(),
+                    Score::new(29, true),
+                    Score::new(0, false),
+                ))
+            })
+            .collect::<Vec<_>>();
+        let total = |seats: &Vec<Seat>| {
+            seats
+                .iter()
+                .map(|s| s.player.my_score.value() + s.player.got_score.value())
+                .sum::<i32>()
+        };
+
+        let mut game =
+            MultiHatagenpei::new(seats, 0, Box::new(SeededDice::new(42)), RuleSet::default());
+        let mut eliminated = vec![];
+        let mut last_log = None;
+        while let Some(game_log) = game.next(None) {
+            // 自分を相手に選ぶことはなく、旗の総数は変わらない
+            assert!(game_log.rolls.iter().all(|r| r.target != game_log.roller));
+            assert_eq!(total(&game_log.seats), 29 * 4);
+            eliminated.extend(game_log.eliminated.iter().cloned());
+            last_log = Some(game_log);
+        }
+
+        // 最後に1人だけ残り、他の3人は1回ずつ敗退している
+        let last_log = last_log.unwrap();
+        let winner = last_log.winner.unwrap();
+        eliminated.sort();
+        eliminated.dedup();
+        assert_eq!(eliminated.len(), 3);
+        assert!(!eliminated.contains(&winner));
+        assert!(game.next(None).is_none());
+    }
+}
@@ -0,0 +1,74 @@
+//!
+//! 模範試合で bot 同士を対戦させるための、bot の設定（ペルソナ）をファイルから読み込むモジュール
+//!
+
+use super::rule_set::DEFAULT_RULE_SET_ID;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+#[derive(Debug, Fail)]
+pub enum PersonaError {
+    #[fail(display = "unknown persona format: {}", _0)]
+    UnknownFormat(String),
+    #[fail(display = "persona name is empty")]
+    EmptyName,
+    #[fail(display = "invalid handicap: {}", _0)]
+    InvalidHandicap(i32),
+}
+
+/// 模範試合に出る bot
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Persona {
+    /// 表示用の名前。模範試合の成績もこの名前で記録する
+    pub name: String,
+    /// ハンデとして追加で持つ中旗の本数
+    #[serde(default)]
+    pub handicap: i32,
+    /// 先手で出るときに使うルールの id
+    #[serde(default = "default_rule_set_id")]
+    pub rule_set_id: String,
+}
+
+fn default_rule_set_id() -> String {
+    return DEFAULT_RULE_SET_ID.to_string();
+}
+
+impl Persona {
+    /// ファイルからペルソナを読み込む。拡張子が toml なら TOML、json なら JSON として扱う
+    pub fn load(path: &Path) -> Result<Persona, failure::Error> {
+        let content = std::fs::read_to_string(path)?;
+        let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+        let persona: Persona = match extension {
+            "toml" => toml::from_str(&content)?,
+            "json" => serde_json::from_str(&content)?,
+            _ => return Err(PersonaError::UnknownFormat(path.display().to_string()).into()),
+        };
+        return persona.validated();
+    }
+
+    fn validated(self) -> Result<Persona, failure::Error> {
+        if self.name.is_empty() {
+            return Err(PersonaError::EmptyName.into());
+        }
+        if self.handicap < 0 {
+            return Err(PersonaError::InvalidHandicap(self.handicap).into());
+        }
+        return Ok(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_persona_test() {
+        let persona = Persona::load(Path::new("personas/minarai.toml")).unwrap();
+        assert_eq!(persona.handicap, 2);
+        // 省略したルールは標準ルールになる
+        assert_eq!(persona.rule_set_id, DEFAULT_RULE_SET_ID);
+
+        let empty: Persona = serde_json::from_str(r#"{"name": ""}"#).unwrap();
+        assert!(empty.validated().is_err());
+    }
+}
@@ -0,0 +1,176 @@
+//!
+//! プレイヤーの強さを Glicko-2 のレーティングで表すモジュール
+//! 計算方法は Mark E. Glickman, "Example of the Glicko-2 system" に従う
+//! 旗源平は 1 ゲームごとに結果が決まるので、1 ゲームを 1 つのレーティング期間として更新する
+//!
+
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+/// 初めて遊ぶプレイヤーのレーティング
+pub const INIT_RATING: f64 = 1500.0;
+/// 初めて遊ぶプレイヤーのレーティングの偏差（RD）
+pub const INIT_DEVIATION: f64 = 350.0;
+/// 初めて遊ぶプレイヤーのレーティングの変動率（σ）
+pub const INIT_VOLATILITY: f64 = 0.06;
+
+/// 変動率の変わりやすさ（τ）。Glickman は 0.3 から 1.2 の間を勧めている
+const TAU: f64 = 0.5;
+/// Glicko のスケールと Glicko-2 のスケールの比
+const SCALE: f64 = 173.7178;
+/// 変動率を求めるときの収束の判定に使う誤差
+const EPSILON: f64 = 0.000001;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Rating {
+    /// レーティング（r）
+    pub rating: f64,
+    /// レーティングの偏差（RD）。小さいほどレーティングが確か
+    pub deviation: f64,
+    /// レーティングの変動率（σ）
+    pub volatility: f64,
+}
+
+impl Default for Rating {
+    fn default() -> Rating {
+        return Rating {
+            rating: INIT_RATING,
+            deviation: INIT_DEVIATION,
+            volatility: INIT_VOLATILITY,
+        };
+    }
+}
+
+/// レーティングの記録。ゲームが終わるたびに、参加したプレイヤーごとに 1 件ずつ追加する
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RatingRecord {
+    /// Slack のユーザー id（bot とのゲームでは bot の id も記録する）
+    pub id: String,
+    /// 記録したときの表示名
+    pub name: String,
+    /// ゲームが終わった後のレーティング
+    pub rating: Rating,
+    /// 記録した時刻（UNIX 時間の秒）
+    pub recorded_at: i64,
+}
+
+impl Rating {
+    /// results（相手のレーティングと、勝ちなら 1.0、負けなら 0.0 のスコアの組）の結果で更新したレーティングを返す
+    /// results が空なら、遊ばなかった期間として偏差だけを広げる
+    pub fn update(&self, results: &[(Rating, f64)]) -> Rating {
+        // Glicko-2 のスケールに直す
+        let mu = (self.rating - INIT_RATING) / SCALE;
+        let phi = self.deviation / SCALE;
+
+        if results.is_empty() {
+            return Rating {
+                rating: self.rating,
+                deviation: (phi * phi + self.volatility * self.volatility).sqrt() * SCALE,
+                volatility: self.volatility,
+            };
+        }
+
+        // 推定分散 v と、レーティングの改善量 delta を求める
+        let mut v_inv = 0.0;
+        let mut score_sum = 0.0;
+        for (opponent, score) in results {
+            let mu_j = (opponent.rating - INIT_RATING) / SCALE;
+            let g_j = g(opponent.deviation / SCALE);
+            let e_j = expected_score(mu, mu_j, g_j);
+            v_inv += g_j * g_j * e_j * (1.0 - e_j);
+            score_sum += g_j * (score - e_j);
+        }
+        let v = 1.0 / v_inv;
+        let delta = v * score_sum;
+
+        let volatility = self.next_volatility(phi, v, delta);
+        let phi_star = (phi * phi + volatility * volatility).sqrt();
+        let new_phi = 1.0 / (1.0 / (phi_star * phi_star) + 1.0 / v).sqrt();
+        let new_mu = mu + new_phi * new_phi * score_sum;
+
+        return Rating {
+            rating: new_mu * SCALE + INIT_RATING,
+            deviation: new_phi * SCALE,
+            volatility: volatility,
+        };
+    }
+
+    /// 新しい変動率を Illinois 法で求める
+    fn next_volatility(&self, phi: f64, v: f64, delta: f64) -> f64 {
+        let a = (self.volatility * self.volatility).ln();
+        let f = |x: f64| {
+            let ex = x.exp();
+            return ex * (delta * delta - phi * phi - v - ex)
+                / (2.0 * (phi * phi + v + ex).powi(2))
+                - (x - a) / (TAU * TAU);
+        };
+
+        let mut lower = a;
+        let mut upper = if delta * delta > phi * phi + v {
+            (delta * delta - phi * phi - v).ln()
+        } else {
+            let mut k = 1.0;
+            while f(a - k * TAU) < 0.0 {
+                k += 1.0;
+            }
+            a - k * TAU
+        };
+        let mut f_lower = f(lower);
+        let mut f_upper = f(upper);
+        while (upper - lower).abs() > EPSILON {
+            let c = lower + (lower - upper) * f_lower / (f_upper - f_lower);
+            let f_c = f(c);
+            if f_c * f_upper <= 0.0 {
+                lower = upper;
+                f_lower = f_upper;
+            } else {
+                f_lower /= 2.0;
+            }
+            upper = c;
+            f_upper = f_c;
+        }
+        return (lower / 2.0).exp();
+    }
+}
+
+fn g(phi: f64) -> f64 {
+    return 1.0 / (1.0 + 3.0 * phi * phi / (PI * PI)).sqrt();
+}
+
+/// レーティング mu のプレイヤーが、レーティング mu_j の相手に勝つ確率の期待値
+fn expected_score(mu: f64, mu_j: f64, g_j: f64) -> f64 {
+    return 1.0 / (1.0 + (-g_j * (mu - mu_j)).exp());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glickman_example_test() {
+        // Glickman の論文の計算例
+        let rating = Rating {
+            rating: 1500.0,
+            deviation: 200.0,
+            volatility: 0.06,
+        };
+        let opponent = |rating: f64, deviation: f64| Rating {
+            rating: rating,
+            deviation: deviation,
+            volatility: INIT_VOLATILITY,
+        };
+        let updated = rating.update(&[
+            (opponent(1400.0, 30.0), 1.0),
+            (opponent(1550.0, 100.0), 0.0),
+            (opponent(1700.0, 300.0), 0.0),
+        ]);
+        assert!((updated.rating - 1464.06).abs() < 0.01);
+        assert!((updated.deviation - 151.52).abs() < 0.01);
+        assert!((updated.volatility - 0.05999).abs() < 0.00001);
+
+        // 遊ばなかった期間は偏差だけが広がる
+        let idle = rating.update(&[]);
+        assert_eq!(idle.rating, rating.rating);
+        assert!(idle.deviation > rating.deviation);
+    }
+}
@@ -0,0 +1,178 @@
+//!
+//! 旗源平の盤面（両プレイヤーのまといと旗、直前に振ったサイコロの目）を SVG で描画するモジュール
+//!
+
+use super::game::*;
+
+const WIDTH: i32 = 520;
+const HEIGHT: i32 = 330;
+/// 1 種類の旗を描く本数の上限。これより多い場合は、本数の表示だけで区別する
+const MAX_DRAWN_FLAGS: i32 = 10;
+const DIE_SIZE: i32 = 36;
+
+/// game_log の直後の盤面を SVG にする
+pub fn render_board_svg(game_log: &GameLog) -> String {
+    let mut svg = String::new();
+    svg.push_str(&format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\" font-family=\"sans-serif\">\n",
+        WIDTH, HEIGHT, WIDTH, HEIGHT
+    ));
+    svg.push_str(&format!(
+        "<rect width=\"{}\" height=\"{}\" fill=\"#f7f1e3\"/>\n",
+        WIDTH, HEIGHT
+    ));
+
+    // 直前に振ったサイコロの目（振り直した場合は最後の目）
+    let last_dice = game_log.events.iter().rev().find_map(|event| match event {
+        GameEvent::DiceRolled { dice1, dice2, .. } => Some((*dice1, *dice2)),
+        _ => None,
+    });
+    svg.push_str(&format!(
+        "<text x=\"16\" y=\"34\" font-size=\"18\">{} の番</text>\n",
+        escape(&game_log.player(&game_log.player_turn).name)
+    ));
+    if let Some((dice1, dice2)) = last_dice {
+        svg.push_str(&render_die(WIDTH - 2 * DIE_SIZE - 24, 10, dice1));
+        svg.push_str(&render_die(WIDTH - DIE_SIZE - 16, 10, dice2));
+    }
+
+    for (i, player) in [&game_log.player1, &game_log.player2].iter().enumerate() {
+        let top = 60 + i as i32 * 135;
+        svg.push_str(&format!(
+            "<text x=\"16\" y=\"{}\" font-size=\"16\" font-weight=\"bold\">{}</text>\n",
+            top + 18,
+            escape(&player.name)
+        ));
+        svg.push_str(&render_score_row(top + 30, "自分の旗", &player.my_score));
+        svg.push_str(&render_score_row(top + 80, "取った旗", &player.got_score));
+    }
+
+    svg.push_str("</svg>\n");
+    return svg;
+}
+
+/// 上端 top から、まといと大旗・中旗・小旗の束を 1 行描く
+fn render_score_row(top: i32, label: &str, score: &Score) -> String {
+    let mut svg = String::new();
+    svg.push_str(&format!(
+        "<text x=\"16\" y=\"{}\" font-size=\"12\">{}</text>\n",
+        top + 26,
+        label
+    ));
+
+    // まとい
+    let mut x = 90;
+    if score.matoi {
+        svg.push_str(&format!(
+            "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"#5d4037\" stroke-width=\"2\"/>\n",
+            x + 8,
+            top,
+            x + 8,
+            top + 40
+        ));
+        svg.push_str(&format!(
+            "<circle cx=\"{}\" cy=\"{}\" r=\"7\" fill=\"#c0392b\"/>\n",
+            x + 8,
+            top + 8
+        ));
+    }
+    x += 28;
+
+    // (旗, 旗の高さ, 旗の色)
+    let flags = [
+        (Flag::Obata, 40, "#c0392b"),
+        (Flag::Chubata, 30, "#2c3e50"),
+        (Flag::Kobata, 20, "#ecf0f1"),
+    ];
+    for (flag, height, color) in flags.iter() {
+        let count = score.count(*flag);
+        for j in 0..std::cmp::min(count, MAX_DRAWN_FLAGS) {
+            svg.push_str(&format!(
+                "<rect x=\"{}\" y=\"{}\" width=\"6\" height=\"{}\" fill=\"{}\" stroke=\"#333\" stroke-width=\"0.5\"/>\n",
+                x + j * 8,
+                top + 40 - height,
+                height,
+                color
+            ));
+        }
+        svg.push_str(&format!(
+            "<text x=\"{}\" y=\"{}\" font-size=\"12\">{} {}</text>\n",
+            x,
+            top + 54,
+            flag.to_string(),
+            count
+        ));
+        x += MAX_DRAWN_FLAGS * 8 + 40;
+    }
+
+    return svg;
+}
+
+/// 左上 (x, y) にサイコロを 1 つ描く
+fn render_die(x: i32, y: i32, face: u8) -> String {
+    let mut svg = format!(
+        "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"6\" fill=\"#fff\" stroke=\"#333\"/>\n",
+        x, y, DIE_SIZE, DIE_SIZE
+    );
+    // 3x3 のマス目のうち、目を打つ位置
+    let pips: &[(i32, i32)] = match face {
+        1 => &[(1, 1)],
+        2 => &[(0, 0), (2, 2)],
+        3 => &[(0, 0), (1, 1), (2, 2)],
+        4 => &[(0, 0), (2, 0), (0, 2), (2, 2)],
+        5 => &[(0, 0), (2, 0), (1, 1), (0, 2), (2, 2)],
+        _ => &[(0, 0), (2, 0), (0, 1), (2, 1), (0, 2), (2, 2)],
+    };
+    let cell = DIE_SIZE / 4;
+    for (col, row) in pips {
+        // 1 の目だけは赤く大きく打つ
+        let (r, color) = if face == 1 {
+            (5, "#c0392b")
+        } else {
+            (3, "#333")
+        };
+        svg.push_str(&format!(
+            "<circle cx=\"{}\" cy=\"{}\" r=\"{}\" fill=\"{}\"/>\n",
+            x + cell * (col + 1),
+            y + cell * (row + 1),
+            r,
+            color
+        ));
+    }
+    return svg;
+}
+
+/// プレイヤー名を SVG のテキストに埋め込めるようにする
+fn escape(s: &str) -> String {
+    return s
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::dice::ScriptedDice;
+    use super::super::rule_set::RuleSet;
+    use super::*;
+
+    #[test]
+    fn render_board_svg_test() {
+        let player =
+            |name: &str| Player::new(name.to_string(), Score::new(29, true), Score::new(0, false));
+        let mut game = Hatagenpei::new(
+            player("<alice>"),
+            player("bob"),
+            PlayerTurn::Player1,
+            Box::new(ScriptedDice::new(&[2, 3])),
+            RuleSet::default(),
+        );
+        let svg = render_board_svg(&game.next().unwrap());
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        // 名前はエスケープされる
+        assert!(svg.contains("&lt;alice&gt;"));
+        assert!(!svg.contains("<alice>"));
+    }
+}
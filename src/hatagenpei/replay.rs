@@ -0,0 +1,272 @@
+//!
+//! 旗源平のゲームを再現するためのリプレイ
+//!
+
+use super::dice::*;
+use super::game::*;
+use super::rule_set::RuleSet;
+use super::score_operator::Progress;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// HatagenpeiController::step 1回分の記録
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ReplayStep {
+    /// Hatagenpei に渡したシード
+    pub seed: u64,
+    /// プレイヤーが実物のサイコロを振って入力した目。空なら、すべてシードから振っている
+    #[serde(default)]
+    pub dice: Vec<u8>,
+    /// Hatagenpei::next の実行ログ（呼び出した順）
+    pub logs: Vec<GameLog>,
+}
+
+/// 1ゲーム分のリプレイ
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Replay {
+    /// ゲーム開始時の進行状態（使用したルールの id もここに含まれる）
+    pub initial: Progress,
+    /// 各 step の記録
+    pub steps: Vec<ReplayStep>,
+    /// ゲームのシード。各 step のシードはここから決まる
+    /// 開始時にはコミットメント（SHA-256）だけを公開し、終了時にシード自体を公開する
+    /// シード導入前に記録されたリプレイでは None になり、各 step のシードはランダムに決める
+    #[serde(default)]
+    pub game_seed: Option<u64>,
+    /// 開始時に公開したゲームのシードのコミットメント
+    /// コミットメントを記録する前に始まったゲームでは None になり、公開されたシードを検証できない
+    #[serde(default)]
+    pub commitment: Option<String>,
+}
+
+#[derive(Debug, Fail)]
+pub enum ReplayError {
+    #[fail(display = "rule set {} is not found", _0)]
+    RuleSetNotFound(String),
+    #[fail(display = "replay has no game seed")]
+    NoGameSeed,
+    #[fail(display = "replay has no seed commitment")]
+    NoCommitment,
+    #[fail(display = "seed does not match the commitment published at the start")]
+    CommitmentMismatch,
+    #[fail(display = "seed is not the game seed of the replay")]
+    GameSeedMismatch,
+    #[fail(display = "seed of step {} is not derived from the game seed", _0)]
+    SeedMismatch(usize),
+    #[fail(display = "replay diverged at step {}, turn {}", step, turn)]
+    Diverged {
+        /// 食い違いが発生した step の番号（0 始まり）
+        step: usize,
+        /// 食い違いが発生した step 内での Hatagenpei::next の呼び出し番号（0 始まり）
+        turn: usize,
+        /// リプレイに記録されていたログ
+        expected: GameLog,
+        /// 再実行して得られたログ。ゲームが既に終了していた場合は None
+        actual: Option<GameLog>,
+    },
+}
+
+impl ReplayStep {
+    /// この step で使ったサイコロを作る。入力された目を出し切った後は、シードから振る
+    pub fn dice_source(&self) -> Box<dyn DiceSource> {
+        if self.dice.is_empty() {
+            return Box::new(SeededDice::new(self.seed));
+        }
+        return Box::new(ScriptedDice::with_fallback(
+            &self.dice,
+            Box::new(SeededDice::new(self.seed)),
+        ));
+    }
+}
+
+/// ゲームのシードのコミットメント。シードを10進数の文字列にして、SHA-256 をとったもの（16進数）
+pub fn seed_commitment(game_seed: u64) -> String {
+    return format!("{:x}", Sha256::digest(game_seed.to_string().as_bytes()));
+}
+
+/// ゲームのシードから、step 番目（0 始まり）の step のシードを決める
+/// "<ゲームのシード>:<step>" の SHA-256 の先頭 8 バイトを、ビッグエンディアンで読んだもの
+pub fn step_seed(game_seed: u64, step: usize) -> u64 {
+    let hash = Sha256::digest(format!("{}:{}", game_seed, step).as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&hash[..8]);
+    return u64::from_be_bytes(bytes);
+}
+
+impl Replay {
+    pub fn new(initial: &Progress, game_seed: u64) -> Replay {
+        return Replay {
+            initial: initial.clone(),
+            steps: vec![],
+            game_seed: Some(game_seed),
+            commitment: Some(seed_commitment(game_seed)),
+        };
+    }
+
+    /// 次の step で使うシード
+    pub fn next_step_seed(&self) -> u64 {
+        return match self.game_seed {
+            Some(game_seed) => step_seed(game_seed, self.steps.len()),
+            None => rand::random::<u64>(),
+        };
+    }
+
+    /// 公開されたゲームのシード game_seed が、開始時に公開したコミットメントと記録したシードに一致するか確かめる
+    /// さらに game_seed から各 step のシードを計算し直し、記録と一致するか確かめた上で、
+    /// そのシードで Hatagenpei::next を再実行して検証する
+    pub fn verify_with_seed(&self, rule_set: &RuleSet, game_seed: u64) -> Result<(), ReplayError> {
+        let stored_seed = self.game_seed.ok_or(ReplayError::NoGameSeed)?;
+        let commitment = self.commitment.as_ref().ok_or(ReplayError::NoCommitment)?;
+        if seed_commitment(game_seed) != *commitment {
+            return Err(ReplayError::CommitmentMismatch);
+        }
+        if game_seed != stored_seed {
+            return Err(ReplayError::GameSeedMismatch);
+        }
+        for (step, replay_step) in self.steps.iter().enumerate() {
+            if replay_step.seed != step_seed(game_seed, step) {
+                return Err(ReplayError::SeedMismatch(step));
+            }
+        }
+        return self.verify(rule_set);
+    }
+
+    /// 記録されたシードで Hatagenpei::next を再実行し、記録されたログと一致するか検証する
+    pub fn verify(&self, rule_set: &RuleSet) -> Result<(), ReplayError> {
+        let mut player1 = self.initial.user.clone();
+        let mut player2 = self.initial.bot.clone();
+        let mut turn = self.initial.turn.clone();
+
+        for (step, replay_step) in self.steps.iter().enumerate() {
+            // HatagenpeiController::step と同じく、毎 step 新しい Hatagenpei を作って、前の step で終わった手番から始める
+            let mut game = Hatagenpei::new(
+                player1.clone(),
+                player2.clone(),
+                turn.clone(),
+                replay_step.dice_source(),
+                rule_set.clone(),
+            );
+            for (turn, expected) in replay_step.logs.iter().enumerate() {
+                // 入力された目で振った最初の手番は、1回だけ振っている
+                let actual = if turn == 0 && !replay_step.dice.is_empty() {
+                    game.next_roll()
+                } else {
+                    game.next()
+                };
+                // イベント導入前に記録されたログにはイベントがないので、イベントを除いて比べる
+                let actual = match actual {
+                    Some(actual) if expected.events.is_empty() => Some(GameLog {
+                        events: vec![],
+                        ..actual
+                    }),
+                    actual => actual,
+                };
+                if actual.as_ref() != Some(expected) {
+                    return Err(ReplayError::Diverged {
+                        step: step,
+                        turn: turn,
+                        expected: expected.clone(),
+                        actual: actual,
+                    });
+                }
+                player1 = expected.player1.clone();
+                player2 = expected.player2.clone();
+            }
+            turn = game.get_turn();
+        }
+
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::score_operator::PlayerIds;
+    use super::*;
+
+    #[test]
+    fn verify_replay_test() {
+        let player =
+            |name: &str| Player::new(name.to_string(), Score::new(29, true), Score::new(0, false));
+        let rule_set = RuleSet::default();
+        let initial = Progress::new(&player("alice"), &player("bob"), &rule_set);
+
+        // 2 step 分のリプレイを作る
+        let mut replay = Replay::new(&initial, 42);
+        let (mut player1, mut player2) = (initial.user.clone(), initial.bot.clone());
+        for _ in 0..2 {
+            let seed = replay.next_step_seed();
+            let mut game = Hatagenpei::new(
+                player1.clone(),
+                player2.clone(),
+                PlayerTurn::Player1,
+                Box::new(SeededDice::new(seed)),
+                rule_set.clone(),
+            );
+            let logs = vec![game.next().unwrap(), game.next().unwrap()];
+            player1 = logs[1].player1.clone();
+            player2 = logs[1].player2.clone();
+            replay.steps.push(ReplayStep {
+                seed: seed,
+                dice: vec![],
+                logs: logs,
+            });
+        }
+        assert!(replay.verify(&rule_set).is_ok());
+        assert!(replay.verify_with_seed(&rule_set, 42).is_ok());
+
+        // 表示名で記録していたリプレイに id を入れても、そのまま検証できる
+        let mut ids = PlayerIds::new();
+        ids.insert("alice".to_string(), "UALICE".to_string());
+        let mut migrated = replay.clone();
+        migrated.assign_player_ids(&ids);
+        assert_eq!(migrated.initial.user.id, "UALICE");
+        assert!(migrated.verify_with_seed(&rule_set, 42).is_ok());
+
+        // 公開されたシードが違えば、開始時のコミットメントと一致しない
+        match replay.verify_with_seed(&rule_set, 43) {
+            Err(ReplayError::CommitmentMismatch) => {}
+            _ => panic!("commitment mismatch is not detected"),
+        }
+
+        // コミットメントと違うシードでゲームを進めていれば、コミットメントが合うシードを出しても検出される
+        let mut cheated = replay.clone();
+        cheated.game_seed = Some(43);
+        match cheated.verify_with_seed(&rule_set, 42) {
+            Err(ReplayError::GameSeedMismatch) => {}
+            _ => panic!("game seed mismatch is not detected"),
+        }
+        cheated.game_seed = Some(42);
+        cheated.steps[0].seed = step_seed(43, 0);
+        match cheated.verify_with_seed(&rule_set, 42) {
+            Err(ReplayError::SeedMismatch(step)) => assert_eq!(step, 0),
+            _ => panic!("seed mismatch is not detected"),
+        }
+
+        // コミットメントを記録する前のリプレイは、公開されたシードを検証できない
+        let mut old_replay = replay.clone();
+        old_replay.commitment = None;
+        match old_replay.verify_with_seed(&rule_set, 42) {
+            Err(ReplayError::NoCommitment) => {}
+            _ => panic!("missing commitment is not detected"),
+        }
+
+        // シードを書き換えると、食い違いが検出される
+        replay.steps[1].seed = 3;
+        match replay.verify(&rule_set) {
+            Err(ReplayError::Diverged { step, .. }) => assert_eq!(step, 1),
+            _ => panic!("divergence is not detected"),
+        }
+    }
+
+    #[test]
+    fn seed_commitment_test() {
+        // echo -n 42 | sha256sum
+        assert_eq!(
+            seed_commitment(42),
+            "73475cb40a568e8da8a045ced110137e159f890ac4da883b6b17dc651b3a8049"
+        );
+        assert_eq!(step_seed(42, 0), step_seed(42, 0));
+        assert_ne!(step_seed(42, 0), step_seed(42, 1));
+    }
+}
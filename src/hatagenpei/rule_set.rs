@@ -0,0 +1,148 @@
+//!
+//! 旗源平のルール（サイコロの目ごとの旗のやり取り）をファイルから読み込むモジュール
+//!
+
+use super::game::HatagenpeiCommand;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// 標準ルールの id
+pub const DEFAULT_RULE_SET_ID: &str = "default";
+
+/// 標準ルール。rules/default.toml をバイナリに埋め込んでおく
+const DEFAULT_RULE_SET_TOML: &str = include_str!("../../rules/default.toml");
+
+#[derive(Debug, Fail)]
+pub enum RuleSetError {
+    #[fail(display = "unknown rule set format: {}", _0)]
+    UnknownFormat(String),
+    #[fail(display = "rule set id is empty")]
+    EmptyId,
+    #[fail(display = "invalid dice: {}, {}", _0, _1)]
+    InvalidDice(u8, u8),
+    #[fail(display = "duplicated dice pair: {}, {}", _0, _1)]
+    DuplicatedPair(u8, u8),
+    #[fail(display = "missing dice pair: {}, {}", _0, _1)]
+    MissingPair(u8, u8),
+}
+
+/// 旗源平のルール一式
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RuleSet {
+    /// ルールを識別する id
+    pub id: String,
+    /// 表示用のルール名
+    pub name: String,
+    /// サイコロの目 21 通りに対応するコマンド
+    pub commands: Vec<HatagenpeiCommand>,
+}
+
+impl Default for RuleSet {
+    fn default() -> RuleSet {
+        return RuleSet::from_toml_str(DEFAULT_RULE_SET_TOML)
+            .expect("failed to load default rule set");
+    }
+}
+
+impl RuleSet {
+    /// ファイルからルールを読み込む。拡張子が toml なら TOML、json なら JSON として扱う
+    pub fn load(path: &Path) -> Result<RuleSet, failure::Error> {
+        let content = std::fs::read_to_string(path)?;
+        let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+        return match extension {
+            "toml" => RuleSet::from_toml_str(&content),
+            "json" => RuleSet::from_json_str(&content),
+            _ => Err(RuleSetError::UnknownFormat(path.display().to_string()).into()),
+        };
+    }
+
+    pub fn from_toml_str(s: &str) -> Result<RuleSet, failure::Error> {
+        let rule_set: RuleSet = toml::from_str(s)?;
+        return rule_set.validated();
+    }
+
+    pub fn from_json_str(s: &str) -> Result<RuleSet, failure::Error> {
+        let rule_set: RuleSet = serde_json::from_str(s)?;
+        return rule_set.validated();
+    }
+
+    /// サイコロの目に対応するコマンドを返す。目の順番は問わない
+    pub fn find_command(&self, dice1: u8, dice2: u8) -> Option<&HatagenpeiCommand> {
+        let (d1, d2) = if dice1 <= dice2 {
+            (dice1, dice2)
+        } else {
+            (dice2, dice1)
+        };
+        return self
+            .commands
+            .iter()
+            .find(|cmd| cmd.dice1 == d1 && cmd.dice2 == d2);
+    }
+
+    /// 目の並びを dice1 <= dice2 にそろえ、21 通りの組み合わせが過不足なく定義されているか検証する
+    fn validated(mut self) -> Result<RuleSet, failure::Error> {
+        if self.id.is_empty() {
+            return Err(RuleSetError::EmptyId.into());
+        }
+
+        let mut defined = [[false; 6]; 6];
+        for cmd in &mut self.commands {
+            if cmd.dice1 < 1 || cmd.dice1 > 6 || cmd.dice2 < 1 || cmd.dice2 > 6 {
+                return Err(RuleSetError::InvalidDice(cmd.dice1, cmd.dice2).into());
+            }
+            if cmd.dice1 > cmd.dice2 {
+                std::mem::swap(&mut cmd.dice1, &mut cmd.dice2);
+            }
+            let (i, j) = ((cmd.dice1 - 1) as usize, (cmd.dice2 - 1) as usize);
+            if defined[i][j] {
+                return Err(RuleSetError::DuplicatedPair(cmd.dice1, cmd.dice2).into());
+            }
+            defined[i][j] = true;
+        }
+
+        for d1 in 1..=6 {
+            for d2 in d1..=6 {
+                if !defined[(d1 - 1) as usize][(d2 - 1) as usize] {
+                    return Err(RuleSetError::MissingPair(d1, d2).into());
+                }
+            }
+        }
+
+        return Ok(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_rule_set_test() {
+        let rule_set = RuleSet::default();
+        assert_eq!(rule_set.id, DEFAULT_RULE_SET_ID);
+        assert_eq!(rule_set.commands.len(), 21);
+        assert_eq!(rule_set.find_command(5, 1).unwrap().point, 10);
+    }
+
+    #[test]
+    fn validate_rule_set_test() {
+        // json で読み込めること、目の順番が入れ替わっていても受け付けること
+        let mut rule_set = RuleSet::default();
+        rule_set.commands[6].dice1 = 2;
+        rule_set.commands[6].dice2 = 1;
+        let json = serde_json::to_string(&rule_set).unwrap();
+        assert_eq!(RuleSet::from_json_str(&json).unwrap(), RuleSet::default());
+
+        // 組み合わせが足りない場合はエラー
+        let mut missing = RuleSet::default();
+        missing.commands.pop();
+        let json = serde_json::to_string(&missing).unwrap();
+        assert!(RuleSet::from_json_str(&json).is_err());
+
+        // 組み合わせが重複している場合はエラー
+        let mut duplicated = RuleSet::default();
+        duplicated.commands[0] = duplicated.commands[1].clone();
+        let json = serde_json::to_string(&duplicated).unwrap();
+        assert!(RuleSet::from_json_str(&json).is_err());
+    }
+}
@@ -1,43 +1,85 @@
 use super::*;
 use std::collections::BTreeMap;
 
+/// key はどれも、特に書いていなければプレイヤーの Slack のユーザー id
 pub struct ScoresInMap {
-    score_map: BTreeMap<String, Progress>,
     winlose_map: BTreeMap<String, WinLose>,
+    replay_map: BTreeMap<String, Replay>,
+    finished_replay_map: BTreeMap<String, Vec<Replay>>,
+    /// key は (player1 の id, player2 の id)
+    match_map: BTreeMap<(String, String), MatchProgress>,
+    challenge_map: BTreeMap<String, Challenge>,
+    /// key はチャンネルの id
+    table_map: BTreeMap<String, TableProgress>,
+    /// key はチャンネルの id
+    team_match_map: BTreeMap<String, TeamMatchProgress>,
+    /// key は陣営の名前
+    team_winlose_map: BTreeMap<String, WinLose>,
+    contribution_map: BTreeMap<String, Contribution>,
+    history_map: BTreeMap<String, Vec<GameHistory>>,
+    exhibition_winlose_map: BTreeMap<String, WinLose>,
+    rating_map: BTreeMap<String, Vec<RatingRecord>>,
+    /// key は (プレイヤーの id, 相手の id)
+    head_to_head_map: BTreeMap<(String, String), HeadToHead>,
+    /// key は (game_id, プレイヤーの id)
+    game_progress_map: BTreeMap<(String, String), String>,
 }
 
 impl ScoresInMap {
     pub fn new() -> ScoresInMap {
         return ScoresInMap {
-            score_map: BTreeMap::new(),
             winlose_map: BTreeMap::new(),
+            replay_map: BTreeMap::new(),
+            finished_replay_map: BTreeMap::new(),
+            match_map: BTreeMap::new(),
+            challenge_map: BTreeMap::new(),
+            table_map: BTreeMap::new(),
+            team_match_map: BTreeMap::new(),
+            team_winlose_map: BTreeMap::new(),
+            contribution_map: BTreeMap::new(),
+            history_map: BTreeMap::new(),
+            exhibition_winlose_map: BTreeMap::new(),
+            rating_map: BTreeMap::new(),
+            head_to_head_map: BTreeMap::new(),
+            game_progress_map: BTreeMap::new(),
         };
     }
 }
 
 impl ScoreOperator for ScoresInMap {
-    fn get_progress(&mut self, player_name: &str) -> Option<Progress> {
-        if let Some(progress) = self.score_map.get(player_name) {
-            return Some(progress.clone());
-        } else {
-            return None;
-        }
+    fn get_game_progress(&mut self, game_id: &str, player_id: &str) -> Option<String> {
+        return self
+            .game_progress_map
+            .get(&(game_id.to_string(), player_id.to_string()))
+            .cloned();
     }
-
-    fn insert_progress(&mut self, progress: &Progress) -> bool {
-        self.score_map
-            .insert(progress.user.name.to_string(), progress.clone());
+    fn get_game_progresses(&self, game_id: &str) -> Vec<String> {
+        return self
+            .game_progress_map
+            .iter()
+            .filter(|((id, _), _)| id == game_id)
+            .map(|(_, data)| data.clone())
+            .collect();
+    }
+    fn insert_game_progress(&mut self, game_id: &str, player_id: &str, data: &str) -> bool {
+        self.game_progress_map.insert(
+            (game_id.to_string(), player_id.to_string()),
+            data.to_string(),
+        );
         return true;
     }
-    fn delete_progress(&mut self, player_name: &str) -> bool {
-        self.score_map.remove(player_name);
+    fn delete_game_progress(&mut self, game_id: &str, player_id: &str) -> bool {
+        self.game_progress_map
+            .remove(&(game_id.to_string(), player_id.to_string()));
         return true;
     }
-    fn update_winloses(&mut self, player_name: &str, is_player_win: bool) -> bool {
-        let mut win_lose = match self.winlose_map.get(player_name) {
+    fn update_winloses(&mut self, player_id: &str, player_name: &str, is_player_win: bool) -> bool {
+        let mut win_lose = match self.winlose_map.get(player_id) {
             Some(win_lose) => win_lose.clone(),
             None => WinLose::new(0, 0, player_name),
         };
+        win_lose.id = player_id.to_string();
+        win_lose.name = player_name.to_string();
 
         if is_player_win {
             win_lose.win += 1;
@@ -45,15 +87,244 @@ impl ScoreOperator for ScoresInMap {
             win_lose.lose += 1;
         }
 
-        self.winlose_map.insert(player_name.to_string(), win_lose);
+        self.winlose_map.insert(player_id.to_string(), win_lose);
 
         return true;
     }
     fn get_win_loses(&self) -> Vec<WinLose> {
         let mut res = vec![];
         for (_, win_lose) in self.winlose_map.iter() {
-            res.push(win_lose.clone());
+            if !win_lose.is_empty() {
+                res.push(win_lose.clone());
+            }
         }
         return res;
     }
+    fn get_win_lose(&mut self, player_id: &str) -> Option<WinLose> {
+        return self.winlose_map.get(player_id).cloned();
+    }
+    fn set_handicap(&mut self, player_id: &str, player_name: &str, handicap: Option<i32>) -> bool {
+        if !is_valid_handicap(handicap) {
+            return false;
+        }
+        let mut win_lose = match self.winlose_map.get(player_id) {
+            Some(win_lose) => win_lose.clone(),
+            None => WinLose::new(0, 0, player_name),
+        };
+        win_lose.id = player_id.to_string();
+        win_lose.name = player_name.to_string();
+        win_lose.handicap = handicap;
+        // 勝敗もハンデもない記録は残さない
+        if win_lose.is_empty() && win_lose.handicap.is_none() {
+            self.winlose_map.remove(player_id);
+        } else {
+            self.winlose_map.insert(player_id.to_string(), win_lose);
+        }
+        return true;
+    }
+    fn get_replay(&mut self, player_id: &str) -> Option<Replay> {
+        return self.replay_map.get(player_id).cloned();
+    }
+    fn insert_replay(&mut self, replay: &Replay) -> bool {
+        self.replay_map
+            .insert(replay.initial.user.id.to_string(), replay.clone());
+        return true;
+    }
+    fn finish_replay(&mut self, player_id: &str) -> bool {
+        if let Some(replay) = self.replay_map.remove(player_id) {
+            self.finished_replay_map
+                .entry(player_id.to_string())
+                .or_insert_with(Vec::new)
+                .push(replay);
+        }
+        return true;
+    }
+    fn delete_replay(&mut self, player_id: &str) -> bool {
+        self.replay_map.remove(player_id);
+        return true;
+    }
+    fn get_latest_finished_replay(&mut self, player_id: &str) -> Option<Replay> {
+        return self
+            .finished_replay_map
+            .get(player_id)
+            .and_then(|replays| replays.last().cloned());
+    }
+    fn get_match_progress(&mut self, player_id: &str) -> Option<MatchProgress> {
+        return self
+            .match_map
+            .iter()
+            .find(|((player1, player2), _)| player1 == player_id || player2 == player_id)
+            .map(|(_, match_progress)| match_progress.clone());
+    }
+    fn insert_match_progress(&mut self, match_progress: &MatchProgress) -> bool {
+        self.match_map.insert(
+            (
+                match_progress.player1.id.clone(),
+                match_progress.player2.id.clone(),
+            ),
+            match_progress.clone(),
+        );
+        return true;
+    }
+    fn delete_match_progress(&mut self, player_id: &str) -> bool {
+        self.match_map
+            .retain(|(player1, player2), _| player1 != player_id && player2 != player_id);
+        return true;
+    }
+    fn get_challenge(&mut self, challenged_id: &str) -> Option<Challenge> {
+        return self.challenge_map.get(challenged_id).cloned();
+    }
+    fn insert_challenge(&mut self, challenge: &Challenge) -> bool {
+        self.challenge_map
+            .insert(challenge.challenged_id.clone(), challenge.clone());
+        return true;
+    }
+    fn delete_challenge(&mut self, challenged_id: &str) -> bool {
+        self.challenge_map.remove(challenged_id);
+        return true;
+    }
+    fn get_table(&mut self, channel: &str) -> Option<TableProgress> {
+        return self.table_map.get(channel).cloned();
+    }
+    fn insert_table(&mut self, table: &TableProgress) -> bool {
+        self.table_map.insert(table.channel.clone(), table.clone());
+        return true;
+    }
+    fn delete_table(&mut self, channel: &str) -> bool {
+        self.table_map.remove(channel);
+        return true;
+    }
+    fn get_team_match(&mut self, channel: &str) -> Option<TeamMatchProgress> {
+        return self.team_match_map.get(channel).cloned();
+    }
+    fn insert_team_match(&mut self, team_match: &TeamMatchProgress) -> bool {
+        self.team_match_map
+            .insert(team_match.channel.clone(), team_match.clone());
+        return true;
+    }
+    fn delete_team_match(&mut self, channel: &str) -> bool {
+        self.team_match_map.remove(channel);
+        return true;
+    }
+    fn update_team_winloses(&mut self, side: Side, is_side_win: bool) -> bool {
+        let win_lose = self
+            .team_winlose_map
+            .entry(side.to_string())
+            .or_insert_with(|| WinLose::new(0, 0, &side.to_string()));
+        if is_side_win {
+            win_lose.win += 1;
+        } else {
+            win_lose.lose += 1;
+        }
+        return true;
+    }
+    fn get_team_win_loses(&self) -> Vec<WinLose> {
+        return self.team_winlose_map.values().cloned().collect();
+    }
+    fn update_exhibition_winloses(&mut self, persona_name: &str, is_persona_win: bool) -> bool {
+        let win_lose = self
+            .exhibition_winlose_map
+            .entry(persona_name.to_string())
+            .or_insert_with(|| WinLose::new(0, 0, persona_name));
+        if is_persona_win {
+            win_lose.win += 1;
+        } else {
+            win_lose.lose += 1;
+        }
+        return true;
+    }
+    fn get_exhibition_win_loses(&self) -> Vec<WinLose> {
+        return self.exhibition_winlose_map.values().cloned().collect();
+    }
+    fn update_contribution(&mut self, contribution: &Contribution) -> bool {
+        self.contribution_map
+            .entry(contribution.id.clone())
+            .or_insert_with(|| Contribution::new(&contribution.id, &contribution.name))
+            .add(contribution);
+        return true;
+    }
+    fn get_contributions(&self) -> Vec<Contribution> {
+        return self.contribution_map.values().cloned().collect();
+    }
+    fn insert_game_history(&mut self, history: &GameHistory, bot_id: &str) -> bool {
+        for player_id in history.player_ids.iter().filter(|id| *id != bot_id) {
+            self.history_map
+                .entry(player_id.clone())
+                .or_insert_with(Vec::new)
+                .push(history.clone());
+        }
+        return true;
+    }
+    fn get_game_histories(&mut self, player_id: &str) -> Vec<GameHistory> {
+        return match self.history_map.get(player_id) {
+            Some(histories) => histories.clone(),
+            None => vec![],
+        };
+    }
+    fn get_recent_game_histories(&mut self, player_id: &str, count: usize) -> Vec<GameHistory> {
+        return match self.history_map.get(player_id) {
+            Some(histories) => histories.iter().rev().take(count).cloned().collect(),
+            None => vec![],
+        };
+    }
+    fn update_head_to_head(
+        &mut self,
+        player_id: &str,
+        player_name: &str,
+        opponent_id: &str,
+        opponent_name: &str,
+        is_player_win: bool,
+    ) -> bool {
+        let head_to_head = self
+            .head_to_head_map
+            .entry((player_id.to_string(), opponent_id.to_string()))
+            .or_insert_with(|| HeadToHead::new(player_id, player_name, opponent_id, opponent_name));
+        head_to_head.name = player_name.to_string();
+        head_to_head.opponent_name = opponent_name.to_string();
+        if is_player_win {
+            head_to_head.win += 1;
+        } else {
+            head_to_head.lose += 1;
+        }
+        return true;
+    }
+    fn get_head_to_heads(&self, player_id: &str) -> Vec<HeadToHead> {
+        return self
+            .head_to_head_map
+            .values()
+            .filter(|head_to_head| head_to_head.id == player_id)
+            .cloned()
+            .collect();
+    }
+    fn insert_head_to_head(&mut self, head_to_head: &HeadToHead) -> bool {
+        self.head_to_head_map.insert(
+            (head_to_head.id.clone(), head_to_head.opponent_id.clone()),
+            head_to_head.clone(),
+        );
+        return true;
+    }
+    fn insert_rating(&mut self, record: &RatingRecord) -> bool {
+        self.rating_map
+            .entry(record.id.clone())
+            .or_insert_with(Vec::new)
+            .push(record.clone());
+        return true;
+    }
+    fn get_ratings(&mut self, player_id: &str) -> Vec<RatingRecord> {
+        return match self.rating_map.get(player_id) {
+            Some(ratings) => ratings.clone(),
+            None => vec![],
+        };
+    }
+    fn get_latest_ratings(&self) -> Vec<RatingRecord> {
+        return self
+            .rating_map
+            .values()
+            .filter_map(|ratings| ratings.last().cloned())
+            .collect();
+    }
+    fn migrate_player_ids(&mut self, _ids: &PlayerIds) -> bool {
+        // メモリ上のデータは起動するたびに空から始まるので、表示名で記録したデータは残っていない
+        return true;
+    }
 }
@@ -1,29 +1,226 @@
 pub mod map;
 pub mod postgre;
 
-use super::game::Player;
+use super::dice_game::DiceGame;
+use super::game::{GameLog, Hatagenpei, Player, PlayerTurn};
+use super::multi::{MultiGameLog, Seat};
+use super::rating::RatingRecord;
+use super::replay::Replay;
+use super::rule_set::{RuleSet, DEFAULT_RULE_SET_ID};
+use super::team::{Contribution, Side, Team};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
-#[derive(Clone, Serialize, Deserialize)]
+/// 保存する Progress の形式のバージョン
+/// バージョンを記録する前に保存された Progress は 0 として読み込まれ、足りない項目は既定値で補う
+pub const PROGRESS_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Progress {
+    /// 保存したときの形式のバージョン
+    #[serde(default)]
+    pub version: u32,
     pub user: Player,
     pub bot: Player,
+    /// 次にサイコロを振るプレイヤー（user が Player1、bot が Player2）
+    #[serde(default = "default_turn")]
+    pub turn: PlayerTurn,
+    /// これまでに進めた step の数
+    #[serde(default)]
+    pub step: u32,
+    /// このゲームで使っているルールの id
+    #[serde(default = "default_rule_set_id")]
+    pub rule_set_id: String,
+    /// ゲームを始めたときのルール。途中でルールのファイルが変わっても、同じルールで続けられるように保存する
+    /// ルールを保存する前に始まったゲームでは None で、rule_set_id から探す
+    #[serde(default)]
+    pub rule_set: Option<RuleSet>,
+    /// ゲームを始めた時刻（UNIX 時間の秒）
+    /// 時刻を記録する前に始まったゲームでは None
+    #[serde(default)]
+    pub started_at: Option<i64>,
+    /// 最後にサイコロを振った時刻（UNIX 時間の秒）。期限切れのゲームを片付けるのに使う
+    /// 時刻を記録する前に始まったゲームでは None
+    #[serde(default)]
+    pub updated_at: Option<i64>,
+}
+
+fn default_turn() -> PlayerTurn {
+    return PlayerTurn::Player1;
+}
+
+fn default_rule_set_id() -> String {
+    return DEFAULT_RULE_SET_ID.to_string();
+}
+
+fn default_game_id() -> String {
+    return Hatagenpei::GAME_ID.to_string();
 }
 
 impl Progress {
-    pub fn new(user: &Player, bot: &Player) -> Progress {
+    pub fn new(user: &Player, bot: &Player, rule_set: &RuleSet) -> Progress {
         return Progress {
+            version: PROGRESS_VERSION,
             user: user.clone(),
             bot: bot.clone(),
+            turn: PlayerTurn::Player1,
+            step: 0,
+            rule_set_id: rule_set.id.clone(),
+            rule_set: Some(rule_set.clone()),
+            started_at: None,
+            updated_at: None,
         };
     }
 }
 
+/// 人間同士の対戦の進行状態
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchProgress {
+    /// 挑戦したプレイヤー（先手）
+    pub player1: Player,
+    /// 挑戦を受けたプレイヤー（後手）
+    pub player2: Player,
+    /// 次にサイコロを振るプレイヤー
+    pub turn: PlayerTurn,
+    /// この対戦で使っているルールの id
+    pub rule_set_id: String,
+    /// これまでの手番のログ（手番順）
+    #[serde(default)]
+    pub logs: Vec<GameLog>,
+    /// 各手番のサイコロに使ったシード（手番順）。このシードから出目を再現できる
+    #[serde(default)]
+    pub seeds: Vec<u64>,
+}
+
+/// まだ受けるかどうか返事をしていない挑戦
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Challenge {
+    /// 挑戦したプレイヤーの名前
+    pub challenger: String,
+    /// 挑戦を受けたプレイヤーの名前
+    pub challenged: String,
+    /// 挑戦したプレイヤーの Slack のユーザー id
+    #[serde(default)]
+    pub challenger_id: String,
+    /// 挑戦を受けたプレイヤーの Slack のユーザー id
+    #[serde(default)]
+    pub challenged_id: String,
+    /// 対戦で使うルールの id
+    pub rule_set_id: String,
+}
+
+/// チャンネルで開かれている、3人以上で遊ぶ卓の進行状態
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TableProgress {
+    /// 卓を開いたチャンネルの id
+    pub channel: String,
+    /// 参加したプレイヤー（参加した順）
+    pub seats: Vec<Seat>,
+    /// 次にサイコロを振るプレイヤーの席番号
+    pub turn: usize,
+    /// ゲームが開始済みかどうか。開始前は参加を受け付ける
+    pub is_started: bool,
+    /// この卓で使っているルールの id
+    pub rule_set_id: String,
+    /// これまでの手番のログ（手番順）
+    #[serde(default)]
+    pub logs: Vec<MultiGameLog>,
+    /// 各手番のサイコロに使ったシード（手番順）。このシードから出目を再現できる
+    #[serde(default)]
+    pub seeds: Vec<u64>,
+}
+
+/// チャンネルで開かれている、源氏と平家のチーム戦の進行状態
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TeamMatchProgress {
+    /// チーム戦を開いたチャンネルの id
+    pub channel: String,
+    pub genji: Team,
+    pub heike: Team,
+    /// 次にサイコロを振る陣営
+    pub turn: Side,
+    /// ゲームが開始済みかどうか。開始前は参加を受け付ける
+    pub is_started: bool,
+    /// このチーム戦で使っているルールの id
+    pub rule_set_id: String,
+    /// これまでの手番のログ（手番順）
+    #[serde(default)]
+    pub logs: Vec<GameLog>,
+    /// 各手番のサイコロに使ったシード（手番順）。このシードから出目を再現できる
+    #[serde(default)]
+    pub seeds: Vec<u64>,
+}
+
+impl TeamMatchProgress {
+    pub fn team(&self, side: Side) -> &Team {
+        return match side {
+            Side::Genji => &self.genji,
+            Side::Heike => &self.heike,
+        };
+    }
+
+    pub fn team_mut(&mut self, side: Side) -> &mut Team {
+        return match side {
+            Side::Genji => &mut self.genji,
+            Side::Heike => &mut self.heike,
+        };
+    }
+}
+
+/// 終了したゲームの記録
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GameHistory {
+    /// 遊んだゲームの DiceGame::GAME_ID。ゲームの種類を記録する前の履歴は旗源平
+    #[serde(default = "default_game_id")]
+    pub game_id: String,
+    /// 参加したプレイヤーの名前（bot との対戦では bot も含む）
+    pub players: Vec<String>,
+    /// 参加したプレイヤーの Slack のユーザー id（players と同じ順で、bot との対戦では bot も含む）
+    /// 履歴はこの id ごとに記録する（bot の履歴は記録しない）
+    #[serde(default)]
+    pub player_ids: Vec<String>,
+    /// 勝ったプレイヤーの名前（チーム戦では陣営の名前）
+    pub winner: String,
+    /// 勝ったプレイヤーの Slack のユーザー id（bot が勝った場合は bot の id）
+    /// チーム戦と、勝者の id を記録する前に終わったゲームでは空
+    #[serde(default)]
+    pub winner_id: String,
+    /// このゲームで使ったルールの id
+    pub rule_set_id: String,
+    /// 各手番のログ（手番順）。卓のゲームでは空で、table_logs に記録する
+    pub logs: Vec<GameLog>,
+    /// 卓のゲームの各手番のログ（手番順）。卓以外のゲームでは空
+    #[serde(default)]
+    pub table_logs: Vec<MultiGameLog>,
+    /// 各手番のサイコロに使ったシード（手番順）
+    /// bot とのゲームでは step ごとのシードがリプレイに残るので、ここは空になる
+    #[serde(default)]
+    pub seeds: Vec<u64>,
+}
+
+/// 管理者が設定できるハンデ（中旗の本数）の上限
+pub const HANDICAP_MAX: i32 = 10;
+
+/// handicap が設定できる範囲（0 から HANDICAP_MAX まで）にあるか。None（勝率から自動で決める）はいつでも設定できる
+pub fn is_valid_handicap(handicap: Option<i32>) -> bool {
+    return match handicap {
+        Some(handicap) => 0 <= handicap && handicap <= HANDICAP_MAX,
+        None => true,
+    };
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct WinLose {
+    /// 表示名。勝敗を登録するたびに新しい名前に更新する
     pub name: String,
+    /// Slack のユーザー id。陣営や模範試合の bot の勝敗では空
+    #[serde(default)]
+    pub id: String,
     pub win: i32,
     pub lose: i32,
+    /// 管理者が設定したハンデ（開始時に追加で持つ中旗の本数）。None なら勝率から自動で決める
+    #[serde(default)]
+    pub handicap: Option<i32>,
 }
 
 impl WinLose {
@@ -32,19 +229,262 @@ impl WinLose {
             win: win,
             lose: lose,
             name: name.to_string(),
+            id: String::new(),
+            handicap: None,
+        };
+    }
+
+    /// まだ終えたゲームがない記録かどうか。ハンデだけを設定したプレイヤーの記録がこうなる
+    pub fn is_empty(&self) -> bool {
+        return self.win == 0 && self.lose == 0;
+    }
+}
+
+/// あるプレイヤーの、特定の相手との勝敗
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct HeadToHead {
+    /// プレイヤーの Slack のユーザー id（bot の勝敗では bot の id）
+    pub id: String,
+    /// プレイヤーの表示名。勝敗を登録するたびに新しい名前に更新する
+    pub name: String,
+    /// 相手の Slack のユーザー id
+    pub opponent_id: String,
+    /// 相手の表示名。勝敗を登録するたびに新しい名前に更新する
+    pub opponent_name: String,
+    pub win: i32,
+    pub lose: i32,
+}
+
+impl HeadToHead {
+    pub fn new(id: &str, name: &str, opponent_id: &str, opponent_name: &str) -> HeadToHead {
+        return HeadToHead {
+            id: id.to_string(),
+            name: name.to_string(),
+            opponent_id: opponent_id.to_string(),
+            opponent_name: opponent_name.to_string(),
+            win: 0,
+            lose: 0,
         };
     }
 }
 
+/// 表示名から Slack のユーザー id への対応表
+/// 表示名をキーにして記録していた頃のデータを、id をキーにして記録し直すときに使う
+pub type PlayerIds = BTreeMap<String, String>;
+
+/// player の id が空なら、表示名から引いた id を入れる
+fn assign_player_id(player: &mut Player, ids: &PlayerIds) {
+    if player.id.is_empty() {
+        if let Some(id) = ids.get(&player.name) {
+            player.id = id.clone();
+        }
+    }
+}
+
+/// ゲームログに出てくるプレイヤーに id を入れる
+fn assign_log_player_ids(logs: &mut Vec<GameLog>, ids: &PlayerIds) {
+    for log in logs.iter_mut() {
+        assign_player_id(&mut log.player1, ids);
+        assign_player_id(&mut log.player2, ids);
+    }
+}
+
+impl Progress {
+    /// 表示名で記録していた頃の進行状態に、プレイヤーの id を入れる
+    pub fn assign_player_ids(&mut self, ids: &PlayerIds) {
+        assign_player_id(&mut self.user, ids);
+    }
+}
+
+impl Replay {
+    /// 表示名で記録していた頃のリプレイに、プレイヤーの id を入れる
+    /// 再実行したときのログと一致するように、記録済みのログにも入れる
+    pub fn assign_player_ids(&mut self, ids: &PlayerIds) {
+        self.initial.assign_player_ids(ids);
+        for step in self.steps.iter_mut() {
+            assign_log_player_ids(&mut step.logs, ids);
+        }
+    }
+}
+
+impl MatchProgress {
+    /// 表示名で記録していた頃の対戦に、プレイヤーの id を入れる
+    pub fn assign_player_ids(&mut self, ids: &PlayerIds) {
+        assign_player_id(&mut self.player1, ids);
+        assign_player_id(&mut self.player2, ids);
+        assign_log_player_ids(&mut self.logs, ids);
+    }
+}
+
+impl Challenge {
+    /// 表示名で記録していた頃の挑戦に、プレイヤーの id を入れる
+    pub fn assign_player_ids(&mut self, ids: &PlayerIds) {
+        if self.challenger_id.is_empty() {
+            self.challenger_id = ids.get(&self.challenger).cloned().unwrap_or_default();
+        }
+        if self.challenged_id.is_empty() {
+            self.challenged_id = ids.get(&self.challenged).cloned().unwrap_or_default();
+        }
+    }
+}
+
+impl TableProgress {
+    /// 表示名で記録していた頃の卓に、プレイヤーの id を入れる
+    pub fn assign_player_ids(&mut self, ids: &PlayerIds) {
+        for seat in self.seats.iter_mut() {
+            assign_player_id(&mut seat.player, ids);
+        }
+    }
+}
+
+impl TeamMatchProgress {
+    /// 表示名で記録していた頃のチーム戦に、メンバーの id を入れる
+    pub fn assign_player_ids(&mut self, ids: &PlayerIds) {
+        for team in [&mut self.genji, &mut self.heike].iter_mut() {
+            for member in team.members.iter_mut() {
+                member.assign_player_ids(ids);
+            }
+        }
+    }
+}
+
+impl Contribution {
+    /// 表示名で記録していた頃の貢献度に、メンバーの id を入れる
+    pub fn assign_player_ids(&mut self, ids: &PlayerIds) {
+        if self.id.is_empty() {
+            self.id = ids.get(&self.name).cloned().unwrap_or_default();
+        }
+    }
+}
+
+impl GameHistory {
+    /// 表示名で記録していた頃の履歴に、プレイヤーの id を入れる
+    pub fn assign_player_ids(&mut self, ids: &PlayerIds) {
+        if self.player_ids.is_empty() {
+            self.player_ids = self
+                .players
+                .iter()
+                .filter_map(|name| ids.get(name).cloned())
+                .collect();
+        }
+        if self.winner_id.is_empty() {
+            self.winner_id = ids.get(&self.winner).cloned().unwrap_or_default();
+        }
+        assign_log_player_ids(&mut self.logs, ids);
+    }
+
+    /// players の index 番目のプレイヤーが勝ったかどうか
+    /// 勝者の id を記録する前に終わったゲームでは、名前で比べる
+    pub fn is_winner(&self, index: usize) -> bool {
+        if self.winner_id.is_empty() {
+            return self.players.get(index) == Some(&self.winner);
+        }
+        return self.player_ids.get(index) == Some(&self.winner_id);
+    }
+}
+
+impl WinLose {
+    /// 表示名で記録していた頃の勝敗に、プレイヤーの id を入れる
+    pub fn assign_player_ids(&mut self, ids: &PlayerIds) {
+        if self.id.is_empty() {
+            self.id = ids.get(&self.name).cloned().unwrap_or_default();
+        }
+    }
+}
+
 pub trait ScoreOperator {
-    /// player_name で指定されたプレイヤーの情報を取得する。スコアがまだなかった場合は、None になる
-    fn get_progress(&mut self, player_name: &str) -> Option<Progress>;
-    /// progress で指定されたプレイヤーの情報を登録する。すでに登録済みの場合は、上書きされる
-    fn insert_progress(&mut self, progress: &Progress) -> bool;
-    /// player_name で指定されたプレイヤーの情報を削除する。
-    fn delete_progress(&mut self, player_name: &str) -> bool;
-    /// player_name で指定されたプレイヤーの勝敗を登録する。
-    fn update_winloses(&mut self, player_name: &str, is_player_win: bool) -> bool;
-    /// 過去の旗源平の勝敗記録を表示する
+    /// game_id で指定されたゲームの、player_id で指定されたプレイヤーの進行状態（シリアライズしたもの）を取得する
+    /// bot と遊ぶ DiceGame（旗源平も含む）は、このメソッドで進行状態を保存する
+    fn get_game_progress(&mut self, game_id: &str, player_id: &str) -> Option<String>;
+    /// game_id で指定されたゲームの、進行中のすべての進行状態（シリアライズしたもの）を取得する
+    fn get_game_progresses(&self, game_id: &str) -> Vec<String>;
+    /// game_id で指定されたゲームの進行状態を登録する。すでに登録済みの場合は、上書きされる
+    fn insert_game_progress(&mut self, game_id: &str, player_id: &str, data: &str) -> bool;
+    /// game_id で指定されたゲームの、player_id で指定されたプレイヤーの進行状態を削除する
+    fn delete_game_progress(&mut self, game_id: &str, player_id: &str) -> bool;
+    /// player_id で指定されたプレイヤーの勝敗を登録する。表示名は player_name に更新する
+    fn update_winloses(&mut self, player_id: &str, player_name: &str, is_player_win: bool) -> bool;
+    /// 過去の旗源平の勝敗記録を表示する。ハンデだけを設定した、まだ勝敗のない記録は含めない
     fn get_win_loses(&self) -> Vec<WinLose>;
+    /// player_id で指定されたプレイヤーの勝敗記録を取得する。記録がまだなかった場合は、None になる
+    fn get_win_lose(&mut self, player_id: &str) -> Option<WinLose>;
+    /// player_id で指定されたプレイヤーのハンデを設定する。None なら勝率から自動で決める
+    /// まだ勝敗のないプレイヤーには 0 勝 0 敗の記録を作ってハンデを保存する（get_win_loses には含めない）
+    /// ハンデが設定できる範囲にない場合は、何もせずに false を返す
+    fn set_handicap(&mut self, player_id: &str, player_name: &str, handicap: Option<i32>) -> bool;
+    /// player_id で指定されたプレイヤーの、進行中のゲームのリプレイを取得する
+    fn get_replay(&mut self, player_id: &str) -> Option<Replay>;
+    /// 進行中のゲームのリプレイを登録する。すでに登録済みの場合は、上書きされる
+    fn insert_replay(&mut self, replay: &Replay) -> bool;
+    /// player_id で指定されたプレイヤーの進行中のリプレイを、終了したゲームのリプレイとして保存する
+    fn finish_replay(&mut self, player_id: &str) -> bool;
+    /// player_id で指定されたプレイヤーの進行中のリプレイを、終了したゲームとして保存せずに削除する
+    fn delete_replay(&mut self, player_id: &str) -> bool;
+    /// player_id で指定されたプレイヤーの、直近に終了したゲームのリプレイを取得する。なければ None になる
+    fn get_latest_finished_replay(&mut self, player_id: &str) -> Option<Replay>;
+    /// player_id で指定されたプレイヤーが参加している、人間同士の対戦の情報を取得する
+    fn get_match_progress(&mut self, player_id: &str) -> Option<MatchProgress>;
+    /// 人間同士の対戦の情報を登録する。同じ2人の対戦がすでに登録済みの場合は、上書きされる
+    fn insert_match_progress(&mut self, match_progress: &MatchProgress) -> bool;
+    /// player_id で指定されたプレイヤーが参加している、人間同士の対戦の情報を削除する
+    fn delete_match_progress(&mut self, player_id: &str) -> bool;
+    /// challenged_id で指定されたプレイヤーへの挑戦を取得する
+    fn get_challenge(&mut self, challenged_id: &str) -> Option<Challenge>;
+    /// 挑戦を登録する。同じプレイヤーへの挑戦がすでに登録済みの場合は、上書きされる
+    fn insert_challenge(&mut self, challenge: &Challenge) -> bool;
+    /// challenged_id で指定されたプレイヤーへの挑戦を削除する
+    fn delete_challenge(&mut self, challenged_id: &str) -> bool;
+    /// channel で指定されたチャンネルの卓を取得する
+    fn get_table(&mut self, channel: &str) -> Option<TableProgress>;
+    /// 卓を登録する。同じチャンネルの卓がすでに登録済みの場合は、上書きされる
+    fn insert_table(&mut self, table: &TableProgress) -> bool;
+    /// channel で指定されたチャンネルの卓を削除する
+    fn delete_table(&mut self, channel: &str) -> bool;
+    /// channel で指定されたチャンネルのチーム戦を取得する
+    fn get_team_match(&mut self, channel: &str) -> Option<TeamMatchProgress>;
+    /// チーム戦を登録する。同じチャンネルのチーム戦がすでに登録済みの場合は、上書きされる
+    fn insert_team_match(&mut self, team_match: &TeamMatchProgress) -> bool;
+    /// channel で指定されたチャンネルのチーム戦を削除する
+    fn delete_team_match(&mut self, channel: &str) -> bool;
+    /// side で指定された陣営の勝敗を登録する
+    fn update_team_winloses(&mut self, side: Side, is_side_win: bool) -> bool;
+    /// 過去のチーム戦の、陣営ごとの勝敗記録を取得する
+    fn get_team_win_loses(&self) -> Vec<WinLose>;
+    /// contribution で指定されたメンバーの貢献度を、これまでの記録に足し合わせる（メンバーの id ごとに記録する）
+    fn update_contribution(&mut self, contribution: &Contribution) -> bool;
+    /// 過去のチーム戦の、メンバーごとの貢献度を取得する
+    fn get_contributions(&self) -> Vec<Contribution>;
+    /// persona_name で指定された bot の、模範試合の勝敗を登録する
+    fn update_exhibition_winloses(&mut self, persona_name: &str, is_persona_win: bool) -> bool;
+    /// 過去の模範試合の、bot ごとの勝敗記録を取得する
+    fn get_exhibition_win_loses(&self) -> Vec<WinLose>;
+    /// 終了したゲームの記録を、参加したプレイヤー全員の履歴として登録する
+    /// bot_id のプレイヤー（bot）の履歴は登録しない
+    fn insert_game_history(&mut self, history: &GameHistory, bot_id: &str) -> bool;
+    /// player_id で指定されたプレイヤーが参加した、終了したゲームの記録を古い順に取得する
+    fn get_game_histories(&mut self, player_id: &str) -> Vec<GameHistory>;
+    /// player_id で指定されたプレイヤーが参加した、終了したゲームの記録を新しい順に count 件まで取得する
+    fn get_recent_game_histories(&mut self, player_id: &str, count: usize) -> Vec<GameHistory>;
+    /// player_id のプレイヤーの、opponent_id の相手との勝敗を登録する（相手から見た勝敗は登録しない）
+    fn update_head_to_head(
+        &mut self,
+        player_id: &str,
+        player_name: &str,
+        opponent_id: &str,
+        opponent_name: &str,
+        is_player_win: bool,
+    ) -> bool;
+    /// player_id のプレイヤーの、相手ごとの勝敗を取得する
+    fn get_head_to_heads(&self, player_id: &str) -> Vec<HeadToHead>;
+    /// 相手ごとの勝敗をそのまま登録する。すでに登録済みの場合は、上書きされる
+    fn insert_head_to_head(&mut self, head_to_head: &HeadToHead) -> bool;
+    /// ゲームが終わった後のレーティングを、プレイヤーのレーティングの履歴に追加する
+    fn insert_rating(&mut self, record: &RatingRecord) -> bool;
+    /// player_id で指定されたプレイヤーのレーティングの履歴を古い順に取得する
+    fn get_ratings(&mut self, player_id: &str) -> Vec<RatingRecord>;
+    /// 全プレイヤーの、最新のレーティングを取得する
+    fn get_latest_ratings(&self) -> Vec<RatingRecord>;
+    /// 表示名をキーにして記録していたデータを、ids で引いた Slack のユーザー id をキーにして記録し直す
+    /// すでに id で記録しているデータはそのままにするので、何度実行してもよい
+    fn migrate_player_ids(&mut self, ids: &PlayerIds) -> bool;
 }
@@ -3,8 +3,25 @@ use super::*;
 // TODO: このあたりの設定は https://docs.rs/config/0.9.3/config/ を使って、Settings.toml から指定できるようにしたい
 const DB_HATAGENPEI_PROGRESS_KEY: &str = "hatagenpei_progress";
 const DB_HATAGENPEI_WINLOSES_KEY: &str = "hatagenpei_winloses";
+const DB_HATAGENPEI_REPLAYS_KEY: &str = "hatagenpei_replays";
+const DB_HATAGENPEI_FINISHED_REPLAYS_KEY: &str = "hatagenpei_finished_replays";
+const DB_HATAGENPEI_MATCHES_KEY: &str = "hatagenpei_matches";
+const DB_HATAGENPEI_CHALLENGES_KEY: &str = "hatagenpei_challenges";
+const DB_HATAGENPEI_TABLES_KEY: &str = "hatagenpei_tables";
+const DB_HATAGENPEI_TEAM_MATCHES_KEY: &str = "hatagenpei_team_matches";
+const DB_HATAGENPEI_TEAM_WINLOSES_KEY: &str = "hatagenpei_team_winloses";
+const DB_HATAGENPEI_CONTRIBUTIONS_KEY: &str = "hatagenpei_contributions";
+const DB_HATAGENPEI_GAME_HISTORIES_KEY: &str = "hatagenpei_game_histories";
+const DB_HATAGENPEI_EXHIBITION_WINLOSES_KEY: &str = "hatagenpei_exhibition_winloses";
+const DB_GAME_PROGRESSES_KEY: &str = "game_progresses";
+const DB_HATAGENPEI_RATINGS_KEY: &str = "hatagenpei_ratings";
+const DB_HATAGENPEI_HEAD_TO_HEADS_KEY: &str = "hatagenpei_head_to_heads";
 
+use super::super::dice_game::DiceGame;
+use super::super::game::Hatagenpei;
 use postgres::{Client};
+use serde::de::DeserializeOwned;
+use log::info;
 use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
 use postgres_openssl::MakeTlsConnector;
 
@@ -25,7 +42,8 @@ impl ScoresInPostgre {
         // postgre に接続
         let mut client = Self::make_client(&postgre_uri[..]);
 
-        // progress管理テーブル作成
+        // 以前の旗源平の progress管理テーブル作成（name はプレイヤーの Slack のユーザー id。以降のテーブルも特に書いていなければ同じ）
+        // 今は game_progresses に保存するので、残っている行を移すためだけに使う
         let create_progress_table_query = format!(
             "CREATE TABLE IF NOT EXISTS {} (
                     name            VARCHAR NOT NULL,
@@ -49,85 +67,313 @@ impl ScoresInPostgre {
             .execute(&create_winlose_table_query[..], &[])
             .expect("failed to create winlose table");
 
+        // 進行中のゲームのリプレイ管理テーブル作成
+        let create_replay_table_query = format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                    name            VARCHAR NOT NULL,
+                    data            VARCHAR NOT NULL
+                  )",
+            DB_HATAGENPEI_REPLAYS_KEY
+        );
+        client
+            .execute(&create_replay_table_query[..], &[])
+            .expect("failed to create replay table");
+
+        // 終了したゲームのリプレイ管理テーブル作成（id で保存した順番がわかるようにする）
+        let create_finished_replay_table_query = format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                    id              SERIAL PRIMARY KEY,
+                    name            VARCHAR NOT NULL,
+                    data            VARCHAR NOT NULL
+                  )",
+            DB_HATAGENPEI_FINISHED_REPLAYS_KEY
+        );
+        client
+            .execute(&create_finished_replay_table_query[..], &[])
+            .expect("failed to create finished replay table");
+
+        // 人間同士の対戦の管理テーブル作成（どちらのプレイヤーからも引けるように、2人の id を持つ）
+        let create_match_table_query = format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                    player1         VARCHAR NOT NULL,
+                    player2         VARCHAR NOT NULL,
+                    data            VARCHAR NOT NULL
+                  )",
+            DB_HATAGENPEI_MATCHES_KEY
+        );
+        client
+            .execute(&create_match_table_query[..], &[])
+            .expect("failed to create match table");
+
+        // 挑戦の管理テーブル作成（name は挑戦を受けたプレイヤーの id）
+        let create_challenge_table_query = format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                    name            VARCHAR NOT NULL,
+                    data            VARCHAR NOT NULL
+                  )",
+            DB_HATAGENPEI_CHALLENGES_KEY
+        );
+        client
+            .execute(&create_challenge_table_query[..], &[])
+            .expect("failed to create challenge table");
+
+        // 3人以上で遊ぶ卓の管理テーブル作成（name はチャンネルの id）
+        let create_table_table_query = format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                    name            VARCHAR NOT NULL,
+                    data            VARCHAR NOT NULL
+                  )",
+            DB_HATAGENPEI_TABLES_KEY
+        );
+        client
+            .execute(&create_table_table_query[..], &[])
+            .expect("failed to create table table");
+
+        // チーム戦の管理テーブル作成（name はチャンネルの id）
+        let create_team_match_table_query = format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                    name            VARCHAR NOT NULL,
+                    data            VARCHAR NOT NULL
+                  )",
+            DB_HATAGENPEI_TEAM_MATCHES_KEY
+        );
+        client
+            .execute(&create_team_match_table_query[..], &[])
+            .expect("failed to create team match table");
+
+        // チーム戦の陣営ごとの勝敗の管理テーブル作成（name は陣営の名前）
+        let create_team_winlose_table_query = format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                    name            VARCHAR NOT NULL,
+                    data            VARCHAR NOT NULL
+                  )",
+            DB_HATAGENPEI_TEAM_WINLOSES_KEY
+        );
+        client
+            .execute(&create_team_winlose_table_query[..], &[])
+            .expect("failed to create team winlose table");
+
+        // チーム戦のメンバーごとの貢献度の管理テーブル作成
+        let create_contribution_table_query = format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                    name            VARCHAR NOT NULL,
+                    data            VARCHAR NOT NULL
+                  )",
+            DB_HATAGENPEI_CONTRIBUTIONS_KEY
+        );
+        client
+            .execute(&create_contribution_table_query[..], &[])
+            .expect("failed to create contribution table");
+
+        // 終了したゲームの履歴の管理テーブル作成（参加したプレイヤーごとに1行。id で保存した順番がわかるようにする）
+        let create_game_history_table_query = format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                    id              SERIAL PRIMARY KEY,
+                    name            VARCHAR NOT NULL,
+                    data            VARCHAR NOT NULL
+                  )",
+            DB_HATAGENPEI_GAME_HISTORIES_KEY
+        );
+        client
+            .execute(&create_game_history_table_query[..], &[])
+            .expect("failed to create game history table");
+
+        // 模範試合の bot ごとの勝敗の管理テーブル作成（人間の勝敗とは分けておく）
+        let create_exhibition_winlose_table_query = format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                    name            VARCHAR NOT NULL,
+                    data            VARCHAR NOT NULL
+                  )",
+            DB_HATAGENPEI_EXHIBITION_WINLOSES_KEY
+        );
+        client
+            .execute(&create_exhibition_winlose_table_query[..], &[])
+            .expect("failed to create exhibition winlose table");
+
+        // 旗源平以外の DiceGame の進行状態の管理テーブル作成（name は game_progress_name で作る）
+        let create_game_progress_table_query = format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                    name            VARCHAR NOT NULL,
+                    data            VARCHAR NOT NULL
+                  )",
+            DB_GAME_PROGRESSES_KEY
+        );
+        client
+            .execute(&create_game_progress_table_query[..], &[])
+            .expect("failed to create game progress table");
+
+        // 旗源平の進行状態も game_progresses に保存するようになったので、以前のテーブルに残っている行を移す
+        let move_progress_query = format!(
+            "INSERT INTO {} (name, data) SELECT $1 || name, data FROM {}",
+            DB_GAME_PROGRESSES_KEY, DB_HATAGENPEI_PROGRESS_KEY
+        );
+        let delete_progress_query = format!("DELETE FROM {}", DB_HATAGENPEI_PROGRESS_KEY);
+        let mut transaction = client.transaction().expect("failed to begin transaction");
+        let moved = transaction
+            .execute(&move_progress_query[..], &[&Self::game_progress_name(Hatagenpei::GAME_ID, "")])
+            .expect("failed to insert query for moving progress");
+        transaction
+            .execute(&delete_progress_query[..], &[])
+            .expect("failed to delete query for moving progress");
+        transaction.commit().expect("failed to commit moving progress");
+        if moved > 0 {
+            info!("moved {} hatagenpei progress rows to {}", moved, DB_GAME_PROGRESSES_KEY);
+        }
+
+        // レーティングの履歴の管理テーブル作成（id で保存した順番がわかるようにする）
+        let create_rating_table_query = format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                    id              SERIAL PRIMARY KEY,
+                    name            VARCHAR NOT NULL,
+                    data            VARCHAR NOT NULL
+                  )",
+            DB_HATAGENPEI_RATINGS_KEY
+        );
+        client
+            .execute(&create_rating_table_query[..], &[])
+            .expect("failed to create rating table");
+
+        // プレイヤーの、相手ごとの勝敗の管理テーブル作成（player がプレイヤーの id、opponent が相手の id）
+        let create_head_to_head_table_query = format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                    player          VARCHAR NOT NULL,
+                    opponent        VARCHAR NOT NULL,
+                    data            VARCHAR NOT NULL
+                  )",
+            DB_HATAGENPEI_HEAD_TO_HEADS_KEY
+        );
+        client
+            .execute(&create_head_to_head_table_query[..], &[])
+            .expect("failed to create head to head table");
+
         return ScoresInPostgre {
             postgre_uri: postgre_uri.clone(),
         };
     }
 
-}
-
-impl ScoreOperator for ScoresInPostgre {
-    fn get_progress(&mut self, player_name: &str) -> Option<Progress> {
-        // postgre に接続
+    /// table から name に一致する行の data を取得する。複数ある場合でも、1つだけ返す
+    fn select_data(&self, table: &str, name: &str) -> Option<String> {
         let mut client = Self::make_client(&self.postgre_uri[..]);
-        // 見つからなかった場合は、insert を実行する
-        let select_query = format!(
-            "SELECT name, data FROM {} where name = $1",
-            DB_HATAGENPEI_PROGRESS_KEY
-        );
+        let select_query = format!("SELECT name, data FROM {} where name = $1", table);
         let res = client
-            .query(&select_query[..], &[&player_name])
-            .expect("failed to select query for get_progress");
+            .query(&select_query[..], &[&name])
+            .expect("failed to select query for select_data");
+        return res.get(0).map(|r| r.get(1));
+    }
 
-        if res.len() == 0 {
-            return None;
+    /// table の name に一致する行の data を更新する。行が存在しない場合は insert する
+    fn upsert_data(&self, table: &str, name: &str, data: &str) {
+        let mut client = Self::make_client(&self.postgre_uri[..]);
+        let update_query = format!("UPDATE {} SET data = $1 WHERE name = $2", table);
+        let updated = client
+            .execute(&update_query[..], &[&data, &name])
+            .expect("failed to update query for upsert_data");
+        if updated == 0 {
+            let insert_query = format!("INSERT INTO {} (name, data) VALUES ($1, $2)", table);
+            client
+                .execute(&insert_query[..], &[&name, &data])
+                .expect("failed to insert query for upsert_data");
         }
+    }
 
-        // 複数ある場合でも、1つだけ返す
-        let r = res.get(0);
-        let data: String = r.expect("failed to get data in HATAGENPEI_PROGRESS").get(1);
-        let progress = serde_json::from_str(&data[..]).expect("failed to serde_json::from_str");
-        return Some(progress);
+    /// table から name に一致する行を削除する
+    fn delete_data(&self, table: &str, name: &str) {
+        let mut client = Self::make_client(&self.postgre_uri[..]);
+        let delete_query = format!("DELETE FROM {} where name = $1", table);
+        client
+            .execute(&delete_query[..], &[&name])
+            .expect("failed to delete query for delete_data");
     }
 
-    fn insert_progress(&mut self, progress: &Progress) -> bool {
-        // postgre に接続
+    /// game_progresses テーブルの name。ゲームごとに分けるため、game_id とプレイヤーの id をつなげる
+    fn game_progress_name(game_id: &str, player_id: &str) -> String {
+        return format!("{}/{}", game_id, player_id);
+    }
+
+    /// table のすべての行の data を取得する
+    fn select_all_data(&self, table: &str) -> Vec<String> {
         let mut client = Self::make_client(&self.postgre_uri[..]);
+        let select_query = format!("SELECT name, data FROM {}", table);
+        let res = client
+            .query(&select_query[..], &[])
+            .expect("failed to select query for select_all_data");
+        return res.iter().map(|r| r.get(1)).collect();
+    }
 
-        // すでに要素が存在している場合は、SQL update
-        // そうでない場合は SQL insert を行う
-        let select_query = format!(
-            "SELECT name, data FROM {} where name = $1",
-            DB_HATAGENPEI_PROGRESS_KEY
-        );
+    /// table のすべての行について、data を assign で書き換え、name を rename で書き換える
+    /// 表示名で記録していた行を id で記録し直すのに使う。書き換えた行の数を返す
+    /// merge は name ごとに 1 行だけの表で指定する。書き換え先の name の行（id で記録し始めた後の行）がすでにあれば、
+    /// 表示名の行を merge でその行に足し合わせてから削除し、同じ name の行が 2 つできないようにする
+    /// 履歴のように name ごとに何行もある表では None にして、そのまま name を書き換える
+    fn migrate_rows<T, R, A>(&self, table: &str, rename: R, assign: A, merge: Option<&dyn Fn(&mut T, &T)>) -> u64
+    where
+        T: Serialize + DeserializeOwned,
+        R: Fn(&str) -> String,
+        A: Fn(&mut T),
+    {
+        let mut client = Self::make_client(&self.postgre_uri[..]);
+        let select_query = format!("SELECT name, data FROM {}", table);
         let res = client
-            .query(&select_query[..], &[&&progress.user.name[..]])
-            .expect("failed to select query for insert_progress");
+            .query(&select_query[..], &[])
+            .expect("failed to select query for migrate_rows");
 
-        let jsonstr = serde_json::to_string(&progress).expect("failed to serde_json::to_string");
-        if res.len() == 0 {
-            // insert
-            let insert_query = format!(
-                "INSERT INTO {} (name, data) VALUES ($1, $2)",
-                DB_HATAGENPEI_PROGRESS_KEY
-            );
-            client
-                .execute(&insert_query[..], &[&&progress.user.name[..], &jsonstr])
-                .expect("failed to insert query for insert_progress");
-        } else {
-            // update
-            let update_query = format!(
-                "UPDATE {} SET data = $1 WHERE name = $2",
-                DB_HATAGENPEI_PROGRESS_KEY
-            );
-            client
-                .execute(&update_query[..], &[&jsonstr, &&progress.user.name[..]])
-                .expect("failed to update query for insert_progress");
+        let update_query = format!("UPDATE {} SET name = $1, data = $2 WHERE name = $3 AND data = $4", table);
+        let delete_query = format!("DELETE FROM {} WHERE name = $1 AND data = $2", table);
+        let mut migrated = 0;
+        for row in &res {
+            let name: String = row.get(0);
+            let data: String = row.get(1);
+            let mut value: T = serde_json::from_str(&data[..]).expect("failed to serde_json::from_str");
+            assign(&mut value);
+            let new_name = rename(&name);
+            let new_data = serde_json::to_string(&value).expect("failed to serde_json::to_string");
+            if new_name == name && new_data == data {
+                continue;
+            }
+            if let Some(merge) = merge {
+                if new_name != name {
+                    if let Some(existing) = self.select_data(table, &new_name) {
+                        let mut existing: T = serde_json::from_str(&existing[..]).expect("failed to serde_json::from_str");
+                        merge(&mut existing, &value);
+                        let merged = serde_json::to_string(&existing).expect("failed to serde_json::to_string");
+                        self.upsert_data(table, &new_name, &merged);
+                        migrated += client
+                            .execute(&delete_query[..], &[&name, &data])
+                            .expect("failed to delete query for migrate_rows");
+                        continue;
+                    }
+                }
+            }
+            migrated += client
+                .execute(&update_query[..], &[&new_name, &new_data, &name, &data])
+                .expect("failed to update query for migrate_rows");
         }
-        return true;
+        return migrated;
     }
 
-    fn delete_progress(&mut self, player_name: &str) -> bool {
-        // postgre に接続
-        let mut client = Self::make_client(&self.postgre_uri[..]);
-        let delete_query = format!("DELETE FROM {} where name = $1", DB_HATAGENPEI_PROGRESS_KEY);
-        client
-            .execute(&delete_query[..], &[&player_name])
-            .expect("failed to delete query for delete_progress");
+}
 
+impl ScoreOperator for ScoresInPostgre {
+    fn get_game_progress(&mut self, game_id: &str, player_id: &str) -> Option<String> {
+        return self.select_data(DB_GAME_PROGRESSES_KEY, &Self::game_progress_name(game_id, player_id));
+    }
+    fn get_game_progresses(&self, game_id: &str) -> Vec<String> {
+        let mut client = Self::make_client(&self.postgre_uri[..]);
+        let select_query = format!("SELECT name, data FROM {} where name LIKE $1", DB_GAME_PROGRESSES_KEY);
+        let res = client
+            .query(&select_query[..], &[&Self::game_progress_name(game_id, "%")])
+            .expect("failed to select query for get_game_progresses");
+        return res.iter().map(|r| r.get(1)).collect();
+    }
+    fn insert_game_progress(&mut self, game_id: &str, player_id: &str, data: &str) -> bool {
+        self.upsert_data(DB_GAME_PROGRESSES_KEY, &Self::game_progress_name(game_id, player_id), data);
+        return true;
+    }
+    fn delete_game_progress(&mut self, game_id: &str, player_id: &str) -> bool {
+        self.delete_data(DB_GAME_PROGRESSES_KEY, &Self::game_progress_name(game_id, player_id));
         return true;
     }
-    fn update_winloses(&mut self, player_name: &str, is_player_win: bool) -> bool {
+    fn update_winloses(&mut self, player_id: &str, player_name: &str, is_player_win: bool) -> bool {
         // postgre に接続
         let mut client = Self::make_client(&self.postgre_uri[..]);
 
@@ -137,16 +383,18 @@ impl ScoreOperator for ScoresInPostgre {
             DB_HATAGENPEI_WINLOSES_KEY
         );
         let res = client
-            .query(&select_query[..], &[&player_name])
+            .query(&select_query[..], &[&player_id])
             .expect("failed to select query for update_winloses");
 
-        let mut win_lose = if res.len() == 0 {
+        let mut win_lose: WinLose = if res.len() == 0 {
             WinLose::new(0, 0, player_name)
         } else {
             let r = res.get(0);
             let data: String = r.expect("failed to get data in HATAGENPEI_WINLOSES").get(1);
             serde_json::from_str(&data[..]).expect("failed to serde_json::from_str")
         };
+        win_lose.id = player_id.to_string();
+        win_lose.name = player_name.to_string();
 
         if is_player_win {
             win_lose.win += 1;
@@ -163,7 +411,7 @@ impl ScoreOperator for ScoresInPostgre {
                 DB_HATAGENPEI_WINLOSES_KEY
             );
             client
-                .execute(&insert_query[..], &[&player_name, &s])
+                .execute(&insert_query[..], &[&player_id, &s])
                 .expect("failed to insert query for update_winloses");
         }
         // update
@@ -173,7 +421,7 @@ impl ScoreOperator for ScoresInPostgre {
                 DB_HATAGENPEI_WINLOSES_KEY
             );
             client
-                .execute(&update_query[..], &[&s, &player_name])
+                .execute(&update_query[..], &[&s, &player_id])
                 .expect("failed to update query for update_winloses");
         }
 
@@ -195,9 +443,545 @@ impl ScoreOperator for ScoresInPostgre {
 
         for row in &query_result {
             let data: String = row.get(1);
-            let win_lose = serde_json::from_str(&data[..]).expect("failed to serde_json::from_str");
-            res.push(win_lose);
+            let win_lose: WinLose = serde_json::from_str(&data[..]).expect("failed to serde_json::from_str");
+            if !win_lose.is_empty() {
+                res.push(win_lose);
+            }
+        }
+        return res;
+    }
+
+    fn get_win_lose(&mut self, player_id: &str) -> Option<WinLose> {
+        let data = self.select_data(DB_HATAGENPEI_WINLOSES_KEY, player_id)?;
+        let win_lose = serde_json::from_str(&data[..]).expect("failed to serde_json::from_str");
+        return Some(win_lose);
+    }
+
+    fn set_handicap(&mut self, player_id: &str, player_name: &str, handicap: Option<i32>) -> bool {
+        if !is_valid_handicap(handicap) {
+            return false;
+        }
+        let mut win_lose = match self.get_win_lose(player_id) {
+            Some(win_lose) => win_lose,
+            None => WinLose::new(0, 0, player_name),
+        };
+        win_lose.id = player_id.to_string();
+        win_lose.name = player_name.to_string();
+        win_lose.handicap = handicap;
+        // 勝敗もハンデもない記録は残さない
+        if win_lose.is_empty() && win_lose.handicap.is_none() {
+            self.delete_data(DB_HATAGENPEI_WINLOSES_KEY, player_id);
+            return true;
+        }
+        let jsonstr = serde_json::to_string(&win_lose).expect("failed to serde_json::to_string");
+        self.upsert_data(DB_HATAGENPEI_WINLOSES_KEY, player_id, &jsonstr);
+        return true;
+    }
+
+    fn get_replay(&mut self, player_id: &str) -> Option<Replay> {
+        let data = self.select_data(DB_HATAGENPEI_REPLAYS_KEY, player_id)?;
+        let replay = serde_json::from_str(&data[..]).expect("failed to serde_json::from_str");
+        return Some(replay);
+    }
+
+    fn insert_replay(&mut self, replay: &Replay) -> bool {
+        let jsonstr = serde_json::to_string(&replay).expect("failed to serde_json::to_string");
+        self.upsert_data(DB_HATAGENPEI_REPLAYS_KEY, &replay.initial.user.id, &jsonstr);
+        return true;
+    }
+
+    fn finish_replay(&mut self, player_id: &str) -> bool {
+        let data = match self.select_data(DB_HATAGENPEI_REPLAYS_KEY, player_id) {
+            Some(data) => data,
+            None => return true,
+        };
+        let mut client = Self::make_client(&self.postgre_uri[..]);
+        let insert_query = format!(
+            "INSERT INTO {} (name, data) VALUES ($1, $2)",
+            DB_HATAGENPEI_FINISHED_REPLAYS_KEY
+        );
+        client
+            .execute(&insert_query[..], &[&player_id, &data])
+            .expect("failed to insert query for finish_replay");
+        self.delete_data(DB_HATAGENPEI_REPLAYS_KEY, player_id);
+        return true;
+    }
+
+    fn delete_replay(&mut self, player_id: &str) -> bool {
+        self.delete_data(DB_HATAGENPEI_REPLAYS_KEY, player_id);
+        return true;
+    }
+
+    fn get_latest_finished_replay(&mut self, player_id: &str) -> Option<Replay> {
+        let mut client = Self::make_client(&self.postgre_uri[..]);
+        let select_query = format!(
+            "SELECT name, data FROM {} where name = $1 ORDER BY id DESC LIMIT 1",
+            DB_HATAGENPEI_FINISHED_REPLAYS_KEY
+        );
+        let query_result = client
+            .query(&select_query[..], &[&player_id])
+            .expect("failed to select query for get_latest_finished_replay");
+
+        let data: String = query_result.get(0)?.get(1);
+        let replay = serde_json::from_str(&data[..]).expect("failed to serde_json::from_str");
+        return Some(replay);
+    }
+
+    fn get_match_progress(&mut self, player_id: &str) -> Option<MatchProgress> {
+        let mut client = Self::make_client(&self.postgre_uri[..]);
+        let select_query = format!(
+            "SELECT player1, player2, data FROM {} where player1 = $1 OR player2 = $1",
+            DB_HATAGENPEI_MATCHES_KEY
+        );
+        let res = client
+            .query(&select_query[..], &[&player_id])
+            .expect("failed to select query for get_match_progress");
+
+        // 複数ある場合でも、1つだけ返す
+        let data: String = res.get(0)?.get(2);
+        let match_progress = serde_json::from_str(&data[..]).expect("failed to serde_json::from_str");
+        return Some(match_progress);
+    }
+
+    fn insert_match_progress(&mut self, match_progress: &MatchProgress) -> bool {
+        let mut client = Self::make_client(&self.postgre_uri[..]);
+        let jsonstr = serde_json::to_string(&match_progress).expect("failed to serde_json::to_string");
+        let player1 = &match_progress.player1.id[..];
+        let player2 = &match_progress.player2.id[..];
+
+        let update_query = format!(
+            "UPDATE {} SET data = $1 WHERE player1 = $2 AND player2 = $3",
+            DB_HATAGENPEI_MATCHES_KEY
+        );
+        let updated = client
+            .execute(&update_query[..], &[&jsonstr, &player1, &player2])
+            .expect("failed to update query for insert_match_progress");
+        if updated == 0 {
+            let insert_query = format!(
+                "INSERT INTO {} (player1, player2, data) VALUES ($1, $2, $3)",
+                DB_HATAGENPEI_MATCHES_KEY
+            );
+            client
+                .execute(&insert_query[..], &[&player1, &player2, &jsonstr])
+                .expect("failed to insert query for insert_match_progress");
+        }
+        return true;
+    }
+
+    fn delete_match_progress(&mut self, player_id: &str) -> bool {
+        let mut client = Self::make_client(&self.postgre_uri[..]);
+        let delete_query = format!(
+            "DELETE FROM {} where player1 = $1 OR player2 = $1",
+            DB_HATAGENPEI_MATCHES_KEY
+        );
+        client
+            .execute(&delete_query[..], &[&player_id])
+            .expect("failed to delete query for delete_match_progress");
+        return true;
+    }
+
+    fn get_challenge(&mut self, challenged_id: &str) -> Option<Challenge> {
+        let data = self.select_data(DB_HATAGENPEI_CHALLENGES_KEY, challenged_id)?;
+        let challenge = serde_json::from_str(&data[..]).expect("failed to serde_json::from_str");
+        return Some(challenge);
+    }
+
+    fn insert_challenge(&mut self, challenge: &Challenge) -> bool {
+        let jsonstr = serde_json::to_string(&challenge).expect("failed to serde_json::to_string");
+        self.upsert_data(DB_HATAGENPEI_CHALLENGES_KEY, &challenge.challenged_id, &jsonstr);
+        return true;
+    }
+
+    fn delete_challenge(&mut self, challenged_id: &str) -> bool {
+        self.delete_data(DB_HATAGENPEI_CHALLENGES_KEY, challenged_id);
+        return true;
+    }
+
+    fn get_table(&mut self, channel: &str) -> Option<TableProgress> {
+        let data = self.select_data(DB_HATAGENPEI_TABLES_KEY, channel)?;
+        let table = serde_json::from_str(&data[..]).expect("failed to serde_json::from_str");
+        return Some(table);
+    }
+
+    fn insert_table(&mut self, table: &TableProgress) -> bool {
+        let jsonstr = serde_json::to_string(&table).expect("failed to serde_json::to_string");
+        self.upsert_data(DB_HATAGENPEI_TABLES_KEY, &table.channel, &jsonstr);
+        return true;
+    }
+
+    fn delete_table(&mut self, channel: &str) -> bool {
+        self.delete_data(DB_HATAGENPEI_TABLES_KEY, channel);
+        return true;
+    }
+
+    fn get_team_match(&mut self, channel: &str) -> Option<TeamMatchProgress> {
+        let data = self.select_data(DB_HATAGENPEI_TEAM_MATCHES_KEY, channel)?;
+        let team_match = serde_json::from_str(&data[..]).expect("failed to serde_json::from_str");
+        return Some(team_match);
+    }
+
+    fn insert_team_match(&mut self, team_match: &TeamMatchProgress) -> bool {
+        let jsonstr = serde_json::to_string(&team_match).expect("failed to serde_json::to_string");
+        self.upsert_data(DB_HATAGENPEI_TEAM_MATCHES_KEY, &team_match.channel, &jsonstr);
+        return true;
+    }
+
+    fn delete_team_match(&mut self, channel: &str) -> bool {
+        self.delete_data(DB_HATAGENPEI_TEAM_MATCHES_KEY, channel);
+        return true;
+    }
+
+    fn update_team_winloses(&mut self, side: Side, is_side_win: bool) -> bool {
+        let name = side.to_string();
+        let mut win_lose = match self.select_data(DB_HATAGENPEI_TEAM_WINLOSES_KEY, &name) {
+            Some(data) => serde_json::from_str(&data[..]).expect("failed to serde_json::from_str"),
+            None => WinLose::new(0, 0, &name),
+        };
+        if is_side_win {
+            win_lose.win += 1;
+        } else {
+            win_lose.lose += 1;
+        }
+        let jsonstr = serde_json::to_string(&win_lose).expect("failed to serde_json::to_string");
+        self.upsert_data(DB_HATAGENPEI_TEAM_WINLOSES_KEY, &name, &jsonstr);
+        return true;
+    }
+
+    fn get_team_win_loses(&self) -> Vec<WinLose> {
+        return self
+            .select_all_data(DB_HATAGENPEI_TEAM_WINLOSES_KEY)
+            .iter()
+            .map(|data| serde_json::from_str(&data[..]).expect("failed to serde_json::from_str"))
+            .collect();
+    }
+
+    fn update_exhibition_winloses(&mut self, persona_name: &str, is_persona_win: bool) -> bool {
+        let mut win_lose = match self.select_data(DB_HATAGENPEI_EXHIBITION_WINLOSES_KEY, persona_name) {
+            Some(data) => serde_json::from_str(&data[..]).expect("failed to serde_json::from_str"),
+            None => WinLose::new(0, 0, persona_name),
+        };
+        if is_persona_win {
+            win_lose.win += 1;
+        } else {
+            win_lose.lose += 1;
+        }
+        let jsonstr = serde_json::to_string(&win_lose).expect("failed to serde_json::to_string");
+        self.upsert_data(DB_HATAGENPEI_EXHIBITION_WINLOSES_KEY, persona_name, &jsonstr);
+        return true;
+    }
+
+    fn get_exhibition_win_loses(&self) -> Vec<WinLose> {
+        return self
+            .select_all_data(DB_HATAGENPEI_EXHIBITION_WINLOSES_KEY)
+            .iter()
+            .map(|data| serde_json::from_str(&data[..]).expect("failed to serde_json::from_str"))
+            .collect();
+    }
+
+    fn update_contribution(&mut self, contribution: &Contribution) -> bool {
+        let mut total = match self.select_data(DB_HATAGENPEI_CONTRIBUTIONS_KEY, &contribution.id) {
+            Some(data) => serde_json::from_str(&data[..]).expect("failed to serde_json::from_str"),
+            None => Contribution::new(&contribution.id, &contribution.name),
+        };
+        total.add(contribution);
+        let jsonstr = serde_json::to_string(&total).expect("failed to serde_json::to_string");
+        self.upsert_data(DB_HATAGENPEI_CONTRIBUTIONS_KEY, &contribution.id, &jsonstr);
+        return true;
+    }
+
+    fn get_contributions(&self) -> Vec<Contribution> {
+        return self
+            .select_all_data(DB_HATAGENPEI_CONTRIBUTIONS_KEY)
+            .iter()
+            .map(|data| serde_json::from_str(&data[..]).expect("failed to serde_json::from_str"))
+            .collect();
+    }
+
+    fn insert_game_history(&mut self, history: &GameHistory, bot_id: &str) -> bool {
+        let mut client = Self::make_client(&self.postgre_uri[..]);
+        let jsonstr = serde_json::to_string(&history).expect("failed to serde_json::to_string");
+        let insert_query = format!(
+            "INSERT INTO {} (name, data) VALUES ($1, $2)",
+            DB_HATAGENPEI_GAME_HISTORIES_KEY
+        );
+        for player_id in history.player_ids.iter().filter(|id| *id != bot_id) {
+            client
+                .execute(&insert_query[..], &[player_id, &jsonstr])
+                .expect("failed to insert query for insert_game_history");
+        }
+        return true;
+    }
+
+    fn get_game_histories(&mut self, player_id: &str) -> Vec<GameHistory> {
+        let mut client = Self::make_client(&self.postgre_uri[..]);
+        let select_query = format!(
+            "SELECT name, data FROM {} where name = $1 ORDER BY id",
+            DB_HATAGENPEI_GAME_HISTORIES_KEY
+        );
+        let query_result = client
+            .query(&select_query[..], &[&player_id])
+            .expect("failed to select query for get_game_histories");
+
+        let mut res = vec![];
+        for row in &query_result {
+            let data: String = row.get(1);
+            let history = serde_json::from_str(&data[..]).expect("failed to serde_json::from_str");
+            res.push(history);
         }
         return res;
     }
+
+    fn get_recent_game_histories(&mut self, player_id: &str, count: usize) -> Vec<GameHistory> {
+        let mut client = Self::make_client(&self.postgre_uri[..]);
+        let select_query = format!(
+            "SELECT name, data FROM {} where name = $1 ORDER BY id DESC LIMIT $2",
+            DB_HATAGENPEI_GAME_HISTORIES_KEY
+        );
+        let query_result = client
+            .query(&select_query[..], &[&player_id, &(count as i64)])
+            .expect("failed to select query for get_recent_game_histories");
+
+        let mut res = vec![];
+        for row in &query_result {
+            let data: String = row.get(1);
+            let history = serde_json::from_str(&data[..]).expect("failed to serde_json::from_str");
+            res.push(history);
+        }
+        return res;
+    }
+
+    fn update_head_to_head(
+        &mut self,
+        player_id: &str,
+        player_name: &str,
+        opponent_id: &str,
+        opponent_name: &str,
+        is_player_win: bool,
+    ) -> bool {
+        let mut client = Self::make_client(&self.postgre_uri[..]);
+        let select_query = format!(
+            "SELECT player, opponent, data FROM {} where player = $1 AND opponent = $2",
+            DB_HATAGENPEI_HEAD_TO_HEADS_KEY
+        );
+        let res = client
+            .query(&select_query[..], &[&player_id, &opponent_id])
+            .expect("failed to select query for update_head_to_head");
+
+        let mut head_to_head: HeadToHead = match res.get(0) {
+            Some(row) => {
+                let data: String = row.get(2);
+                serde_json::from_str(&data[..]).expect("failed to serde_json::from_str")
+            }
+            None => HeadToHead::new(player_id, player_name, opponent_id, opponent_name),
+        };
+        head_to_head.name = player_name.to_string();
+        head_to_head.opponent_name = opponent_name.to_string();
+        if is_player_win {
+            head_to_head.win += 1;
+        } else {
+            head_to_head.lose += 1;
+        }
+        let jsonstr = serde_json::to_string(&head_to_head).expect("failed to serde_json::to_string");
+
+        if res.len() == 0 {
+            let insert_query = format!(
+                "INSERT INTO {} (player, opponent, data) VALUES ($1, $2, $3)",
+                DB_HATAGENPEI_HEAD_TO_HEADS_KEY
+            );
+            client
+                .execute(&insert_query[..], &[&player_id, &opponent_id, &jsonstr])
+                .expect("failed to insert query for update_head_to_head");
+        } else {
+            let update_query = format!(
+                "UPDATE {} SET data = $1 WHERE player = $2 AND opponent = $3",
+                DB_HATAGENPEI_HEAD_TO_HEADS_KEY
+            );
+            client
+                .execute(&update_query[..], &[&jsonstr, &player_id, &opponent_id])
+                .expect("failed to update query for update_head_to_head");
+        }
+        return true;
+    }
+
+    fn get_head_to_heads(&self, player_id: &str) -> Vec<HeadToHead> {
+        let mut client = Self::make_client(&self.postgre_uri[..]);
+        let select_query = format!(
+            "SELECT player, opponent, data FROM {} where player = $1",
+            DB_HATAGENPEI_HEAD_TO_HEADS_KEY
+        );
+        let query_result = client
+            .query(&select_query[..], &[&player_id])
+            .expect("failed to select query for get_head_to_heads");
+
+        let mut res = vec![];
+        for row in &query_result {
+            let data: String = row.get(2);
+            let head_to_head = serde_json::from_str(&data[..]).expect("failed to serde_json::from_str");
+            res.push(head_to_head);
+        }
+        return res;
+    }
+
+    fn insert_head_to_head(&mut self, head_to_head: &HeadToHead) -> bool {
+        let mut client = Self::make_client(&self.postgre_uri[..]);
+        let jsonstr = serde_json::to_string(&head_to_head).expect("failed to serde_json::to_string");
+        let update_query = format!(
+            "UPDATE {} SET data = $1 WHERE player = $2 AND opponent = $3",
+            DB_HATAGENPEI_HEAD_TO_HEADS_KEY
+        );
+        let updated = client
+            .execute(&update_query[..], &[&jsonstr, &head_to_head.id, &head_to_head.opponent_id])
+            .expect("failed to update query for insert_head_to_head");
+        if updated == 0 {
+            let insert_query = format!(
+                "INSERT INTO {} (player, opponent, data) VALUES ($1, $2, $3)",
+                DB_HATAGENPEI_HEAD_TO_HEADS_KEY
+            );
+            client
+                .execute(&insert_query[..], &[&head_to_head.id, &head_to_head.opponent_id, &jsonstr])
+                .expect("failed to insert query for insert_head_to_head");
+        }
+        return true;
+    }
+
+    fn insert_rating(&mut self, record: &RatingRecord) -> bool {
+        let mut client = Self::make_client(&self.postgre_uri[..]);
+        let jsonstr = serde_json::to_string(&record).expect("failed to serde_json::to_string");
+        let insert_query = format!(
+            "INSERT INTO {} (name, data) VALUES ($1, $2)",
+            DB_HATAGENPEI_RATINGS_KEY
+        );
+        client
+            .execute(&insert_query[..], &[&record.id, &jsonstr])
+            .expect("failed to insert query for insert_rating");
+        return true;
+    }
+
+    fn get_ratings(&mut self, player_id: &str) -> Vec<RatingRecord> {
+        let mut client = Self::make_client(&self.postgre_uri[..]);
+        let select_query = format!(
+            "SELECT name, data FROM {} where name = $1 ORDER BY id",
+            DB_HATAGENPEI_RATINGS_KEY
+        );
+        let query_result = client
+            .query(&select_query[..], &[&player_id])
+            .expect("failed to select query for get_ratings");
+
+        let mut res = vec![];
+        for row in &query_result {
+            let data: String = row.get(1);
+            let record = serde_json::from_str(&data[..]).expect("failed to serde_json::from_str");
+            res.push(record);
+        }
+        return res;
+    }
+
+    fn get_latest_ratings(&self) -> Vec<RatingRecord> {
+        let mut client = Self::make_client(&self.postgre_uri[..]);
+        // プレイヤーごとに、最後に追加した行だけを取る
+        let select_query = format!(
+            "SELECT DISTINCT ON (name) name, data FROM {} ORDER BY name, id DESC",
+            DB_HATAGENPEI_RATINGS_KEY
+        );
+        let query_result = client
+            .query(&select_query[..], &[])
+            .expect("failed to select query for get_latest_ratings");
+
+        let mut res = vec![];
+        for row in &query_result {
+            let data: String = row.get(1);
+            let record = serde_json::from_str(&data[..]).expect("failed to serde_json::from_str");
+            res.push(record);
+        }
+        return res;
+    }
+
+    fn migrate_player_ids(&mut self, ids: &PlayerIds) -> bool {
+        // 表示名の行は id の行に、id がない行（チャンネルや陣営の行、すでに移行済みの行）はそのままにする
+        let rename = |name: &str| ids.get(name).cloned().unwrap_or_else(|| name.to_string());
+        let keep = |name: &str| name.to_string();
+
+        // id の行がすでにある場合、勝敗と貢献度は表示名の行の分を足し合わせ、進行中のものは新しい id の行の方を残す
+        let merge_win_lose = |w: &mut WinLose, legacy: &WinLose| {
+            w.win += legacy.win;
+            w.lose += legacy.lose;
+            if w.handicap.is_none() {
+                w.handicap = legacy.handicap;
+            }
+        };
+        let merge_contribution = |c: &mut Contribution, legacy: &Contribution| {
+            let name = c.name.clone();
+            c.add(legacy);
+            c.name = name;
+        };
+
+        let mut migrated = 0;
+        migrated += self.migrate_rows(DB_HATAGENPEI_WINLOSES_KEY, rename, |w: &mut WinLose| w.assign_player_ids(ids), Some(&merge_win_lose));
+        migrated += self.migrate_rows(DB_HATAGENPEI_REPLAYS_KEY, rename, |r: &mut Replay| r.assign_player_ids(ids), Some(&|_: &mut Replay, _: &Replay| {}));
+        migrated += self.migrate_rows(DB_HATAGENPEI_FINISHED_REPLAYS_KEY, rename, |r: &mut Replay| r.assign_player_ids(ids), None);
+        migrated += self.migrate_rows(DB_HATAGENPEI_CHALLENGES_KEY, rename, |c: &mut Challenge| c.assign_player_ids(ids), Some(&|_: &mut Challenge, _: &Challenge| {}));
+        migrated += self.migrate_rows(DB_HATAGENPEI_TABLES_KEY, keep, |t: &mut TableProgress| t.assign_player_ids(ids), None);
+        migrated += self.migrate_rows(DB_HATAGENPEI_TEAM_MATCHES_KEY, keep, |t: &mut TeamMatchProgress| t.assign_player_ids(ids), None);
+        migrated += self.migrate_rows(DB_HATAGENPEI_CONTRIBUTIONS_KEY, rename, |c: &mut Contribution| c.assign_player_ids(ids), Some(&merge_contribution));
+        migrated += self.migrate_rows(DB_HATAGENPEI_GAME_HISTORIES_KEY, rename, |h: &mut GameHistory| h.assign_player_ids(ids), None);
+        // game_progresses の name は "{game_id}/{プレイヤー名}"
+        let rename_game_progress = |name: &str| match name.find('/') {
+            Some(i) => Self::game_progress_name(&name[..i], &rename(&name[i + 1..])),
+            None => name.to_string(),
+        };
+        // 旗源平の進行状態だけは、中のプレイヤーにも id を入れる（他のゲームの進行状態は Progress として読めない）
+        let assign_game_progress = |value: &mut serde_json::Value| {
+            if let Ok(mut progress) = serde_json::from_value::<Progress>(value.clone()) {
+                progress.assign_player_ids(ids);
+                *value = serde_json::to_value(&progress).expect("failed to serde_json::to_value");
+            }
+        };
+        migrated += self.migrate_rows(DB_GAME_PROGRESSES_KEY, rename_game_progress, assign_game_progress, Some(&|_: &mut serde_json::Value, _: &serde_json::Value| {}));
+
+        // 対戦のテーブルだけは、2人分の列を持っている
+        let mut client = Self::make_client(&self.postgre_uri[..]);
+        let select_query = format!("SELECT player1, player2, data FROM {}", DB_HATAGENPEI_MATCHES_KEY);
+        let res = client
+            .query(&select_query[..], &[])
+            .expect("failed to select query for migrate_player_ids");
+        let update_query = format!(
+            "UPDATE {} SET player1 = $1, player2 = $2, data = $3 WHERE player1 = $4 AND player2 = $5",
+            DB_HATAGENPEI_MATCHES_KEY
+        );
+        let exists_query = format!(
+            "SELECT data FROM {} WHERE (player1 IN ($1, $2) OR player2 IN ($1, $2)) AND NOT (player1 = $3 AND player2 = $4)",
+            DB_HATAGENPEI_MATCHES_KEY
+        );
+        let delete_query = format!("DELETE FROM {} WHERE player1 = $1 AND player2 = $2", DB_HATAGENPEI_MATCHES_KEY);
+        for row in &res {
+            let player1: String = row.get(0);
+            let player2: String = row.get(1);
+            let data: String = row.get(2);
+            let mut match_progress: MatchProgress = serde_json::from_str(&data[..]).expect("failed to serde_json::from_str");
+            match_progress.assign_player_ids(ids);
+            let new_data = serde_json::to_string(&match_progress).expect("failed to serde_json::to_string");
+            if new_data == data {
+                continue;
+            }
+            // どちらかがすでに id で記録した対戦をしていれば、そちらの方が新しいので、表示名の対戦は捨てる
+            let (new_player1, new_player2) = (rename(&player1), rename(&player2));
+            if new_player1 != player1 || new_player2 != player2 {
+                let exists = client
+                    .query(&exists_query[..], &[&new_player1, &new_player2, &player1, &player2])
+                    .expect("failed to select query for migrate_player_ids");
+                if !exists.is_empty() {
+                    migrated += client
+                        .execute(&delete_query[..], &[&player1, &player2])
+                        .expect("failed to delete query for migrate_player_ids");
+                    continue;
+                }
+            }
+            migrated += client
+                .execute(&update_query[..], &[&new_player1, &new_player2, &new_data, &player1, &player2])
+                .expect("failed to update query for migrate_player_ids");
+        }
+
+        info!("migrated {} rows keyed by player name to player id", migrated);
+        return true;
+    }
 }
@@ -0,0 +1,208 @@
+//!
+//! 旗源平をたくさん自動で遊ばせて、ルールや初期の旗の本数の調整に使う統計を取るモジュール
+//!
+
+use super::dice::SeededDice;
+use super::game::*;
+use super::rule_set::RuleSet;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::thread;
+
+/// 先手の決め方
+#[derive(Debug, Clone, PartialEq)]
+pub enum FirstPlayer {
+    Player1,
+    Player2,
+    /// ゲームごとに交互に先手を入れ替える
+    Alternate,
+}
+
+#[derive(Debug, Clone)]
+pub struct SimulationConfig {
+    /// 遊ばせるゲーム数
+    pub games: usize,
+    /// 並列に動かすスレッド数
+    pub threads: usize,
+    /// player1 の初期の旗の本数（小旗何本分か）
+    pub player1_init_score: i32,
+    /// player2 の初期の旗の本数（小旗何本分か）
+    pub player2_init_score: i32,
+    pub first_player: FirstPlayer,
+    pub rule_set: RuleSet,
+    /// i 番目のゲームは seed + i をシードにして遊ぶ
+    pub seed: u64,
+}
+
+/// シミュレーション結果。分布は (値 => 出現回数) で持つ
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct SimulationResult {
+    pub games: usize,
+    pub player1_wins: usize,
+    pub player2_wins: usize,
+    /// 先手が勝ったゲーム数
+    pub first_player_wins: usize,
+    /// ゲームが終わるまでの手番数の分布
+    pub game_lengths: BTreeMap<usize, usize>,
+    /// 1手番でサイコロを振った回数の分布（1 なら振り直しなし）
+    pub reroll_chain_lengths: BTreeMap<usize, usize>,
+    /// まといを取ったのが、ゲーム開始から何回目にサイコロを振ったときかの分布
+    pub matoi_capture_rolls: BTreeMap<usize, usize>,
+}
+
+impl SimulationResult {
+    pub fn player1_win_rate(&self) -> f64 {
+        return self.player1_wins as f64 / self.games as f64;
+    }
+
+    pub fn first_player_win_rate(&self) -> f64 {
+        return self.first_player_wins as f64 / self.games as f64;
+    }
+
+    fn merge(&mut self, other: &SimulationResult) {
+        self.games += other.games;
+        self.player1_wins += other.player1_wins;
+        self.player2_wins += other.player2_wins;
+        self.first_player_wins += other.first_player_wins;
+        for (dst, src) in [
+            (&mut self.game_lengths, &other.game_lengths),
+            (&mut self.reroll_chain_lengths, &other.reroll_chain_lengths),
+            (&mut self.matoi_capture_rolls, &other.matoi_capture_rolls),
+        ]
+        .iter_mut()
+        {
+            for (k, v) in src.iter() {
+                *dst.entry(*k).or_insert(0) += v;
+            }
+        }
+    }
+
+    /// metric,value,count の形式の CSV にする（勝率の行は count に割合が入る）
+    pub fn to_csv(&self) -> String {
+        let mut lines = vec!["metric,value,count".to_string()];
+        lines.push(format!("games,,{}", self.games));
+        lines.push(format!("player1_wins,,{}", self.player1_wins));
+        lines.push(format!("player2_wins,,{}", self.player2_wins));
+        lines.push(format!("player1_win_rate,,{}", self.player1_win_rate()));
+        lines.push(format!(
+            "first_player_win_rate,,{}",
+            self.first_player_win_rate()
+        ));
+        for (metric, distribution) in [
+            ("game_length", &self.game_lengths),
+            ("reroll_chain_length", &self.reroll_chain_lengths),
+            ("matoi_capture_roll", &self.matoi_capture_rolls),
+        ]
+        .iter()
+        {
+            for (value, count) in distribution.iter() {
+                lines.push(format!("{},{},{}", metric, value, count));
+            }
+        }
+        return lines.join("\n");
+    }
+}
+
+/// config に従ってゲームを遊ばせ、結果を集計する
+pub fn simulate(config: &SimulationConfig) -> SimulationResult {
+    let threads = std::cmp::max(config.threads, 1);
+    let handles = (0..threads)
+        .map(|t| {
+            let config = config.clone();
+            // ゲーム番号 i は i % threads == t のスレッドが担当する
+            thread::spawn(move || {
+                let mut result = SimulationResult::default();
+                let mut i = t;
+                while i < config.games {
+                    play_game(&config, i, &mut result);
+                    i += threads;
+                }
+                result
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut result = SimulationResult::default();
+    for handle in handles {
+        result.merge(&handle.join().expect("simulation thread panicked"));
+    }
+    return result;
+}
+
+/// index 番目のゲームを最後まで遊ばせ、result に集計する
+fn play_game(config: &SimulationConfig, index: usize, result: &mut SimulationResult) {
+    let first_player = match config.first_player {
+        FirstPlayer::Player1 => PlayerTurn::Player1,
+        FirstPlayer::Player2 => PlayerTurn::Player2,
+        FirstPlayer::Alternate if index % 2 == 0 => PlayerTurn::Player1,
+        FirstPlayer::Alternate => PlayerTurn::Player2,
+    };
+    let mut game = Hatagenpei::new(
+        Player::new(
+            "player1".to_string(),
+            Score::new(config.player1_init_score, true),
+            Score::new(0, false),
+        ),
+        Player::new(
+            "player2".to_string(),
+            Score::new(config.player2_init_score, true),
+            Score::new(0, false),
+        ),
+        first_player.clone(),
+        Box::new(SeededDice::new(config.seed.wrapping_add(index as u64))),
+        config.rule_set.clone(),
+    );
+
+    let mut turns = 0;
+    let mut rolls = 0;
+    while let Some(game_log) = game.next() {
+        turns += 1;
+        rolls += game_log.commands.len();
+        *result
+            .reroll_chain_lengths
+            .entry(game_log.commands.len())
+            .or_insert(0) += 1;
+
+        let winner = match game_log.game_state {
+            GameState::Player1Win => PlayerTurn::Player1,
+            GameState::Player2Win => PlayerTurn::Player2,
+            GameState::YetPlaying => continue,
+        };
+        result.games += 1;
+        match winner {
+            PlayerTurn::Player1 => result.player1_wins += 1,
+            PlayerTurn::Player2 => result.player2_wins += 1,
+        }
+        if winner == first_player {
+            result.first_player_wins += 1;
+        }
+        *result.game_lengths.entry(turns).or_insert(0) += 1;
+        *result.matoi_capture_rolls.entry(rolls).or_insert(0) += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simulate_test() {
+        let mut config = SimulationConfig {
+            games: 200,
+            threads: 1,
+            player1_init_score: 29,
+            player2_init_score: 29,
+            first_player: FirstPlayer::Alternate,
+            rule_set: RuleSet::default(),
+            seed: 42,
+        };
+        let single = simulate(&config);
+        assert_eq!(single.games, 200);
+        assert_eq!(single.player1_wins + single.player2_wins, 200);
+        assert_eq!(single.game_lengths.values().sum::<usize>(), 200);
+
+        // スレッド数を変えても、同じシードなら同じ結果になる
+        config.threads = 3;
+        assert_eq!(simulate(&config), single);
+    }
+}
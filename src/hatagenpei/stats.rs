@@ -0,0 +1,252 @@
+//!
+//! 終了したゲームの履歴から、プレイヤーごとの統計を計算するモジュール
+//! 先手・後手がはっきりしている 1 対 1 の旗源平（bot とのゲームと人間同士の対戦）だけを集計する
+//!
+
+use super::dice_game::DiceGame;
+use super::game::{Hatagenpei, PlayerTurn};
+use super::score_operator::GameHistory;
+use std::collections::BTreeMap;
+
+/// よく出た目として数える組み合わせの数
+const FREQUENT_DICE_COUNT: usize = 3;
+
+/// プレイヤーの統計
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerStats {
+    /// 終了したゲームの数（卓やチーム戦、旗源平以外のゲームも含む）
+    pub games: usize,
+    /// 集計した 1 対 1 のゲームの数
+    pub duel_games: usize,
+    /// 先手での (勝ち, 負け)
+    pub first: (i32, i32),
+    /// 後手での (勝ち, 負け)
+    pub second: (i32, i32),
+    /// よく出た目 ((小さい目, 大きい目), 回数) の、回数の多い順
+    pub frequent_dice: Vec<((u8, u8), usize)>,
+    /// 1 手番で続けて振り直した回数の最大
+    pub longest_reroll_chain: usize,
+    /// 勝ったゲームの、決着までの手番数の平均。勝ったゲームがなければ None
+    pub average_turns_to_win: Option<f64>,
+    /// 勝ったゲームで、旗が最も少なくなったときの本数（小旗何本分か）。勝ったゲームがなければ None
+    pub biggest_comeback: Option<i32>,
+    /// 最長の連勝数
+    pub longest_win_streak: usize,
+    /// 最長の連敗数
+    pub longest_lose_streak: usize,
+}
+
+impl PlayerStats {
+    /// player_id の終了したゲームの履歴 histories（古い順）から統計を計算する
+    pub fn from_histories(player_id: &str, histories: &[GameHistory]) -> PlayerStats {
+        let mut stats = PlayerStats {
+            games: histories.len(),
+            duel_games: 0,
+            first: (0, 0),
+            second: (0, 0),
+            frequent_dice: vec![],
+            longest_reroll_chain: 0,
+            average_turns_to_win: None,
+            biggest_comeback: None,
+            longest_win_streak: 0,
+            longest_lose_streak: 0,
+        };
+        let mut dice_counts = BTreeMap::new();
+        let mut turns_to_win = vec![];
+        // 今続いている連勝（+）または連敗（-）の数
+        let mut streak: i32 = 0;
+
+        for history in histories {
+            if history.game_id != Hatagenpei::GAME_ID {
+                continue;
+            }
+            // bot とのゲームでは、プレイヤーが先手で bot が後手になる
+            let index = match history.player_ids.iter().position(|id| id == player_id) {
+                Some(index) if history.players.len() == 2 => index,
+                _ => continue,
+            };
+            let turn = if index == 0 {
+                PlayerTurn::Player1
+            } else {
+                PlayerTurn::Player2
+            };
+            let is_win = history.is_winner(index);
+            stats.duel_games += 1;
+
+            let record = match turn {
+                PlayerTurn::Player1 => &mut stats.first,
+                PlayerTurn::Player2 => &mut stats.second,
+            };
+            if is_win {
+                record.0 += 1;
+            } else {
+                record.1 += 1;
+            }
+
+            streak = match (is_win, streak) {
+                (true, s) if s > 0 => s + 1,
+                (true, _) => 1,
+                (false, s) if s < 0 => s - 1,
+                (false, _) => -1,
+            };
+            if streak > 0 {
+                stats.longest_win_streak = std::cmp::max(stats.longest_win_streak, streak as usize);
+            } else {
+                stats.longest_lose_streak =
+                    std::cmp::max(stats.longest_lose_streak, (-streak) as usize);
+            }
+
+            let mut min_flags = None;
+            for game_log in &history.logs {
+                let player = game_log.player(&turn);
+                let flags = player.my_score.value() + player.got_score.value();
+                min_flags = Some(min_flags.map_or(flags, |min: i32| std::cmp::min(min, flags)));
+
+                if game_log.player_turn != turn {
+                    continue;
+                }
+                for cmd in &game_log.commands {
+                    let dice = (
+                        std::cmp::min(cmd.dice1, cmd.dice2),
+                        std::cmp::max(cmd.dice1, cmd.dice2),
+                    );
+                    *dice_counts.entry(dice).or_insert(0) += 1;
+                }
+                let rerolls = game_log.commands.iter().filter(|cmd| cmd.again).count();
+                stats.longest_reroll_chain = std::cmp::max(stats.longest_reroll_chain, rerolls);
+            }
+
+            if is_win {
+                turns_to_win.push(history.logs.len());
+                if let Some(min_flags) = min_flags {
+                    stats.biggest_comeback = Some(
+                        stats
+                            .biggest_comeback
+                            .map_or(min_flags, |comeback| std::cmp::min(comeback, min_flags)),
+                    );
+                }
+            }
+        }
+
+        if !turns_to_win.is_empty() {
+            stats.average_turns_to_win =
+                Some(turns_to_win.iter().sum::<usize>() as f64 / turns_to_win.len() as f64);
+        }
+
+        // 回数の多い順、同じ回数なら目の小さい順に並べる
+        let mut frequent_dice = dice_counts.into_iter().collect::<Vec<_>>();
+        frequent_dice.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        frequent_dice.truncate(FREQUENT_DICE_COUNT);
+        stats.frequent_dice = frequent_dice;
+
+        return stats;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::dice::ScriptedDice;
+    use super::super::dice_game::DiceGame;
+    use super::super::game::*;
+    use super::super::rule_set::{RuleSet, DEFAULT_RULE_SET_ID};
+    use super::*;
+
+    /// rolls の目で player1 と player2 のゲームを決着まで進め、全手番のログを返す
+    fn play(player1: (&str, i32), player2: (&str, i32), rolls: &[u8]) -> Vec<GameLog> {
+        let player = |(name, flags): (&str, i32)| {
+            Player::new(
+                name.to_string(),
+                Score::new(flags, true),
+                Score::new(0, false),
+            )
+        };
+        let mut game = Hatagenpei::new(
+            player(player1),
+            player(player2),
+            PlayerTurn::Player1,
+            Box::new(ScriptedDice::new(rolls)),
+            RuleSet::default(),
+        );
+        let mut logs = vec![];
+        while let Some(game_log) = game.next() {
+            logs.push(game_log);
+        }
+        return logs;
+    }
+
+    /// winner は勝ったプレイヤーの (名前, id) の組
+    fn history(
+        players: &[&str],
+        player_ids: &[&str],
+        winner: (&str, &str),
+        logs: Vec<GameLog>,
+    ) -> GameHistory {
+        return GameHistory {
+            game_id: Hatagenpei::GAME_ID.to_string(),
+            players: players.iter().map(|name| name.to_string()).collect(),
+            player_ids: player_ids.iter().map(|id| id.to_string()).collect(),
+            winner: winner.0.to_string(),
+            winner_id: winner.1.to_string(),
+            rule_set_id: DEFAULT_RULE_SET_ID.to_string(),
+            logs: logs,
+            table_logs: vec![],
+            seeds: vec![],
+        };
+    }
+
+    #[test]
+    fn from_histories_tests() {
+        // 先手が 1-5, 5-1 と 2 回続けて振り直して小旗 20 本をもらい、3-4 で止まる
+        // 後手の 1-2 で先手が 1 本返し、先手の 1-2 で旗のない後手のまといを取る（3 手番で先手の勝ち）
+        let quick_win = [1, 5, 5, 1, 3, 4, 1, 2, 1, 2];
+        // 先手が 3-4 で止まり、後手の 1-2 で先手の 1 本を取る。もう一度繰り返して、後手が先手のまといを取る
+        let slow_lose = [3, 4, 1, 2, 3, 4, 1, 2];
+
+        let histories = vec![
+            // alice が先手で勝つ
+            history(
+                &["alice", "bob"],
+                &["UALICE", "UBOB"],
+                ("alice", "UALICE"),
+                play(("alice", 29), ("bob", 20), &quick_win),
+            ),
+            // alice が後手で負ける
+            history(
+                &["bob", "alice"],
+                &["UBOB", "UALICE"],
+                ("bob", "UBOB"),
+                play(("bob", 29), ("alice", 20), &quick_win),
+            ),
+            // bot と同じ表示名の alice が、bot に負ける
+            history(
+                &["hatagenpeikun", "hatagenpeikun"],
+                &["UALICE", "UBOT"],
+                ("hatagenpeikun", "UBOT"),
+                play(("hatagenpeikun", 1), ("hatagenpeikun", 20), &slow_lose),
+            ),
+        ];
+        assert_eq!(histories[2].logs.len(), 4);
+
+        let stats = PlayerStats::from_histories("UALICE", &histories);
+        assert_eq!(stats.games, 3);
+        assert_eq!(stats.duel_games, 3);
+        assert_eq!(stats.first, (1, 1));
+        assert_eq!(stats.second, (0, 1));
+        assert_eq!(stats.longest_win_streak, 1);
+        assert_eq!(stats.longest_lose_streak, 2);
+        assert_eq!(stats.longest_reroll_chain, 2);
+        assert_eq!(stats.average_turns_to_win, Some(3.0));
+        // 勝ったゲームでは、後手に 1 本返した後の 29 - 1 + 20 = 48 本が最も少ない
+        assert_eq!(stats.biggest_comeback, Some(48));
+        assert_eq!(
+            stats.frequent_dice,
+            vec![((3, 4), 3), ((1, 2), 2), ((1, 5), 2)]
+        );
+
+        // 勝者の id を記録する前の履歴は、名前で比べる
+        let mut old_history = histories[0].clone();
+        old_history.winner_id = String::new();
+        let stats = PlayerStats::from_histories("UALICE", &[old_history]);
+        assert_eq!(stats.first, (1, 0));
+    }
+}
@@ -0,0 +1,127 @@
+//!
+//! 源氏と平家に分かれて遊ぶ、チーム戦の旗源平
+//! チームの旗は全員で共有し、チームのメンバーが順番にサイコロを振る
+//!
+
+use super::game::*;
+use serde::{Deserialize, Serialize};
+
+/// チーム戦の陣営
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum Side {
+    Genji,
+    Heike,
+}
+
+impl Side {
+    pub fn to_string(&self) -> String {
+        return match self {
+            Side::Genji => "源氏",
+            Side::Heike => "平家",
+        }
+        .to_string();
+    }
+
+    /// 相手の陣営
+    pub fn opposite(&self) -> Side {
+        return match self {
+            Side::Genji => Side::Heike,
+            Side::Heike => Side::Genji,
+        };
+    }
+
+    /// Hatagenpei で遊ぶときの手番（源氏が Player1、平家が Player2）
+    pub fn to_player_turn(&self) -> PlayerTurn {
+        return match self {
+            Side::Genji => PlayerTurn::Player1,
+            Side::Heike => PlayerTurn::Player2,
+        };
+    }
+}
+
+/// メンバー1人の貢献度
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Contribution {
+    pub name: String,
+    /// メンバーの Slack のユーザー id。貢献度はこの id ごとに記録する
+    #[serde(default)]
+    pub id: String,
+    /// 参加したチーム戦の数
+    pub games: i32,
+    /// 勝ったチーム戦の数
+    pub wins: i32,
+    /// サイコロを振った手番の数
+    pub rolls: i32,
+    /// 自分の手番でチームが増やした旗の本数（小旗何本分か。減らした場合はマイナス）
+    pub flags: i32,
+    /// 相手チームのまといを取った回数
+    pub matoi_captures: i32,
+}
+
+impl Contribution {
+    pub fn new(id: &str, name: &str) -> Contribution {
+        return Contribution {
+            name: name.to_string(),
+            id: id.to_string(),
+            games: 0,
+            wins: 0,
+            rolls: 0,
+            flags: 0,
+            matoi_captures: 0,
+        };
+    }
+
+    /// other の記録を足し合わせる。表示名は other の新しい方に更新する
+    pub fn add(&mut self, other: &Contribution) {
+        self.name = other.name.clone();
+        self.games += other.games;
+        self.wins += other.wins;
+        self.rolls += other.rolls;
+        self.flags += other.flags;
+        self.matoi_captures += other.matoi_captures;
+    }
+}
+
+/// チーム
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Team {
+    pub side: Side,
+    /// チーム全員で共有する旗
+    pub player: Player,
+    /// メンバーと、このチーム戦での貢献度（参加した順）
+    pub members: Vec<Contribution>,
+    /// 次にサイコロを振るメンバーの番号
+    pub next_member: usize,
+}
+
+impl Team {
+    pub fn new(side: Side, player: Player) -> Team {
+        return Team {
+            side: side,
+            player: player,
+            members: vec![],
+            next_member: 0,
+        };
+    }
+
+    /// id で指定されたユーザーがメンバーにいるか
+    pub fn has_member(&self, id: &str) -> bool {
+        return self.members.iter().any(|m| m.id == id);
+    }
+
+    /// 次にサイコロを振るメンバー
+    pub fn roller(&mut self) -> &mut Contribution {
+        let index = self.next_member % self.members.len();
+        return &mut self.members[index];
+    }
+
+    /// 次のメンバーに順番を回す
+    pub fn rotate(&mut self) {
+        self.next_member = (self.next_member + 1) % self.members.len();
+    }
+
+    /// チームが持っている旗の本数（自分の旗と取った旗の合計。小旗何本分か）
+    pub fn value(&self) -> i32 {
+        return self.player.my_score.value() + self.player.got_score.value();
+    }
+}
@@ -9,6 +9,7 @@ extern crate serde;
 extern crate serde_derive;
 extern crate serde_json;
 extern crate slack;
+extern crate toml;
 
 #[macro_use]
 extern crate failure;
@@ -24,9 +24,12 @@
 use getopts::Options;
 use hatagenpeikun::event_handler::MyHandler;
 use hatagenpeikun::hatagenpei::controller::DataStore;
+use hatagenpeikun::hatagenpei::persona::Persona;
+use hatagenpeikun::hatagenpei::rule_set::RuleSet;
 use log::error;
 use slack::RtmClient;
 use std::env;
+use std::path::Path;
 
 fn print_usage(program: &str, opts: Options) {
     let brief = format!("Usage : {} SLACK_API_TOKEN [options]", program);
@@ -45,6 +48,40 @@ fn main() {
     );
 
     opts.optopt("p", "postgre_uri", "set postgre uri", "");
+    opts.optmulti(
+        "r",
+        "rule",
+        "add hatagenpei rule set file",
+        "rules/xxx.toml | rules/xxx.json",
+    );
+    opts.optmulti(
+        "e",
+        "persona",
+        "add bot persona file for exhibition matches",
+        "personas/xxx.toml | personas/xxx.json",
+    );
+    opts.optmulti(
+        "a",
+        "admin",
+        "add slack user id of hatagenpeikun admin",
+        "U12345678",
+    );
+    opts.optflag(
+        "b",
+        "board",
+        "upload svg board of hatagenpei after each step",
+    );
+    opts.optopt(
+        "x",
+        "expire",
+        "discard bot games untouched for the given days (matches, challenges, tables and team matches never expire)",
+        "DAYS",
+    );
+    opts.optflag(
+        "m",
+        "migrate",
+        "move data recorded by display name to slack user id on the first connect",
+    );
     opts.optflag("h", "help", "print this help menu");
 
     let matches = match opts.parse(&args[1..]) {
@@ -73,9 +110,69 @@ fn main() {
     env::set_var("RUST_LOG", loglevel);
     env_logger::init();
 
+    let mut rule_sets = vec![];
+    for path in matches.opt_strs("r") {
+        match RuleSet::load(Path::new(&path)) {
+            Ok(rule_set) => rule_sets.push(rule_set),
+            Err(err) => {
+                error!("failed to load rule set {}: {}", path, err);
+                return;
+            }
+        }
+    }
+
+    let mut personas = vec![];
+    for path in matches.opt_strs("e") {
+        match Persona::load(Path::new(&path)) {
+            Ok(persona) => personas.push(persona),
+            Err(err) => {
+                error!("failed to load persona {}: {}", path, err);
+                return;
+            }
+        }
+    }
+
+    let admins = matches.opt_strs("a");
+
+    // 盤面のアップロードには、Web API を使うので token が必要になる
+    let board_token = if matches.opt_present("b") {
+        Some(api_key.clone())
+    } else {
+        None
+    };
+
+    let expire_days = match matches.opt_str("x").map(|days| days.parse::<i64>()) {
+        Some(Ok(days)) if days > 0 => Some(days),
+        Some(Ok(days)) => {
+            error!("expire days must be positive: {}", days);
+            return;
+        }
+        Some(Err(err)) => {
+            error!("failed to parse expire days: {}", err);
+            return;
+        }
+        None => None,
+    };
+
     let mut handler = match matches.opt_str("p") {
-        Some(uri) => MyHandler::new(DataStore::Postgre { uri: uri }),
-        _ => MyHandler::new(DataStore::OnMemory),
+        Some(uri) => MyHandler::new(
+            DataStore::Postgre { uri: uri },
+            rule_sets,
+            personas,
+            admins,
+            board_token,
+            expire_days,
+            matches.opt_present("m"),
+        ),
+        _ => MyHandler::new(
+            DataStore::OnMemory,
+            rule_sets,
+            personas,
+            admins,
+            board_token,
+            expire_days,
+            matches.opt_present("m"),
+        ),
     };
 
     let r = RtmClient::login_and_run(&api_key, &mut handler);